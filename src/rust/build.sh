@@ -30,6 +30,7 @@ do
      cd "$LIB" || exit
      cargo build
      cargo test
+     cargo test --all-features
      cargo +stable fmt -- --check
    )
 done
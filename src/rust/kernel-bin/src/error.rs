@@ -17,6 +17,10 @@ pub enum BootError {
     PanicDealloc = 0xe0000002,
     /// Multiboot2 information structure (passed via `ebx` register) doesn't contain UEFI system table.
     PanicMBISUefiSystemTableMissing = 0xe0000003,
+    /// The kernel stack overflowed, either into the guard page or over the canary.
+    PanicKernelStackOverflow = 0xe0000004,
+    /// A double fault that is not caused by a stack overflow.
+    PanicDoubleFault = 0xe0000005,
 
     Other = 0xffffffff,
 }
@@ -0,0 +1,72 @@
+//! Module for the GDT, the TSS and the IDT of the kernel. They replace the tables of the
+//! firmware after the UEFI boot services were exited. So far, only the double fault
+//! handler is installed. It runs on its own stack, so that it also works when the
+//! kernel stack overflowed into the guard page, see [`crate::stack`].
+
+use crate::error::BootError;
+use crate::percpu::InterruptContext;
+use crate::stack;
+use alloc::boxed::Box;
+use kernel_lib::mem::PageAlignedByteBuf;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Index into the interrupt stack table of the TSS for the double fault handler.
+const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 0x4000;
+static mut DOUBLE_FAULT_STACK: PageAlignedByteBuf<DOUBLE_FAULT_STACK_SIZE> =
+    PageAlignedByteBuf::new_zeroed();
+
+/// Loads the GDT with a TSS and the IDT. The tables live on the kernel heap. Must be
+/// called after the UEFI boot services were exited, because the firmware relies on its
+/// own tables until then.
+pub fn init() {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::new(double_fault_stack_top());
+    let tss = Box::leak(Box::new(tss));
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    gdt.load();
+    unsafe {
+        CS::set_reg(code_selector);
+        DS::set_reg(data_selector);
+        ES::set_reg(data_selector);
+        SS::set_reg(data_selector);
+        load_tss(tss_selector);
+    }
+
+    let idt = Box::leak(Box::new(InterruptDescriptorTable::new()));
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.load();
+    log::debug!("loaded GDT, TSS and IDT");
+}
+
+/// Returns the exclusive end of the stack of the double fault handler.
+fn double_fault_stack_top() -> u64 {
+    unsafe { DOUBLE_FAULT_STACK.get().as_ptr_range().end as u64 }
+}
+
+/// Reports a kernel stack overflow, if the last page fault hit the guard page of the
+/// stack. The page fault itself escalates to a double fault, because the CPU can't push
+/// the exception frame onto the overflowed stack.
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, _error_code: u64) -> ! {
+    let _context = InterruptContext::enter_on_stack(double_fault_stack_top());
+    let fault_addr = unsafe { x86::controlregs::cr2() } as u64;
+    if stack::guard_page().contains(&fault_addr) {
+        boot_error!(BootError::PanicKernelStackOverflow, "kernel stack overflow");
+    }
+    boot_error!(BootError::PanicDoubleFault, "double fault: {:#?}", frame);
+}
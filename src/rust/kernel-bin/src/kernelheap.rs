@@ -1,34 +1,239 @@
-//! Module for the kernel heap. So far, my solution is simple. I have all the heap
-//! already inside the binary as static array. Therefore, I don't have to work with
-//! page tables, find free frames from the memory map etc.
+//! Module for the kernel heap. The initial heap is already inside the binary as static
+//! array. Therefore, I don't have to work with page tables, find free frames from the
+//! memory map etc. during early boot. After the UEFI boot services were exited, the
+//! heap grows on demand with memory from the frame allocator, see [`enable_growth`].
 //!
 //! My chunk allocator is the heart of the functionality. It gets a slice of memory,
 //! a second slice as management storage, and then can manage the memory. It manages
-//! the memory in chunks of 256 bytes.
+//! the memory in chunks of 256 bytes. Objects of up to 128 bytes are served by a slab
+//! layer in front of it, so that they don't waste most of a chunk. With the
+//! `buddy-heap` feature, a buddy allocator replaces the chunk allocator. It finds free
+//! blocks faster and keeps large power-of-two allocations, e.g. for DMA buffers and
+//! page tables, from fragmenting the heap.
 
-use kernel_lib::kernelheap::global_static_allocator::GlobalStaticChunkAllocator;
+use crate::error::BootError;
+#[cfg(feature = "heap-trace")]
+use crate::logger::qemu_debugcon::QemuDebugconLogger;
+use crate::physmem;
+use core::alloc::Layout;
+use core::ops::Range;
+use core::slice;
+#[cfg(feature = "heap-trace")]
+use core::{fmt::Write, panic::Location};
+use kernel_lib::irq_mutex::RawIrqSafeLock;
+#[cfg(feature = "buddy-heap")]
+use kernel_lib::kernelheap::buddy_allocator::{BuddyAllocator, DEFAULT_BUDDY_BLOCK_SIZE};
+use kernel_lib::kernelheap::bump_arena::BumpArena;
+use kernel_lib::kernelheap::chunk_allocator::ChunkAllocatorStats;
+#[cfg(not(feature = "buddy-heap"))]
+use kernel_lib::kernelheap::chunk_allocator::{ChunkAllocator, DEFAULT_ALLOCATOR_CHUNK_SIZE};
+use kernel_lib::kernelheap::global_static_allocator::GlobalStaticSlabAllocator;
+#[cfg(feature = "heap-trace")]
+use kernel_lib::kernelheap::tracer::AllocTracer;
+use kernel_lib::mem::frame_allocator::{FrameSize, HUGE_FRAME_SIZE};
+use kernel_lib::mem::region_set::MemoryRegionSet;
 use kernel_lib::mem::PageAlignedByteBuf;
+use kernel_lib::ticket_mutex::RawTicketLock;
+
+/// Backend of the kernel heap. Manages the static heap and each added region.
+#[cfg(not(feature = "buddy-heap"))]
+type Backend = ChunkAllocator<'static, DEFAULT_ALLOCATOR_CHUNK_SIZE>;
+/// Backend of the kernel heap. Manages the static heap and each added region.
+#[cfg(feature = "buddy-heap")]
+type Backend = BuddyAllocator<'static, DEFAULT_BUDDY_BLOCK_SIZE>;
+
+/// Type of the global allocator. Small objects are served from slabs, everything else
+/// from the [`Backend`]. [`kernel_lib::kernelheap::global_static_allocator::GlobalStaticAllocator`]
+/// is a drop-in replacement without the slabs. The locks disable interrupts, so that
+/// interrupt handlers can allocate.
+type KernelHeap = GlobalStaticSlabAllocator<'static, Backend, RawIrqSafeLock<RawTicketLock>>;
 
 /// Chunk size must be a multiple of 8, so that the bitmap can cover all fields properly.
 const MULTIPLE_OF: usize = 8;
 /// 32768 chunks -> 8 MiB Heap. Must be be a multiple of 8.
-pub const HEAP_SIZE: usize = GlobalStaticChunkAllocator::CHUNK_SIZE * MULTIPLE_OF * 4096 * 2;
+pub const HEAP_SIZE: usize = KernelHeap::CHUNK_SIZE * MULTIPLE_OF * 4096 * 2;
 static mut HEAP: PageAlignedByteBuf<HEAP_SIZE> = PageAlignedByteBuf::new_zeroed();
 // always make sure, that the division is "clean", i.e. no remainder
-const BITMAP_SIZE: usize = HEAP_SIZE / GlobalStaticChunkAllocator::CHUNK_SIZE / 8;
+#[cfg(not(feature = "buddy-heap"))]
+const BITMAP_SIZE: usize = HEAP_SIZE / KernelHeap::CHUNK_SIZE / 8;
+#[cfg(feature = "buddy-heap")]
+const BITMAP_SIZE: usize = Backend::metadata_size(HEAP_SIZE);
 static mut BITMAP: PageAlignedByteBuf<BITMAP_SIZE> = PageAlignedByteBuf::new_zeroed();
 
 #[global_allocator]
-static KERNEL_HEAP: GlobalStaticChunkAllocator = GlobalStaticChunkAllocator::new();
+static KERNEL_HEAP: KernelHeap = KernelHeap::new();
 
 /// Initializes the global static rust allocator. It uses static memory already available
-/// inside the address space.
+/// inside the address space. The memory lives in the `.bss` section, hence it is zeroed.
 pub fn init() {
-    unsafe { KERNEL_HEAP.init(HEAP.get_mut(), BITMAP.get_mut()).unwrap() }
+    unsafe {
+        KERNEL_HEAP
+            .init_zeroed(HEAP.get_mut(), BITMAP.get_mut())
+            .unwrap()
+    }
+    #[cfg(feature = "heap-trace")]
+    KERNEL_HEAP.set_tracer(&DebugconAllocTracer);
     log::debug!("initialized allocator");
 }
 
+/// Minimum size of the memory that [`grow`] takes from the frame allocator at once, so
+/// that not every small allocation needs a region of its own.
+const HEAP_GROW_STEP: u64 = 16 * 1024 * 1024;
+
+/// Lets the kernel heap grow on demand with memory from the frame allocator, whenever it
+/// is full. Hence, the heap can use all RAM of the machine, including the memory of the
+/// UEFI boot services after [`physmem::release_boot_services_memory`]. Must be called
+/// after [`physmem::init`].
+pub fn enable_growth() {
+    KERNEL_HEAP.set_grow_fn(grow);
+    log::debug!("kernel heap grows on demand from now on");
+}
+
+/// Grow function of the kernel heap. Takes at least [`HEAP_GROW_STEP`] bytes of physical
+/// memory, and enough for `layout`, from the frame allocator as 2 MiB frames. Contiguous
+/// frames become a single heap region. The bitmap of each region is carved out of the
+/// region itself. UEFI identity-maps all memory, hence physical addresses are valid
+/// pointers. Returns whether the heap grew.
+///
+/// Runs when the heap is full, hence it must not allocate. The frame allocator must not
+/// be locked while the heap allocates.
+fn grow(layout: Layout) -> bool {
+    // one more frame for the bitmap of the region and the alignment
+    let frames_for_layout = (layout.size() + layout.align()) as u64 / HUGE_FRAME_SIZE + 1;
+    let frames = core::cmp::max(HEAP_GROW_STEP / HUGE_FRAME_SIZE, frames_for_layout);
+    let mut regions = MemoryRegionSet::<{ KernelHeap::MAX_REGIONS }>::new();
+    for _ in 0..frames {
+        let frame = match physmem::allocate(FrameSize::Size2MiB) {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+        if regions.insert(frame..frame + HUGE_FRAME_SIZE).is_err() {
+            unsafe { physmem::free(frame, FrameSize::Size2MiB).unwrap() };
+            break;
+        }
+    }
+    let mut grown = false;
+    for range in regions.iter() {
+        if add_region(range.clone()) {
+            grown = true;
+        } else {
+            for frame in range.clone().step_by(HUGE_FRAME_SIZE as usize) {
+                unsafe { physmem::free(frame, FrameSize::Size2MiB).unwrap() };
+            }
+        }
+    }
+    grown
+}
+
+/// Adds the physical memory range to the kernel heap. Returns whether it was added.
+fn add_region(range: Range<u64>) -> bool {
+    let region = unsafe {
+        slice::from_raw_parts_mut(range.start as *mut u8, (range.end - range.start) as usize)
+    };
+    unsafe { KERNEL_HEAP.add_region(region) }.is_ok()
+}
+
+/// Runs `f` with a [`BumpArena`] of `size` bytes, that is taken from the kernel heap.
+/// Data of a boot phase can live in the arena, e.g. in a `Vec<T, &BumpArena>`. All of
+/// it is freed at once when `f` returns, hence it can't leak into later boot phases.
+pub fn with_arena<R>(size: usize, f: impl FnOnce(&BumpArena) -> R) -> R {
+    let mut memory = vec![0_u8; size];
+    let arena = BumpArena::new(&mut memory);
+    f(&arena)
+}
+
+/// Logs the usage and the fragmentation of the kernel heap. `phase` describes the
+/// current boot phase.
+pub fn log_stats(phase: &str) {
+    log_stats_snapshot(phase, KERNEL_HEAP.stats());
+}
+
+/// Like [`log_stats`] but for the panic handler. Doesn't wait for the heap lock, because
+/// the panic may have happened while the lock was held.
+pub fn try_log_stats(phase: &str) {
+    match KERNEL_HEAP.try_stats() {
+        Some(stats) => log_stats_snapshot(phase, stats),
+        None => log::error!("heap stats ({}): unavailable, heap is locked", phase),
+    }
+}
+
+fn log_stats_snapshot(phase: &str, stats: ChunkAllocatorStats) {
+    log::debug!(
+        "heap stats ({}): used={} KiB, free={} KiB, peak={} KiB, largest free run={} KiB, free runs={}, allocs={}, deallocs={}",
+        phase,
+        stats.used_bytes() / 1024,
+        stats.free_bytes() / 1024,
+        stats.peak_used_chunks * stats.chunk_size / 1024,
+        stats.largest_free_run * stats.chunk_size / 1024,
+        stats.free_runs,
+        stats.alloc_count,
+        stats.dealloc_count
+    );
+}
+
+/// Invoked when the kernel heap can't satisfy an infallible allocation, i.e. when
+/// [`KernelHeap`] returned a null pointer.
 #[alloc_error_handler]
 fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
-    panic!("alloc error: {:#?}", layout);
+    boot_error!(BootError::PanicAlloc, "alloc error: {:#?}", layout);
+}
+
+/// Streams a compact record of each heap operation to the QEMU debugcon, so that heap
+/// timelines can be reconstructed and leaks can be found offline. All records start
+/// with `#heap` to separate them from regular log messages:
+/// - `#heap A <ptr> <size> <align> <file>:<line>`
+/// - `#heap D <ptr> <size> <align> <file>:<line>`
+/// - `#heap R <ptr> <new ptr> <size> <new size> <align> <file>:<line>`
+///
+/// A failed operation has `0x0` as (new) pointer.
+#[cfg(feature = "heap-trace")]
+#[derive(Debug)]
+struct DebugconAllocTracer;
+
+#[cfg(feature = "heap-trace")]
+impl AllocTracer for DebugconAllocTracer {
+    fn on_alloc(&self, layout: Layout, ptr: *mut u8, location: &'static Location<'static>) {
+        let _ = writeln!(
+            QemuDebugconLogger::new(),
+            "#heap A {:?} {} {} {}:{}",
+            ptr,
+            layout.size(),
+            layout.align(),
+            location.file(),
+            location.line()
+        );
+    }
+
+    fn on_dealloc(&self, layout: Layout, ptr: *mut u8, location: &'static Location<'static>) {
+        let _ = writeln!(
+            QemuDebugconLogger::new(),
+            "#heap D {:?} {} {} {}:{}",
+            ptr,
+            layout.size(),
+            layout.align(),
+            location.file(),
+            location.line()
+        );
+    }
+
+    fn on_realloc(
+        &self,
+        layout: Layout,
+        ptr: *mut u8,
+        new_size: usize,
+        new_ptr: *mut u8,
+        location: &'static Location<'static>,
+    ) {
+        let _ = writeln!(
+            QemuDebugconLogger::new(),
+            "#heap R {:?} {:?} {} {} {} {}:{}",
+            ptr,
+            new_ptr,
+            layout.size(),
+            new_size,
+            layout.align(),
+            location.file(),
+            location.line()
+        );
+    }
 }
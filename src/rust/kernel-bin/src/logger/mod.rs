@@ -1,52 +1,110 @@
 use crate::logger::fb_logger::FramebufferLogger;
 use crate::logger::qemu_debugcon::QemuDebugconLogger;
 use crate::logger::serial::SerialLogger;
+use crate::percpu::PerCpu;
 use crate::UefiGopFramebuffer;
 use alloc::sync::Arc;
 use core::fmt::Write;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use kernel_lib::fakelock::FakeLock;
+use kernel_lib::log_levels::ModuleLevels;
+use kernel_lib::once::Once;
 use log::{LevelFilter, Log, Metadata, Record};
 use runs_inside_qemu::runs_inside_qemu;
 
 mod fb_logger;
-mod qemu_debugcon;
+pub mod qemu_debugcon;
 mod serial;
 
 /// Public logger that gets used by [`log`].
 pub static LOGGER: LoggerFacade = LoggerFacade::new();
 
+/// Tells whether a core is in [`LoggerFacade::log`]. Records that a core logs while it
+/// already logs, e.g. in a panic in the framebuffer logger, only go to a fresh debugcon
+/// or serial logger. Otherwise, the interrupted logger would be used twice at the same
+/// time.
+static LOGGING: PerCpu<AtomicBool> = PerCpu::new(|| AtomicBool::new(false));
+
+/// Maximum number of module-specific levels. See [`LoggerFacade::set_module_level`].
+const MAX_MODULE_LEVELS: usize = 16;
+
+/// All level filters, indexed by their `usize` value.
+const LEVEL_FILTERS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// Errors of [`LoggerFacade`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoggerError {
+    /// There are already [`MAX_MODULE_LEVELS`] module-specific levels.
+    TooManyModuleLevels,
+}
+
+/// The destinations of log messages. Each has its own level.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sink {
+    /// Usually redirected into a file by QEMU, hence it can take everything.
+    QemuDebugcon = 0,
+    Serial = 1,
+    Framebuffer = 2,
+}
+
+impl Sink {
+    const ALL: [Sink; 3] = [Sink::QemuDebugcon, Sink::Serial, Sink::Framebuffer];
+}
+
 /// Logger facade that glues the log log level together with
 /// all possible logging implementations. Uses the [`log`]-crate
 /// under the hood.
+///
+/// Each [`Sink`] has its own level, that can be changed at any time. Additionally, the
+/// level of single modules can be overridden per sink.
+///
+/// The loggers are behind a [`FakeLock`] and not behind a real lock, because the panic
+/// handler must be able to log, even if the panic happened while logging.
 #[derive(Debug)]
 pub struct LoggerFacade<'a> {
-    init_done: AtomicBool,
-    /// Level for log messages that get logged to screen instead of a file.
-    /// Usually, we don't want to pollute the screen but keep all log messages
-    /// in a file.
-    screen_level: FakeLock<LevelFilter>,
+    init: Once,
+    /// Level of each [`Sink`], indexed by the sink. The values are [`LevelFilter`]s as
+    /// `usize`, so that they can be changed without any lock.
+    levels: [AtomicUsize; Sink::ALL.len()],
+    /// Whether the debugcon logger exists. Cached in [`Self::init`], because the
+    /// re-entrant path of [`Self::log`] can't access `inner`.
+    has_qemu_debugcon: AtomicBool,
+    /// Module-specific levels, indexed by the sink. See [`Self::set_module_level`].
+    module_levels: FakeLock<ModuleLevels<MAX_MODULE_LEVELS>>,
     inner: FakeLock<Loggers<'a>>,
 }
 
 impl<'a> LoggerFacade<'a> {
     const fn new() -> Self {
         Self {
-            init_done: AtomicBool::new(false),
-            screen_level: FakeLock::new(LevelFilter::Trace),
+            init: Once::new(),
+            levels: [
+                AtomicUsize::new(LevelFilter::Trace as usize),
+                AtomicUsize::new(LevelFilter::Trace as usize),
+                AtomicUsize::new(LevelFilter::Trace as usize),
+            ],
+            has_qemu_debugcon: AtomicBool::new(false),
+            module_levels: FakeLock::new(ModuleLevels::new()),
             // inner: SimpleMutex::new(Loggers::new()),
             inner: FakeLock::new(Loggers::new()),
         }
     }
 
+    /// Initializes the loggers. The debugcon gets everything, the serial device and the
+    /// framebuffer get messages up to `screen_level`.
     pub fn init(&self, screen_level: LevelFilter) {
-        assert!(
-            !self.init_done.load(Ordering::SeqCst),
-            "logger may only be initialized once!"
-        );
-
-        self.init_self(screen_level);
-        self.init_generic();
+        let initialized = self.init.call_once(|| {
+            self.init_self(screen_level);
+            self.init_generic();
+        });
+        assert!(initialized, "logger may only be initialized once!");
 
         log::info!("KernelLogger init done");
     }
@@ -58,28 +116,112 @@ impl<'a> LoggerFacade<'a> {
         inner.init_framebuffer(FramebufferLogger::new(framebuffer))
     }
 
-    /// Sets the level of messages that should be logged to the screen.
+    /// Sets the level of messages that should be logged to the screen, i.e. to the
+    /// serial device and to the framebuffer. Usually, we don't want to pollute the screen
+    /// but keep all log messages in a file.
     pub fn set_screen_level(&self, level: LevelFilter) {
-        // *self.screen_level.lock() = level;
-        *self.screen_level.get_mut() = level;
+        self.set_level(Sink::Serial, level);
+        self.set_level(Sink::Framebuffer, level);
+    }
+
+    /// Sets the level of a single sink.
+    pub fn set_level(&self, sink: Sink, level: LevelFilter) {
+        self.levels[sink as usize].store(level as usize, Ordering::Relaxed);
+        self.update_max_level();
+    }
+
+    pub fn level(&self, sink: Sink) -> LevelFilter {
+        LEVEL_FILTERS[self.levels[sink as usize].load(Ordering::Relaxed)]
+    }
+
+    /// Overrides the level of `sink` for messages whose target, usually the module
+    /// path, is `module` or a submodule of it, e.g. to silence a chatty module or to
+    /// trace a single one. The most specific module wins. The other sinks keep their
+    /// level.
+    pub fn set_module_level(
+        &self,
+        sink: Sink,
+        module: &'static str,
+        level: LevelFilter,
+    ) -> Result<(), LoggerError> {
+        self.module_levels
+            .get_mut()
+            .set(sink as usize, module, level)
+            .map_err(|_| LoggerError::TooManyModuleLevels)?;
+        self.update_max_level();
+        Ok(())
+    }
+
+    /// Returns the effective level of `sink` for messages of `target`.
+    fn effective_level(&self, sink: Sink, target: &str) -> LevelFilter {
+        self.module_levels
+            .get()
+            .get(sink as usize, target)
+            .unwrap_or_else(|| self.level(sink))
+    }
+
+    /// Tells [`log`] the most verbose level of all sinks and overrides, so that the
+    /// macros can discard messages that no sink takes early.
+    fn update_max_level(&self) {
+        let sinks = Sink::ALL.iter().map(|sink| self.level(*sink));
+        let modules = self.module_levels.get().max();
+        let max = sinks.max().unwrap_or(LevelFilter::Off).max(modules);
+        log::set_max_level(max);
     }
 
     fn init_self(&self, screen_level: LevelFilter) {
         // let mut inner = self.inner.lock();
         let mut inner = self.inner.get_mut();
         inner.init();
+        self.has_qemu_debugcon
+            .store(inner.qemu_debugcon.is_some(), Ordering::Relaxed);
 
+        self.set_level(Sink::QemuDebugcon, LevelFilter::Trace);
         self.set_screen_level(screen_level);
-
-        self.init_done.store(true, Ordering::SeqCst);
     }
 
     fn init_generic(&self) {
         log::set_logger(&LOGGER).expect("logger init must happen only once");
-        // by default: enable ALL levels
-        // --> un-enable some fields on the logger implementation, i.e. drop several messages
-        //     if the level is too unimportant for a certain logger
-        log::set_max_level(LevelFilter::max());
+        self.update_max_level();
+    }
+
+    /// Logs a record of a core that already logs. Uses the debugcon, if there is one,
+    /// and the serial port otherwise, e.g. on real hardware. Both get a fresh logger,
+    /// so that the interrupted one stays untouched.
+    fn log_reentrant(&self, record: &Record) {
+        let enabled = |sink| record.level() <= self.effective_level(sink, record.target());
+        if self.has_qemu_debugcon.load(Ordering::Relaxed) {
+            if enabled(Sink::QemuDebugcon) {
+                QemuDebugconLogger::new().log(record);
+            }
+        } else if enabled(Sink::Serial) {
+            SerialLogger::new().log(record);
+        }
+    }
+
+    /// Passes the record to all loggers whose level it passes.
+    fn log_to_all(&self, record: &Record) {
+        // let mut inner = self.inner.lock();
+        let mut inner = self.inner.get_mut();
+        let enabled = |sink| record.level() <= self.effective_level(sink, record.target());
+
+        if enabled(Sink::QemuDebugcon) {
+            if let Some(logger) = inner.qemu_debugcon.as_mut() {
+                logger.log(record);
+            }
+        }
+
+        if enabled(Sink::Serial) {
+            if let Some(logger) = inner.serial.as_mut() {
+                logger.log(record);
+            }
+        }
+
+        if enabled(Sink::Framebuffer) {
+            if let Some(logger) = inner.framebuffer.as_mut() {
+                logger.log(record);
+            }
+        }
     }
 }
 
@@ -115,39 +257,19 @@ impl<'a> Loggers<'a> {
 
 impl<'a> Log for LoggerFacade<'a> {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        // TODO: When is this getting called?!
-        // metadata.level().to_level_filter() >= *self.screen_level.lock()
-        metadata.level().to_level_filter() >= *self.screen_level.get()
+        Sink::ALL
+            .iter()
+            .any(|sink| metadata.level() <= self.effective_level(*sink, metadata.target()))
     }
 
     fn log(&self, record: &Record) {
-        // TODO deadlock, when nested exception!
-        // let mut inner = self.inner.lock();
-        let mut inner = self.inner.get_mut();
-
-        // QEMU_DEBUGCON: log everything @ trace level, because I log this
-        // into a file instead of polluting the screen or the framebuffer.
-        if let Some(logger) = inner.qemu_debugcon.as_mut() {
-            logger.log(record);
-        }
-
-        // I'm not sure about the interplay between .enabled() and log::set_max_level.
-        // Actually, I want something like this: Allow all levels but I can remove some
-        // levels for certain loggers.
-        if record.level().to_level_filter() == LevelFilter::Trace {
-            // todo use screen level property at all?!
+        let logging = LOGGING.get();
+        if logging.swap(true, Ordering::Relaxed) {
+            self.log_reentrant(record);
             return;
         }
-
-        // now only log stuff, that should not pollute the screen too much.
-
-        if let Some(logger) = inner.serial.as_mut() {
-            logger.log(record);
-        }
-
-        if let Some(logger) = inner.framebuffer.as_mut() {
-            logger.log(record);
-        }
+        self.log_to_all(record);
+        logging.store(false, Ordering::Relaxed);
     }
 
     fn flush(&self) {
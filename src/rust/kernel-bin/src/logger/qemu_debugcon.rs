@@ -37,3 +37,9 @@ impl Write for QemuDebugconLogger {
         Ok(())
     }
 }
+
+/// Writes a violation of a [`kernel_lib::fakelock::FakeLock`] to the debugcon. Doesn't use
+/// [`crate::logger::LOGGER`], because the logger itself sits behind a `FakeLock`.
+pub fn report_fakelock_violation(args: core::fmt::Arguments) {
+    let _ = writeln!(QemuDebugconLogger::new(), "[FAKELOCK] {}", args);
+}
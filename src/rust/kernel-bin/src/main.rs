@@ -12,6 +12,10 @@
 #![feature(alloc_error_handler)]
 // required to access ".message()" on PanicInfo
 #![feature(panic_info_message)]
+// collections that allocate from boot phase arenas
+#![feature(allocator_api)]
+// double fault handler
+#![feature(abi_x86_interrupt)]
 #![deny(missing_debug_implementations)]
 
 core::arch::global_asm!(include_str!("start.S"));
@@ -27,8 +31,14 @@ extern crate alloc;
 mod panic;
 mod error;
 mod f32_compat;
+mod interrupts;
 mod kernelheap;
 mod logger;
+mod paging;
+mod percpu;
+mod physmem;
+mod relocation;
+mod stack;
 mod sysinfo;
 mod uefi_gop_fb;
 
@@ -36,7 +46,9 @@ use crate::error::BootError;
 use crate::logger::LOGGER;
 use crate::sysinfo::SysInfo;
 use crate::uefi_gop_fb::UefiGopFramebuffer;
+use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::{mem, slice};
 use log::LevelFilter;
 use multiboot2::{BootInformation as Multiboot2Info, MbiLoadError};
@@ -52,10 +64,14 @@ use uefi::Handle;
 /// because visibility is a Rust feature and not important for the object file.
 #[no_mangle]
 fn entry_rust(multiboot2_magic: u32, multiboot2_info_ptr: u32) -> ! {
+    stack::init_canary();
+    kernel_lib::fakelock::set_report_fn(logger::qemu_debugcon::report_fakelock_violation);
+    kernel_lib::fakelock::set_bsp_only();
     // Error, Warn, Info, Debug -> Log to screen
     // everything + Trace -> Log only to file
     LOGGER.init(LevelFilter::Debug);
     kernelheap::init();
+    kernelheap::log_stats("heap initialized");
 
     let multiboot2_info = get_multiboot2_info(multiboot2_magic, multiboot2_info_ptr)
         .expect("Multiboot2 information structure pointer must be valid!");
@@ -67,6 +83,7 @@ fn entry_rust(multiboot2_magic: u32, multiboot2_info_ptr: u32) -> ! {
     let uefi_fb =
         UefiGopFramebuffer::new(&uefi_boot_system_table).expect("No Framebuffer available!");
     LOGGER.init_framebuffer_logger(uefi_fb.clone());
+    kernelheap::log_stats("framebuffer logger initialized");
 
     let fs = uefi_boot_system_table
         .boot_services()
@@ -74,11 +91,14 @@ fn entry_rust(multiboot2_magic: u32, multiboot2_info_ptr: u32) -> ! {
         .unwrap()
         .unwrap();
     let fs = unsafe { &mut *fs.get() };
-    // TODO: not sure what will be inside buf; is there already a type for that in the UEFI crate?
-    let mut buf = [0; 4096];
-    let mut dir = fs.open_volume().unwrap().unwrap();
-    let res = dir.read_entry(&mut buf).unwrap().unwrap().unwrap();
-    log::debug!("{:#?}", res);
+    kernelheap::with_arena(8192, |arena| {
+        // TODO: not sure what will be inside buf; is there already a type for that in the UEFI crate?
+        let mut buf = Vec::new_in(arena);
+        buf.resize(4096, 0_u8);
+        let mut dir = fs.open_volume().unwrap().unwrap();
+        let res = dir.read_entry(&mut buf).unwrap().unwrap().unwrap();
+        log::debug!("{:#?}", res);
+    });
 
     log::debug!("Valid Multiboot2 boot.");
     log::debug!(
@@ -101,11 +121,32 @@ fn entry_rust(multiboot2_magic: u32, multiboot2_info_ptr: u32) -> ! {
         log::debug!("{}", tag.cmdline().unwrap());
     }
 
-    let (uefi_rt_system_table, _uefi_memory_map) =
+    stack::check_canary();
+    let (uefi_rt_system_table, uefi_memory_map) =
         exit_uefi_boot_services(uefi_boot_system_table, uefi_image_handle)
             .expect("Exit UEFI boot services failed.");
 
     log::info!("UEFI boot services exited");
+    physmem::init(&uefi_memory_map, &multiboot2_info);
+    kernelheap::enable_growth();
+    stack::check_canary();
+    paging::init(&uefi_memory_map, uefi_fb.get().phys_range());
+
+    let uefi_rt_system_table = Box::into_raw(Box::new(uefi_rt_system_table));
+    unsafe { relocation::jump_to_higher_half(kernel_main, uefi_rt_system_table as usize) }
+}
+
+/// Continuation of [`entry_rust`] in the higher half. Receives the UEFI runtime system
+/// table as raw pointer to a [`Box`].
+extern "C" fn kernel_main(uefi_rt_system_table: usize) -> ! {
+    let uefi_rt_system_table =
+        unsafe { Box::from_raw(uefi_rt_system_table as *mut SystemTable<Runtime>) };
+    log::info!("kernel runs in the higher half");
+    percpu::init_bsp();
+    interrupts::init();
+    // the firmware page tables, GDT and IDT are no longer in use
+    physmem::release_boot_services_memory();
+    kernelheap::log_stats("UEFI boot services exited");
 
     if runs_inside_qemu::runs_inside_qemu().is_very_likely() {
         log::info!("We run inside QEMU :)");
@@ -115,16 +156,19 @@ fn entry_rust(multiboot2_magic: u32, multiboot2_info_ptr: u32) -> ! {
 
     let sysinfo = SysInfo::new(&uefi_rt_system_table, &x86::cpuid::CpuId::new());
     log::debug!("CPU: {:#?}", sysinfo.cpu_info().extended_brand_string());
-    log::debug!(
-        "Caches: {:#?}",
-        sysinfo
-            .cpu_info()
-            .cache_descriptions()
-            .iter()
-            .filter(|x| x.is_some())
-            .map(|x| x.unwrap())
-            .collect::<alloc::vec::Vec<_>>()
-    );
+    kernelheap::with_arena(4096, |arena| {
+        let mut caches = Vec::new_in(arena);
+        caches.extend(
+            sysinfo
+                .cpu_info()
+                .cache_descriptions()
+                .into_iter()
+                .flatten(),
+        );
+        log::debug!("Caches: {:#?}", caches);
+    });
+    kernelheap::log_stats("system info collected");
+    stack::log_high_water_mark("boot finished");
 
     loop {}
 }
@@ -156,10 +200,12 @@ fn get_uefi_info(info: &Multiboot2Info) -> Result<(SystemTable<Boot>, Handle), (
     Ok((table, handle))
 }
 
-fn exit_uefi_boot_services<'a>(
+/// Exits the UEFI boot services. Returns the UEFI system table with runtime services
+/// and a copy of the final UEFI memory map.
+fn exit_uefi_boot_services(
     table: SystemTable<Boot>,
     handle: Handle,
-) -> Result<(SystemTable<Runtime>, &'a mut [u8]), ()> {
+) -> Result<(SystemTable<Runtime>, Vec<MemoryDescriptor>), ()> {
     let mmap_storage = {
         let max_mmap_size = table.boot_services().memory_map_size().map_size
             + 8 * mem::size_of::<MemoryDescriptor>();
@@ -171,13 +217,12 @@ fn exit_uefi_boot_services<'a>(
         unsafe { slice::from_raw_parts_mut(ptr, max_mmap_size) }
     };
 
-    let uefi_rt_system_table = table
+    let (uefi_rt_system_table, memory_map) = table
         .exit_boot_services(handle, mmap_storage)
         .unwrap()
-        .unwrap()
-        .0;
+        .unwrap();
 
-    Ok((uefi_rt_system_table, mmap_storage))
+    Ok((uefi_rt_system_table, memory_map.copied().collect()))
 }
 
 // see https://docs.rust-embedded.org/embedonomicon/smallest-no-std.html
@@ -0,0 +1,203 @@
+//! Module for the page tables of the kernel. After the UEFI boot services were exited,
+//! the kernel builds its own address space, so that it no longer depends on the page
+//! tables that the firmware left behind. It identity-maps the same memory as the firmware
+//! did, and additionally maps the kernel image into the higher half, where the kernel
+//! continues afterwards. See [`crate::relocation`].
+//!
+//! The sections of the kernel image are mapped W^X: `.text` is read+execute, `.rodata`
+//! is read-only and `.data`/`.bss` are read+write. Everything except for code is mapped
+//! non-executable, if the CPU supports it. The code of the UEFI runtime services is
+//! read+execute like `.text`. The guard page below the boot stack is not mapped at all.
+//! See [`init`].
+
+use crate::{physmem, relocation, stack};
+use core::ops::Range;
+use kernel_lib::mem::frame_allocator::{FrameSize, FRAME_SIZE};
+use kernel_lib::mem::region_set::MemoryRegionSet;
+use kernel_lib::paging::{FrameSource, PageSize, PageTableBuilder, PageTableFlags};
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use x86::controlregs::{Cr0, Cr4};
+use x86::cpuid::CpuId;
+
+extern "C" {
+    /// Section boundaries. Defined in `link.ld`. All of them are page-aligned.
+    static __text_begin: u8;
+    static __text_end: u8;
+    static __rodata_begin: u8;
+    static __rodata_end: u8;
+    static __data_begin: u8;
+    static __data_end: u8;
+}
+
+/// Maximum number of identity-mapped regions, after adjacent regions were merged.
+const MAX_MAPPED_REGIONS: usize = 128;
+
+/// `EFER.NXE`: enables the [`PageTableFlags::NO_EXECUTE`] bit.
+const EFER_NXE: u64 = 1 << 11;
+
+/// Page tables live in frames of the [`physmem`] frame allocator. Physical memory is
+/// identity-mapped, hence the default [`FrameSource::table_ptr`] is correct. The frames
+/// are never boot services memory, because [`physmem`] reserves it until
+/// [`physmem::release_boot_services_memory`]. Hence, zeroing them doesn't destroy the
+/// page tables of the firmware, which are still active during [`init`].
+#[derive(Debug)]
+struct PhysmemFrames;
+
+impl FrameSource for PhysmemFrames {
+    fn allocate_frame(&mut self) -> Option<u64> {
+        physmem::allocate(FrameSize::Size4KiB).ok()
+    }
+}
+
+/// Builds a fresh PML4 that identity-maps the kernel image, the framebuffer and all
+/// memory of the UEFI memory map, and loads it into `CR3`. The sections of the kernel
+/// image are also mapped at [`relocation::to_higher_half`]. Right before `CR3` is
+/// written, the kernel is relocated for the higher half. Must be called after the frame
+/// allocator was initialized and while the kernel still runs in the identity mapping.
+/// The boot services memory must still be reserved, i.e.
+/// [`physmem::release_boot_services_memory`] must be called afterwards: it holds the
+/// active page tables of the firmware.
+///
+/// Only the kernel `.text` and the code of the UEFI runtime services stay executable,
+/// and both are read-only. Afterwards, `CR0.WP`, SMEP and SMAP are enabled, if
+/// available, so that the permissions also apply to the kernel itself.
+pub fn init(memory_map: &[MemoryDescriptor], framebuffer: Range<u64>) {
+    let no_execute = if enable_no_execute() {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    };
+
+    let kernel_image = physmem::kernel_image();
+    let mut data = MemoryRegionSet::<MAX_MAPPED_REGIONS>::new();
+    let mut code = MemoryRegionSet::<MAX_MAPPED_REGIONS>::new();
+    for desc in memory_map {
+        let range = page_range(desc.phys_start..desc.phys_start + desc.page_count * FRAME_SIZE);
+        let regions = match desc.ty {
+            MemoryType::RUNTIME_SERVICES_CODE => &mut code,
+            _ => &mut data,
+        };
+        regions.insert(range).expect("too many memory regions");
+    }
+    data.insert(page_range(framebuffer))
+        .expect("too many memory regions");
+    // the kernel image is mapped section by section
+    for range in code.iter().cloned().chain([kernel_image.clone()]) {
+        data.subtract(range).expect("too many memory regions");
+    }
+    code.subtract(kernel_image.clone())
+        .expect("too many memory regions");
+
+    let max_page_size = if supports_1gib_pages() {
+        PageSize::Size1GiB
+    } else {
+        PageSize::Size2MiB
+    };
+    let mut frames = PhysmemFrames;
+    let mut builder = PageTableBuilder::new(&mut frames)
+        .expect("no frame for the PML4")
+        .with_max_page_size(max_page_size);
+    let mut map = |range: Range<u64>, flags: PageTableFlags| {
+        builder
+            .identity_map(range.clone(), flags)
+            .unwrap_or_else(|e| panic!("can't map {:#x?}: {:?}", range, e));
+        if range.start >= kernel_image.start && range.end <= kernel_image.end {
+            let virt = relocation::to_higher_half(range.start);
+            builder
+                .map_range(virt, range.start, range.end - range.start, flags)
+                .unwrap_or_else(|e| panic!("can't map {:#x?} at {:#x}: {:?}", range, virt, e));
+        }
+    };
+
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    data.iter()
+        .for_each(|range| map(range.clone(), writable | no_execute));
+    // like `.text`: executable, but not writable
+    code.iter()
+        .for_each(|range| map(range.clone(), PageTableFlags::PRESENT));
+    unsafe {
+        map(section(&__text_begin, &__text_end), PageTableFlags::PRESENT);
+        map(
+            section(&__rodata_begin, &__rodata_end),
+            PageTableFlags::PRESENT | no_execute,
+        );
+    }
+    // the guard page of the boot stack stays unmapped
+    let mut data_section = MemoryRegionSet::<2>::new();
+    data_section
+        .insert(unsafe { section(&__data_begin, &__data_end) })
+        .unwrap();
+    data_section.subtract(stack::guard_page()).unwrap();
+    data_section
+        .iter()
+        .for_each(|range| map(range.clone(), writable | no_execute));
+
+    let pml4 = builder.pml4_addr();
+    unsafe {
+        relocation::relocate_to_higher_half();
+        x86::controlregs::cr3_write(pml4);
+    }
+    log::info!(
+        "switched to the kernel page tables (PML4 at {:#x}, {} regions, kernel at {:#x})",
+        pml4,
+        data.len() + code.len(),
+        relocation::to_higher_half(kernel_image.start)
+    );
+    enable_write_protection();
+}
+
+/// Returns the page-aligned address range between two linker symbols.
+fn section(begin: &u8, end: &u8) -> Range<u64> {
+    begin as *const u8 as u64..end as *const u8 as u64
+}
+
+/// Extends the range to full pages.
+fn page_range(range: Range<u64>) -> Range<u64> {
+    let mask = FRAME_SIZE - 1;
+    range.start & !mask..(range.end + mask) & !mask
+}
+
+/// Whether the CPU supports 1 GiB pages.
+fn supports_1gib_pages() -> bool {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_1gib_pages())
+}
+
+/// Sets `EFER.NXE`, if the CPU supports it. Returns whether the
+/// [`PageTableFlags::NO_EXECUTE`] bit can be used.
+fn enable_no_execute() -> bool {
+    let supported = CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_execute_disable());
+    if supported {
+        unsafe {
+            let efer = x86::msr::rdmsr(x86::msr::IA32_EFER);
+            x86::msr::wrmsr(x86::msr::IA32_EFER, efer | EFER_NXE);
+        }
+    } else {
+        log::warn!("CPU doesn't support NX, all memory stays executable");
+    }
+    supported
+}
+
+/// Enables `CR0.WP`, so that read-only pages are also read-only for the kernel, and
+/// SMEP and SMAP, if the CPU supports them. There are no user pages yet, but with them,
+/// the kernel can't accidentally execute or access user memory later.
+fn enable_write_protection() {
+    let features = CpuId::new().get_extended_feature_info();
+    let smep = features.as_ref().map_or(false, |info| info.has_smep());
+    let smap = features.as_ref().map_or(false, |info| info.has_smap());
+    unsafe {
+        x86::controlregs::cr0_write(x86::controlregs::cr0() | Cr0::CR0_WRITE_PROTECT);
+        let mut cr4 = x86::controlregs::cr4();
+        if smep {
+            cr4 |= Cr4::CR4_ENABLE_SMEP;
+        }
+        if smap {
+            cr4 |= Cr4::CR4_ENABLE_SMAP;
+        }
+        x86::controlregs::cr4_write(cr4);
+    }
+    log::info!("enabled CR0.WP, SMEP: {}, SMAP: {}", smep, smap);
+}
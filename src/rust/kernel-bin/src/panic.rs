@@ -1,8 +1,9 @@
 use crate::error::BootError;
+use crate::percpu::{self, PanicState};
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::sync::atomic::Ordering;
-use kernel_lib::mutex::SimpleMutex;
+use kernel_lib::once::OnceCell;
 
 /// Global single instance of [`BootStageAwarePanicHandler`].
 pub static PANIC_HANDLER: PanicHandler = PanicHandler::new();
@@ -19,10 +20,8 @@ fn panic(info: &PanicInfo) -> ! {
 #[macro_export]
 macro_rules! boot_error {
     ($error_enum_variant:path, $($arg:tt)*) => {
-        {
-            let mut lock = $crate::panic::PANIC_HANDLER.panic_error_code().lock();
-            lock.replace($error_enum_variant);
-        }
+        // the first error wins, e.g. in a nested panic
+        let _ = $crate::panic::PANIC_HANDLER.panic_error_code().set($error_enum_variant);
         panic!($($arg)*)
     };
     ($error_enum_variant:path) => {
@@ -33,13 +32,13 @@ macro_rules! boot_error {
 pub struct PanicHandler {
     /// Tells if there is contextual information for the panic. This is set
     /// when the [`panic_error!`]-macro is used instead of [`panic!`].
-    panic_error_code: SimpleMutex<Option<BootError>>,
+    panic_error_code: OnceCell<BootError>,
 }
 
 impl PanicHandler {
     const fn new() -> Self {
         Self {
-            panic_error_code: SimpleMutex::new(None),
+            panic_error_code: OnceCell::new(),
         }
     }
 
@@ -68,24 +67,44 @@ impl PanicHandler {
     /// etc. We just trust in case of a panic, that this code is correct. Otherwise,
     /// an error can only be found in the register specified in the function.
     ///
-    /// **Rust panics are not recoverable!** The kernel halts afterwards.
-    /// TODO: so far unclear how to handle this when eventually multiple cores are available
+    /// **Rust panics are not recoverable!** The kernel halts afterwards. Each core tracks
+    /// its own [`PanicState`], hence a nested panic on one core is not confused with a
+    /// panic on another core.
     pub fn handle_panic(&self, info: &PanicInfo) -> ! {
         // In case anything goes wrong in the panic handler (e.g. nested panic), we have
         // at least a hint in the register, that a panic occurred.
         {
-            let lock = self.panic_error_code.lock();
-            let error_code = lock.unwrap_or(BootError::PanicGeneric);
+            let error_code = self
+                .panic_error_code
+                .get()
+                .copied()
+                .unwrap_or(BootError::PanicGeneric);
 
             // Uses the `r15` register of the boot processor (BP) to signal the specific [`BootError`].
             // This is useful, if our panic handler itself fails for example with printing an error.
             unsafe { core::arch::asm!("mov r15, {0}", in(reg) error_code.code()) };
         }
 
+        let local = percpu::cpu_local();
+        let state = local
+            .map(|l| l.enter_panic())
+            .unwrap_or(PanicState::Panicking);
+
         // Make sure we print a nice error; the Logger will take care of this
         let msg = self.generate_panic_msg(info);
         // the logger implementation will log this to an appropriate place
-        log::error!("{}", msg);
+        log::error!(
+            "CPU {} (interrupt nesting {}, stack top {:#x}): {}",
+            percpu::cpu_id(),
+            local.map(|l| l.nesting()).unwrap_or(0),
+            local.map(|l| l.stack_top()).unwrap_or(0),
+            msg
+        );
+        // the statistics probably caused the nested panic
+        if state == PanicState::Panicking {
+            crate::kernelheap::try_log_stats("panic");
+            crate::stack::log_high_water_mark("panic");
+        }
 
         // After a panic in the Rust kernel, we do not recover in any way
         // Game Over :)
@@ -96,7 +115,7 @@ impl PanicHandler {
         }
     }
 
-    pub fn panic_error_code(&self) -> &SimpleMutex<Option<BootError>> {
+    pub fn panic_error_code(&self) -> &OnceCell<BootError> {
         &self.panic_error_code
     }
 }
@@ -0,0 +1,209 @@
+//! Module for CPU-local data. The `GS` base of each CPU points to its [`CpuLocal`] block,
+//! hence the current CPU finds its own data with a single memory access and without any
+//! lock. [`PerCpu`] builds on this and keeps one value per CPU.
+//!
+//! So far, only the bootstrap processor (BSP) runs. It installs its block in
+//! [`init_bsp`]. Until then, [`cpu_id`] is `0` and [`cpu_local`] is `None`.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use kernel_lib::once::OnceCell;
+
+/// Upper bound of the number of CPUs that [`PerCpu`] can hold values for.
+pub const MAX_CPUS: usize = 64;
+
+/// Tells whether `GS` points to a [`CpuLocal`] block. Before, `GS` holds whatever the
+/// firmware left in it.
+static GS_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Data that each CPU has for itself. Only the owning CPU accesses it, but interrupts
+/// on the same CPU may, hence the fields are atomics.
+#[derive(Debug)]
+#[repr(C)]
+pub struct CpuLocal {
+    /// Points to this block. Must be the first field: `gs:[0]` yields the address of
+    /// the block, because the `GS` base itself can only be read with `rdmsr`.
+    this: *const CpuLocal,
+    cpu_id: usize,
+    /// Top of the stack that the CPU currently runs on. Handlers on their own stack
+    /// switch it, see [`InterruptContext::enter_on_stack`].
+    stack_top: AtomicU64,
+    /// Depth of nested interrupts and exceptions. See [`InterruptContext`].
+    nesting: AtomicUsize,
+    /// See [`PanicState`].
+    panic_state: AtomicU8,
+}
+
+impl CpuLocal {
+    fn new(cpu_id: usize, stack_top: u64) -> Self {
+        Self {
+            this: core::ptr::null(),
+            cpu_id,
+            stack_top: AtomicU64::new(stack_top),
+            nesting: AtomicUsize::new(0),
+            panic_state: AtomicU8::new(PanicState::None as u8),
+        }
+    }
+
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
+    pub fn stack_top(&self) -> u64 {
+        self.stack_top.load(Ordering::Relaxed)
+    }
+
+    /// Must be called, whenever the CPU switches to another stack. Returns the previous
+    /// stack top.
+    pub fn set_stack_top(&self, stack_top: u64) -> u64 {
+        self.stack_top.swap(stack_top, Ordering::Relaxed)
+    }
+
+    pub fn nesting(&self) -> usize {
+        self.nesting.load(Ordering::Relaxed)
+    }
+
+    /// Advances the [`PanicState`] of this CPU and returns the new state. A single
+    /// atomic update, so that only one panic sees [`PanicState::Panicking`], even if an
+    /// exception or NMI panics in between.
+    pub fn enter_panic(&self) -> PanicState {
+        let next = |state| {
+            if state == PanicState::None as u8 {
+                PanicState::Panicking
+            } else {
+                PanicState::NestedPanic
+            }
+        };
+        let previous = self
+            .panic_state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                Some(next(state) as u8)
+            })
+            .unwrap();
+        next(previous)
+    }
+}
+
+/// Tells whether a CPU is in its panic handler.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicState {
+    None = 0,
+    Panicking = 1,
+    /// The panic handler panicked itself, e.g. while logging.
+    NestedPanic = 2,
+}
+
+/// Installs the [`CpuLocal`] block of the BSP and makes the CPU id available for the
+/// lock owner tracking of [`kernel_lib::mutex`]. Must be called once, after the heap was
+/// set up and after [`crate::relocation::jump_to_higher_half`], so that the stack top
+/// is the higher-half address of the boot stack.
+pub fn init_bsp() {
+    install(CpuLocal::new(0, crate::stack::top()));
+    kernel_lib::mutex::set_cpu_id_fn(cpu_id);
+    log::debug!("installed CPU-local block of the BSP");
+}
+
+/// Moves `local` to the heap and makes it the block of the current CPU.
+fn install(local: CpuLocal) {
+    let local = Box::leak(Box::new(local));
+    let ptr: *const CpuLocal = local;
+    local.this = ptr;
+    unsafe { x86::msr::wrmsr(x86::msr::IA32_GS_BASE, ptr as u64) };
+    GS_INSTALLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns the [`CpuLocal`] block of the current CPU, if it was already installed.
+pub fn cpu_local() -> Option<&'static CpuLocal> {
+    if !GS_INSTALLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let local: *const CpuLocal;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, qword ptr gs:[0]",
+            out(reg) local,
+            options(nostack, preserves_flags, readonly)
+        )
+    };
+    Some(unsafe { &*local })
+}
+
+/// Returns the id of the current CPU. The BSP has id `0`.
+pub fn cpu_id() -> usize {
+    cpu_local().map(CpuLocal::cpu_id).unwrap_or(0)
+}
+
+/// Increments the nesting depth of the current CPU, until it is dropped. Interrupt
+/// handlers create one first thing.
+#[derive(Debug)]
+pub struct InterruptContext {
+    /// Stack top to restore on drop, if the handler runs on its own stack.
+    previous_stack_top: Option<u64>,
+}
+
+impl InterruptContext {
+    pub fn enter() -> Self {
+        if let Some(local) = cpu_local() {
+            local.nesting.fetch_add(1, Ordering::Relaxed);
+        }
+        Self {
+            previous_stack_top: None,
+        }
+    }
+
+    /// Like [`Self::enter`], for handlers that run on their own stack, e.g. from the
+    /// interrupt stack table. The stack top of the CPU is switched until the drop.
+    pub fn enter_on_stack(stack_top: u64) -> Self {
+        let mut context = Self::enter();
+        context.previous_stack_top = cpu_local().map(|local| local.set_stack_top(stack_top));
+        context
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        if let Some(local) = cpu_local() {
+            if let Some(stack_top) = self.previous_stack_top {
+                local.set_stack_top(stack_top);
+            }
+            local.nesting.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// One value of `T` per CPU. Each CPU only sees its own value, which is created with
+/// the init function on first access. Like a thread local, but for CPUs.
+///
+/// The values are only shared with interrupt handlers on the same CPU. They can
+/// interrupt the code that holds a reference at any time, hence `T` must be [`Sync`],
+/// e.g. an atomic, just as if it were shared between CPUs.
+pub struct PerCpu<T> {
+    values: [OnceCell<T>; MAX_CPUS],
+    init: fn() -> T,
+}
+
+unsafe impl<T: Sync> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: OnceCell<T> = OnceCell::new();
+
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            values: [Self::EMPTY; MAX_CPUS],
+            init,
+        }
+    }
+
+    /// Returns the value of the current CPU.
+    pub fn get(&self) -> &T {
+        self.values[cpu_id()].get_or_init(self.init)
+    }
+}
+
+impl<T> core::fmt::Debug for PerCpu<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PerCpu").finish_non_exhaustive()
+    }
+}
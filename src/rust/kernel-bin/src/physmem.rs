@@ -0,0 +1,195 @@
+//! Module for the physical memory. After the UEFI boot services were exited, all usable
+//! RAM of the UEFI memory map is managed by a [`FrameAllocator`]. Other subsystems, such
+//! as the kernel heap, get their memory from here.
+//!
+//! The memory of the UEFI boot services is reserved at first, because it still holds
+//! the page tables, the GDT and the IDT of the firmware. It is released with
+//! [`release_boot_services_memory`], once the kernel loaded its own tables.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use kernel_lib::mem::frame_allocator::{
+    FrameAllocator, FrameAllocatorError, FrameSize, PhysMemoryRegion, FRAME_SIZE,
+};
+use kernel_lib::mem::region_set::MemoryRegionSet;
+use kernel_lib::mutex::SimpleMutex;
+use multiboot2::BootInformation as Multiboot2Info;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+
+extern "C" {
+    /// Begin of the kernel image. Defined in `link.ld`.
+    static __kernel_begin: u8;
+    /// End of the kernel image, including `.bss`. Defined in `link.ld`.
+    static __kernel_end: u8;
+}
+
+/// Memory below 1 MiB is left alone. It contains legacy stuff, such as the BIOS data
+/// area, and may be needed later, e.g. as trampoline for application processors.
+const LOW_MEMORY_END: u64 = 0x100000;
+
+/// Maximum number of reserved ranges, after adjacent ranges were merged.
+const MAX_RESERVED_RANGES: usize = 64;
+
+/// Maximum number of boot services memory ranges, after adjacent ranges were merged.
+const MAX_BOOT_SERVICES_RANGES: usize = 128;
+
+static FRAME_ALLOCATOR: SimpleMutex<Option<FrameAllocator<'static>>> = SimpleMutex::new(None);
+
+/// Memory of the UEFI boot services that is reserved until
+/// [`release_boot_services_memory`].
+static BOOT_SERVICES_MEMORY: SimpleMutex<MemoryRegionSet<MAX_BOOT_SERVICES_RANGES>> =
+    SimpleMutex::new(MemoryRegionSet::new());
+
+/// Initializes the frame allocator from the final UEFI memory map. The kernel image,
+/// the boot modules and the Multiboot2 information structure are reserved, because
+/// they are still in use. The kernel image is taken from the linker script and not from
+/// the ELF sections of the Multiboot2 information, because the latter contain link
+/// addresses, which differ from the load address of the relocatable kernel. The memory
+/// of the UEFI boot services is reserved as well, see [`release_boot_services_memory`].
+/// The bitmap of the allocator lives on the kernel heap.
+pub fn init(memory_map: &[MemoryDescriptor], multiboot2_info: &Multiboot2Info) {
+    let regions = memory_map
+        .iter()
+        .filter_map(phys_memory_region)
+        .collect::<Vec<_>>();
+    let phys_end = regions
+        .iter()
+        .filter(|region| region.usable)
+        .map(|region| region.range.end)
+        .max()
+        .unwrap_or(0);
+
+    let mut reserved = MemoryRegionSet::<MAX_RESERVED_RANGES>::new();
+    let mut reserve = |range| reserved.insert(range).expect("too many reserved ranges");
+    reserve(0..LOW_MEMORY_END);
+    reserve(multiboot2_info.start_address() as u64..multiboot2_info.end_address() as u64);
+    reserve(kernel_image());
+    for module in multiboot2_info.module_tags() {
+        reserve(module.start_address() as u64..module.end_address() as u64);
+    }
+
+    let mut boot_services = BOOT_SERVICES_MEMORY.lock();
+    for desc in memory_map
+        .iter()
+        .filter(|desc| is_boot_services_memory(desc))
+    {
+        let end = desc.phys_start + desc.page_count * uefi::table::boot::PAGE_SIZE as u64;
+        boot_services
+            .insert(desc.phys_start..end)
+            .expect("too many boot services memory ranges");
+    }
+    // reserved ranges stay reserved, even if they are boot services memory
+    for range in reserved.iter() {
+        let frames = range.start / FRAME_SIZE * FRAME_SIZE
+            ..(range.end + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+        boot_services
+            .subtract(frames)
+            .expect("too many boot services memory ranges");
+    }
+    let all_reserved = reserved
+        .iter()
+        .chain(boot_services.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let bitmap = vec![0; FrameAllocator::bitmap_len(phys_end)].leak();
+    let alloc = FrameAllocator::new(&regions, &all_reserved, bitmap).unwrap();
+    log::info!(
+        "physical memory: {} MiB total, {} MiB usable, {} MiB reserved for boot services",
+        alloc.total_ram() / 1024 / 1024,
+        alloc.usable_ram() / 1024 / 1024,
+        boot_services.total_size() / 1024 / 1024
+    );
+    *FRAME_ALLOCATOR.lock() = Some(alloc);
+}
+
+/// Hands the memory of the UEFI boot services to the frame allocator. Must be called
+/// after the kernel loaded its own page tables, GDT and IDT, because those of the
+/// firmware live in this memory. See [`crate::paging::init`] and
+/// [`crate::interrupts::init`].
+pub fn release_boot_services_memory() {
+    let mut boot_services = BOOT_SERVICES_MEMORY.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let alloc = frame_allocator
+        .as_mut()
+        .expect("frame allocator must be initialized");
+    for range in boot_services.iter() {
+        for frame in range.clone().step_by(FRAME_SIZE as usize) {
+            unsafe { alloc.free(frame, FrameSize::Size4KiB).unwrap() };
+        }
+    }
+    let released = boot_services.total_size();
+    *boot_services = MemoryRegionSet::new();
+    // logging may grow the kernel heap, which locks the frame allocator
+    drop(frame_allocator);
+    drop(boot_services);
+    log::info!(
+        "released {} MiB of boot services memory",
+        released / 1024 / 1024
+    );
+}
+
+/// Allocates a physical frame. See [`FrameAllocator::allocate`].
+pub fn allocate(size: FrameSize) -> Result<u64, FrameAllocatorError> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("frame allocator must be initialized")
+        .allocate(size)
+}
+
+/// Returns a physical frame. See [`FrameAllocator::free`].
+///
+/// # Safety
+/// See [`FrameAllocator::free`].
+pub unsafe fn free(addr: u64, size: FrameSize) -> Result<(), FrameAllocatorError> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("frame allocator must be initialized")
+        .free(addr, size)
+}
+
+/// Returns the address range of the kernel image, according to the linker script. This
+/// is the physical address range, as long as the kernel runs in the identity mapping.
+/// See [`crate::relocation`].
+pub fn kernel_image() -> Range<u64> {
+    unsafe {
+        let begin = &__kernel_begin as *const u8 as u64;
+        let end = &__kernel_end as *const u8 as u64;
+        begin..end
+    }
+}
+
+/// Whether the memory of the descriptor belongs to the UEFI boot services. It is free to
+/// use after they were exited, but see [`release_boot_services_memory`].
+fn is_boot_services_memory(desc: &MemoryDescriptor) -> bool {
+    matches!(
+        desc.ty,
+        MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA
+    )
+}
+
+/// Maps a UEFI memory descriptor to a [`PhysMemoryRegion`]. Returns `None` for memory
+/// that is not RAM, such as MMIO.
+fn phys_memory_region(desc: &MemoryDescriptor) -> Option<PhysMemoryRegion> {
+    let usable = match desc.ty {
+        MemoryType::CONVENTIONAL
+        | MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::BOOT_SERVICES_DATA => true,
+        MemoryType::LOADER_CODE
+        | MemoryType::LOADER_DATA
+        | MemoryType::RUNTIME_SERVICES_CODE
+        | MemoryType::RUNTIME_SERVICES_DATA
+        | MemoryType::ACPI_RECLAIM
+        | MemoryType::ACPI_NON_VOLATILE
+        | MemoryType::PERSISTENT_MEMORY => false,
+        _ => return None,
+    };
+    let begin = desc.phys_start;
+    let end = begin + desc.page_count * uefi::table::boot::PAGE_SIZE as u64;
+    Some(PhysMemoryRegion {
+        range: begin..end,
+        usable,
+    })
+}
@@ -0,0 +1,91 @@
+//! Module for the relocation of the kernel. The kernel is linked as static PIE, so that
+//! GRUB can load it to any physical address. `start.S` applies the relocations for the
+//! load address, before any Rust code runs.
+//!
+//! Once the kernel page tables also map the kernel image into the higher half (see
+//! [`crate::paging`]), the relocations are applied again for [`KERNEL_HIGHER_HALF_BASE`]
+//! and the kernel continues there with [`jump_to_higher_half`]. The identity mapping
+//! stays, hence pointers that were created before, e.g. into the heap, remain valid.
+//!
+//! Addresses of linker symbols are computed RIP-relative. Before the jump, they are
+//! physical addresses; afterwards, they are higher-half addresses.
+
+use core::slice;
+
+extern "C" {
+    /// Boundaries of the relocation table. Defined in `link.ld`.
+    static __rela_dyn_begin: Elf64Rela;
+    static __rela_dyn_end: Elf64Rela;
+    /// Offset between load address and link address. Set by `start.S`.
+    static KERNEL_LOAD_OFFSET: u64;
+    /// Defined in `start.S`.
+    fn switch_to_higher_half(arg: usize, continuation: u64, stack_offset: u64) -> !;
+}
+
+/// Base of the higher half. The kernel runs at this base plus its link address, i.e.,
+/// in the top 2 GiB of the virtual address space.
+pub const KERNEL_HIGHER_HALF_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// The only relocation type of a static PIE: `*(base + offset) = base + addend`.
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Entry of the `.rela.dyn` section.
+#[repr(C)]
+#[derive(Debug)]
+struct Elf64Rela {
+    offset: u64,
+    info: u64,
+    addend: u64,
+}
+
+/// Offset between the physical load address and the link address of the kernel.
+/// Wraps around, if the kernel was loaded below its link address.
+pub fn load_offset() -> u64 {
+    unsafe { KERNEL_LOAD_OFFSET }
+}
+
+/// Returns the higher-half address of a physical address inside the kernel image.
+pub fn to_higher_half(phys: u64) -> u64 {
+    phys.wrapping_sub(load_offset())
+        .wrapping_add(KERNEL_HIGHER_HALF_BASE)
+}
+
+/// Applies the relocations again, so that all absolute addresses in the kernel image
+/// point into the higher half. The kernel image is written through its identity mapping.
+///
+/// # Safety
+/// Must be called while the kernel still runs in the identity mapping. The higher-half
+/// mapping must be activated right afterwards, because no relocated pointer, e.g. of
+/// the logger, is valid until then.
+pub unsafe fn relocate_to_higher_half() {
+    let begin = &__rela_dyn_begin as *const Elf64Rela;
+    let end = &__rela_dyn_end as *const Elf64Rela;
+    let relocations = slice::from_raw_parts(begin, end.offset_from(begin) as usize);
+    for rela in relocations
+        .iter()
+        .filter(|rela| rela.info as u32 == R_X86_64_RELATIVE)
+    {
+        let ptr = rela.offset.wrapping_add(load_offset()) as *mut u64;
+        ptr.write_volatile(rela.addend.wrapping_add(KERNEL_HIGHER_HALF_BASE));
+    }
+}
+
+/// Continues with `continuation(arg)` in the higher half. The stack is kept but from now
+/// on used through the higher-half mapping. The current call chain is abandoned, hence
+/// its locals are never dropped.
+///
+/// # Safety
+/// The kernel page tables with the higher-half mapping must be active. See
+/// [`crate::paging::init`].
+pub unsafe fn jump_to_higher_half(continuation: extern "C" fn(usize) -> !, arg: usize) -> ! {
+    // Depending on the code, the compiler takes the address RIP-relative or from the
+    // already relocated GOT.
+    let continuation = continuation as usize as u64;
+    let continuation = if continuation >= KERNEL_HIGHER_HALF_BASE {
+        continuation
+    } else {
+        to_higher_half(continuation)
+    };
+    let stack_offset = to_higher_half(0);
+    switch_to_higher_half(arg, continuation, stack_offset)
+}
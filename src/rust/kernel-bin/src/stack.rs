@@ -0,0 +1,88 @@
+//! Module for the boot stack, that `start.S` reserves in `.bss`. It has no overflow
+//! protection from the hardware until the kernel page tables are active. Until then, a
+//! canary at the bottom of the stack detects overflows after the fact, see
+//! [`check_canary`]. Afterwards, the guard page below the stack is unmapped, see
+//! [`guard_page`] and [`crate::interrupts`].
+//!
+//! `start.S` paints the stack, so that [`high_water_mark`] can tell how deep it ever was.
+
+use crate::error::BootError;
+use core::ops::Range;
+use core::slice;
+use kernel_lib::mem::{stack_paint, PAGE_SIZE};
+
+extern "C" {
+    /// Page below the stack. Defined in `start.S`.
+    static _initial_stack_guard: u8;
+    /// Lowest address of the stack. Defined in `start.S`.
+    static _initial_stack_bottom: u8;
+    /// Exclusive end of the stack. Defined in `start.S`.
+    static _initial_stack_top: u8;
+}
+
+/// Value that fills the bottom of the stack.
+const CANARY: u64 = 0x57ac_c0de_57ac_c0de;
+
+/// Number of canary words at the bottom of the stack.
+const CANARY_WORDS: usize = 8;
+
+/// Returns the address range of the guard page below the stack.
+pub fn guard_page() -> Range<u64> {
+    let begin = unsafe { &_initial_stack_guard as *const u8 as u64 };
+    begin..begin + PAGE_SIZE as u64
+}
+
+/// Returns the exclusive end of the stack.
+pub fn top() -> u64 {
+    unsafe { &_initial_stack_top as *const u8 as u64 }
+}
+
+/// Writes the canary to the bottom of the stack. Must be called first thing during
+/// boot, when the stack is still shallow.
+pub fn init_canary() {
+    let canary = canary_ptr();
+    for i in 0..CANARY_WORDS {
+        unsafe { canary.add(i).write_volatile(CANARY) };
+    }
+}
+
+/// Panics with [`BootError::PanicKernelStackOverflow`], if the canary at the bottom of
+/// the stack was overwritten.
+pub fn check_canary() {
+    let canary = canary_ptr();
+    let intact = (0..CANARY_WORDS).all(|i| unsafe { canary.add(i).read_volatile() } == CANARY);
+    if !intact {
+        boot_error!(BootError::PanicKernelStackOverflow, "kernel stack overflow");
+    }
+}
+
+/// Returns the deepest stack usage in bytes since boot, without the canary.
+pub fn high_water_mark() -> usize {
+    stack_paint::high_water_mark(stack_above_canary())
+}
+
+/// Logs the [`high_water_mark`] in relation to the stack size.
+pub fn log_high_water_mark(phase: &str) {
+    let size = stack_above_canary().len() * 8;
+    let used = high_water_mark();
+    log::debug!(
+        "stack ({}): max used={} KiB of {} KiB ({}%)",
+        phase,
+        used / 1024,
+        size / 1024,
+        used * 100 / size
+    );
+}
+
+/// The painted part of the stack, that is not covered by the canary.
+fn stack_above_canary() -> &'static [u64] {
+    unsafe {
+        let bottom = canary_ptr().add(CANARY_WORDS) as *const u64;
+        let top = &_initial_stack_top as *const u8 as *const u64;
+        slice::from_raw_parts(bottom, top.offset_from(bottom) as usize)
+    }
+}
+
+fn canary_ptr() -> *mut u64 {
+    unsafe { &_initial_stack_bottom as *const u8 as *mut u64 }
+}
@@ -3,6 +3,7 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Write};
+use core::ops::Range;
 use core::{ptr, slice};
 use kernel_lib::fakelock::FakeLock;
 use noto_sans_mono_bitmap::{get_bitmap, BitmapChar, BitmapHeight, FontWeight};
@@ -220,6 +221,12 @@ impl<'a> UefiGopFramebuffer<'a> {
     pub fn bytes_per_pixel(&self) -> usize {
         4
     }
+
+    /// Physical address range of the framebuffer memory. UEFI identity-maps all memory.
+    pub fn phys_range(&self) -> Range<u64> {
+        let begin = self.framebuffer_slice.as_ptr() as u64;
+        begin..begin + self.framebuffer_slice.len() as u64
+    }
 }
 
 impl<'a> Write for UefiGopFramebuffer<'a> {
@@ -0,0 +1,87 @@
+//! Benchmarks for the bitmap search of [`ChunkAllocator`]. Run them with `cargo bench`.
+//!
+//! [`linear_scan`] mirrors the previous search of the allocator, that tested one bit
+//! per iteration. It is the baseline for the word-based search of the allocator.
+
+#![feature(test)]
+
+extern crate test;
+
+use core::alloc::Layout;
+use kernel_lib::kernelheap::chunk_allocator::{ChunkAllocator, DEFAULT_ALLOCATOR_CHUNK_SIZE};
+use test::{black_box, Bencher};
+
+/// Same number of chunks as the 8 MiB kernel heap in kernel-bin.
+const CHUNK_COUNT: usize = 32768;
+const HEAP_SIZE: usize = CHUNK_COUNT * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+/// Number of chunks of each allocation in the benchmarks.
+const ALLOC_CHUNKS: usize = 4;
+
+/// Bitmap where all chunks except for the last few are used.
+fn nearly_full_bitmap() -> Vec<u8> {
+    let mut bitmap = vec![0xff; CHUNK_COUNT / 8];
+    let len = bitmap.len();
+    bitmap[len - 1] = 0;
+    bitmap
+}
+
+/// Bitmap where every other chunk is used, except for the last few. Free runs
+/// are too short for the allocations in the benchmarks.
+fn fragmented_bitmap() -> Vec<u8> {
+    let mut bitmap = vec![0x55; CHUNK_COUNT / 8];
+    let len = bitmap.len();
+    bitmap[len - 1] = 0;
+    bitmap
+}
+
+/// Bit-by-bit search for `chunk_num` free chunks.
+fn linear_scan(bitmap: &[u8], chunk_num: usize) -> Option<usize> {
+    let is_free = |i: usize| (bitmap[i / 8] >> (i % 8)) & 1 == 0;
+    let mut begin = 0;
+    while begin + chunk_num <= CHUNK_COUNT {
+        let mut coherent_chunk_count = 0;
+        while coherent_chunk_count < chunk_num && is_free(begin + coherent_chunk_count) {
+            coherent_chunk_count += 1;
+        }
+        if coherent_chunk_count == chunk_num {
+            return Some(begin);
+        }
+        begin += coherent_chunk_count + 1;
+    }
+    None
+}
+
+fn bench_linear_scan(b: &mut Bencher, bitmap: Vec<u8>) {
+    b.iter(|| linear_scan(black_box(&bitmap), black_box(ALLOC_CHUNKS)).unwrap());
+}
+
+fn bench_allocator(b: &mut Bencher, mut bitmap: Vec<u8>) {
+    let mut heap = vec![0_u8; HEAP_SIZE];
+    let mut alloc =
+        ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::new(&mut heap, &mut bitmap).unwrap();
+    let layout = Layout::from_size_align(ALLOC_CHUNKS * DEFAULT_ALLOCATOR_CHUNK_SIZE, 1).unwrap();
+    b.iter(|| {
+        let ptr = alloc.try_alloc(black_box(layout)).unwrap();
+        unsafe { alloc.try_dealloc(ptr.as_ptr(), layout).unwrap() };
+    });
+}
+
+#[bench]
+fn nearly_full_linear_scan(b: &mut Bencher) {
+    bench_linear_scan(b, nearly_full_bitmap());
+}
+
+#[bench]
+fn nearly_full_allocator(b: &mut Bencher) {
+    bench_allocator(b, nearly_full_bitmap());
+}
+
+#[bench]
+fn fragmented_linear_scan(b: &mut Bencher) {
+    bench_linear_scan(b, fragmented_bitmap());
+}
+
+#[bench]
+fn fragmented_allocator(b: &mut Bencher) {
+    bench_allocator(b, fragmented_bitmap());
+}
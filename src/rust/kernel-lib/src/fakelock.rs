@@ -1,4 +1,50 @@
+use crate::mutex::current_cpu_id;
 use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Whether the `fakelock-debug` feature is active. In this mode, each [`FakeLock`] counts
+/// its live shared and mutable borrows, like a [`core::cell::RefCell`]. Aliasing
+/// violations are reported with [`set_report_fn`] instead of being silently accepted.
+/// Additionally, accesses from other CPUs than the bootstrap processor can be reported.
+/// See [`set_bsp_only`].
+pub const FAKELOCK_DEBUG: bool = cfg!(feature = "fakelock-debug");
+
+/// Marks that [`set_bsp_only`] wasn't called.
+const ANY_CPU: usize = usize::MAX;
+
+/// See [`set_bsp_only`].
+static BSP_ID: AtomicUsize = AtomicUsize::new(ANY_CPU);
+
+/// See [`set_report_fn`].
+static REPORT_FN: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Sets the function that reports violations in [`FAKELOCK_DEBUG`] mode. It should write
+/// to a low-level channel, such as a serial port, but never use a logger that itself
+/// sits behind a [`FakeLock`]. Until then, violations panic.
+pub fn set_report_fn(report: fn(fmt::Arguments)) {
+    REPORT_FN.store(report as *mut (), Ordering::Relaxed);
+}
+
+/// Must be called on the bootstrap processor. From now on, each access to a [`FakeLock`]
+/// from another CPU is reported in [`FAKELOCK_DEBUG`] mode. The CPU id comes from
+/// [`crate::mutex::set_cpu_id_fn`].
+pub fn set_bsp_only() {
+    BSP_ID.store(current_cpu_id(), Ordering::Relaxed);
+}
+
+fn report(args: fmt::Arguments) {
+    let report = REPORT_FN.load(Ordering::Relaxed);
+    if report.is_null() {
+        panic!("{}", args);
+    } else {
+        let report = unsafe { core::mem::transmute::<*mut (), fn(fmt::Arguments)>(report) };
+        report(args);
+    }
+}
 
 /// A fake lock which helps to signal Rust memory safety on global static mutable vars.
 /// **This should only be used during the boot process as long as only a single core
@@ -6,9 +52,88 @@ use core::cell::UnsafeCell;
 ///
 /// Even tho we could use a real mutex, this makes it simpler to cope with nested locking, e.g.
 /// the panic handler needs access to a variable, but the panic'ed code still holds the lock.
+/// With [`FAKELOCK_DEBUG`], such nested accesses are at least reported.
+///
+/// For values that are only written once during initialization, prefer the types in
+/// [`crate::once`]. They are safe on multiple cores.
 #[derive(Debug)]
 pub struct FakeLock<T> {
     data: UnsafeCell<T>,
+    /// Only maintained with [`FAKELOCK_DEBUG`].
+    borrows: Borrows,
+}
+
+/// Live borrows of a [`FakeLock`] in [`FAKELOCK_DEBUG`] mode. Both counters are
+/// incremented and decremented symmetrically, so that they stay correct, even if a
+/// reported violation continues.
+#[derive(Debug)]
+struct Borrows {
+    shared: AtomicUsize,
+    mutable: AtomicUsize,
+    /// Location of the latest mutable borrow.
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl Borrows {
+    const fn new() -> Self {
+        Self {
+            shared: AtomicUsize::new(0),
+            mutable: AtomicUsize::new(0),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn add_shared(&self, location: &Location) {
+        check_bsp(location);
+        if self.mutable.load(Ordering::Relaxed) != 0 {
+            self.report_conflict("shared", location);
+        }
+        self.shared.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add_mutable(&self, location: &'static Location<'static>) {
+        check_bsp(location);
+        if self.mutable.load(Ordering::Relaxed) != 0 {
+            self.report_conflict("mutable", location);
+        } else if self.shared.load(Ordering::Relaxed) != 0 {
+            report(format_args!(
+                "FakeLock: mutable borrow at {} while {} shared borrow(s) are alive",
+                location,
+                self.shared.load(Ordering::Relaxed)
+            ));
+        }
+        self.mutable.fetch_add(1, Ordering::Relaxed);
+        self.location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+    }
+
+    fn report_conflict(&self, kind: &str, location: &Location) {
+        let owner = self.location.load(Ordering::Relaxed);
+        match unsafe { owner.as_ref() } {
+            Some(owner) => report(format_args!(
+                "FakeLock: {} borrow at {} while mutably borrowed since {}",
+                kind, location, owner
+            )),
+            None => report(format_args!(
+                "FakeLock: {} borrow at {} while mutably borrowed",
+                kind, location
+            )),
+        }
+    }
+}
+
+fn check_bsp(location: &Location) {
+    let bsp = BSP_ID.load(Ordering::Relaxed);
+    if bsp == ANY_CPU {
+        return;
+    }
+    let cpu = current_cpu_id();
+    if cpu != bsp {
+        report(format_args!(
+            "FakeLock: access at {} from CPU {}, but only the BSP (CPU {}) may access it",
+            location, cpu, bsp
+        ));
+    }
 }
 
 // tell Rust this is safe - use with caution!
@@ -20,17 +145,78 @@ impl<T> FakeLock<T> {
     pub const fn new(data: T) -> Self {
         Self {
             data: UnsafeCell::new(data),
+            borrows: Borrows::new(),
         }
     }
 
-    /// Returns read only reference to the data.
-    pub fn get(&self) -> &T {
-        unsafe { &*self.data.get() }
+    /// Returns read only access to the data.
+    #[track_caller]
+    pub fn get(&self) -> FakeLockRef<T> {
+        if FAKELOCK_DEBUG {
+            self.borrows.add_shared(Location::caller());
+        }
+        FakeLockRef { lock: self }
     }
 
-    /// Returns mutable reference to the data.
-    pub fn get_mut(&self) -> &mut T {
-        unsafe { &mut *self.data.get() }
+    /// Returns mutable access to the data.
+    #[track_caller]
+    pub fn get_mut(&self) -> FakeLockRefMut<T> {
+        if FAKELOCK_DEBUG {
+            self.borrows.add_mutable(Location::caller());
+        }
+        FakeLockRefMut { lock: self }
+    }
+}
+
+/// Shared borrow of a [`FakeLock`].
+#[derive(Debug)]
+pub struct FakeLockRef<'a, T> {
+    lock: &'a FakeLock<T>,
+}
+
+impl<T> Deref for FakeLockRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for FakeLockRef<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        if FAKELOCK_DEBUG {
+            self.lock.borrows.shared.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Mutable borrow of a [`FakeLock`].
+#[derive(Debug)]
+pub struct FakeLockRefMut<'a, T> {
+    lock: &'a FakeLock<T>,
+}
+
+impl<T> Deref for FakeLockRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for FakeLockRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for FakeLockRefMut<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        if FAKELOCK_DEBUG {
+            self.lock.borrows.mutable.fetch_sub(1, Ordering::Relaxed);
+        }
     }
 }
 
@@ -42,10 +228,41 @@ mod tests {
 
     #[test]
     fn test_boot_lock() {
-        fn use_static_str(_static_str: &'static str) {}
-        assert_eq!("", GLOBAL_TEST.get());
+        fn use_static_lock(_static_lock: &'static FakeLock<String>) {}
+        assert_eq!("", *GLOBAL_TEST.get());
         GLOBAL_TEST.get_mut().push_str("Moin");
-        assert_eq!("Moin", GLOBAL_TEST.get());
-        use_static_str(GLOBAL_TEST.get().as_str())
+        assert_eq!("Moin", *GLOBAL_TEST.get());
+        use_static_lock(&GLOBAL_TEST)
+    }
+
+    #[cfg(feature = "fakelock-debug")]
+    #[test]
+    fn test_borrows() {
+        let lock = FakeLock::new(0);
+        let a = lock.get();
+        let b = lock.get();
+        assert_eq!(*a + *b, 0);
+        drop((a, b));
+        *lock.get_mut() += 1;
+        assert_eq!(lock.borrows.shared.load(Ordering::SeqCst), 0);
+        assert_eq!(lock.borrows.mutable.load(Ordering::SeqCst), 0);
+    }
+
+    #[cfg(feature = "fakelock-debug")]
+    #[test]
+    #[should_panic(expected = "FakeLock: shared borrow at src/fakelock.rs")]
+    fn test_shared_while_mutable() {
+        let lock = FakeLock::new(0);
+        let _a = lock.get_mut();
+        let _b = lock.get();
+    }
+
+    #[cfg(feature = "fakelock-debug")]
+    #[test]
+    #[should_panic(expected = "while 1 shared borrow(s) are alive")]
+    fn test_mutable_while_shared() {
+        let lock = FakeLock::new(0);
+        let _a = lock.get();
+        let _b = lock.get_mut();
     }
 }
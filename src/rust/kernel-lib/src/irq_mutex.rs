@@ -0,0 +1,151 @@
+//! Module for [`IrqSafeMutex`].
+
+use crate::mutex::{Mutex, MutexGuard, RawLock};
+use crate::ticket_mutex::RawTicketLock;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Mutex that disables interrupts while it is held. Otherwise, an interrupt handler
+/// that needs the same lock, e.g. because it allocates heap memory, spins forever,
+/// because the interrupted lock holder never continues. The previous state of the
+/// interrupt flag is restored, when the guard is dropped.
+pub type IrqSafeMutex<T> = Mutex<RawIrqSafeLock<RawTicketLock>, T>;
+
+/// Guard of [`IrqSafeMutex`].
+pub type IrqSafeMutexGuard<'a, T> = MutexGuard<'a, RawIrqSafeLock<RawTicketLock>, T>;
+
+/// Access to the interrupt flag of the CPU. Exists, so that the locking logic can be
+/// tested on the host, where the flag can't be changed.
+pub trait InterruptFlag {
+    /// Disables interrupts. Returns whether they were enabled before.
+    fn disable() -> bool;
+
+    /// Enables interrupts.
+    fn enable();
+}
+
+/// `RFLAGS.IF` of x86_64. Only usable in ring 0.
+#[derive(Debug)]
+pub struct X86InterruptFlag;
+
+#[cfg(target_arch = "x86_64")]
+impl InterruptFlag for X86InterruptFlag {
+    fn disable() -> bool {
+        const RFLAGS_IF: u64 = 1 << 9;
+        let rflags: u64;
+        unsafe { core::arch::asm!("pushfq", "pop {}", "cli", out(reg) rflags) };
+        rflags & RFLAGS_IF != 0
+    }
+
+    fn enable() {
+        unsafe { core::arch::asm!("sti", options(nostack)) };
+    }
+}
+
+/// Wraps another [`RawLock`] and disables interrupts, before the lock is acquired.
+#[derive(Debug)]
+pub struct RawIrqSafeLock<R, I = X86InterruptFlag> {
+    inner: R,
+    /// State of the interrupt flag before the lock was acquired. Only written by the
+    /// lock holder.
+    interrupts_enabled: AtomicBool,
+    _flag: PhantomData<I>,
+}
+
+unsafe impl<R: RawLock, I: InterruptFlag> RawLock for RawIrqSafeLock<R, I> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self = Self {
+        inner: R::UNLOCKED,
+        interrupts_enabled: AtomicBool::new(false),
+        _flag: PhantomData,
+    };
+
+    fn lock(&self) {
+        // Interrupts stay disabled while spinning. Otherwise, an interrupt could take
+        // the lock between the acquisition and the disabling.
+        let enabled = I::disable();
+        self.inner.lock();
+        self.interrupts_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    fn try_lock(&self) -> bool {
+        let enabled = I::disable();
+        let locked = self.inner.try_lock();
+        if locked {
+            self.interrupts_enabled.store(enabled, Ordering::Relaxed);
+        } else if enabled {
+            I::enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        let enabled = self.interrupts_enabled.load(Ordering::Relaxed);
+        self.inner.unlock();
+        if enabled {
+            I::enable();
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutex::RawSpinLock;
+    use std::cell::Cell;
+
+    thread_local! {
+        static INTERRUPTS: Cell<bool> = Cell::new(true);
+    }
+
+    #[derive(Debug)]
+    struct FakeInterruptFlag;
+
+    impl InterruptFlag for FakeInterruptFlag {
+        fn disable() -> bool {
+            INTERRUPTS.with(|flag| flag.replace(false))
+        }
+
+        fn enable() {
+            INTERRUPTS.with(|flag| flag.set(true));
+        }
+    }
+
+    type FakeIrqSafeMutex<T> = Mutex<RawIrqSafeLock<RawSpinLock, FakeInterruptFlag>, T>;
+
+    fn interrupts_enabled() -> bool {
+        INTERRUPTS.with(Cell::get)
+    }
+
+    #[test]
+    fn test_interrupts_restored() {
+        let mutex = FakeIrqSafeMutex::new(0);
+
+        let outer = mutex.lock();
+        assert!(!interrupts_enabled());
+        // failed attempts don't change the flag
+        assert!(mutex.try_lock().is_none());
+        assert!(!interrupts_enabled());
+        drop(outer);
+        assert!(interrupts_enabled());
+
+        // nested locks: interrupts stay disabled until the outermost lock is released
+        let other = FakeIrqSafeMutex::new(0);
+        let outer = mutex.lock();
+        let inner = other.try_lock().unwrap();
+        drop(inner);
+        assert!(!interrupts_enabled());
+        drop(outer);
+        assert!(interrupts_enabled());
+
+        // interrupts that were disabled before stay disabled
+        FakeInterruptFlag::disable();
+        drop(mutex.lock());
+        assert!(!interrupts_enabled());
+        FakeInterruptFlag::enable();
+    }
+}
@@ -0,0 +1,632 @@
+//! Module for [`BuddyAllocator`].
+
+use crate::kernelheap::chunk_allocator::{ChunkAllocatorError, ChunkAllocatorStats};
+use crate::kernelheap::heap_allocator::HeapAllocator;
+use crate::mem::PAGE_SIZE;
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+
+pub const DEFAULT_BUDDY_BLOCK_SIZE: usize = 256;
+
+/// Maximum number of orders. Enough for every heap size in the address space.
+const MAX_ORDERS: usize = usize::BITS as usize;
+
+/// Free block of an order. The free blocks of each order build an intrusive doubly
+/// linked list, so that a block can be removed in O(1) when it merges with its buddy.
+#[derive(Debug)]
+struct FreeBlock {
+    prev: Option<NonNull<FreeBlock>>,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// Binary buddy allocator that takes mutable references to arbitrary external memory
+/// backing storages, like [`super::chunk_allocator::ChunkAllocator`]. Blocks of order
+/// `n` have a size of `BLOCK_SIZE * 2^n` and are naturally aligned to their size
+/// relative to the beginning of the heap. Allocation and deallocation take
+/// `O(log n)`. Large power-of-two allocations don't fragment the heap.
+///
+/// The metadata storage holds two bits for each node of the binary tree of blocks:
+/// whether the block is free and whether the block is allocated. Its size is returned
+/// by [`Self::metadata_size`]. The free blocks themselves are linked in free lists.
+///
+/// The heap size doesn't need to be a power of two. The tree covers the heap size
+/// rounded up to the next power of two, and blocks that are not completely inside
+/// the heap are never free.
+///
+/// It is mandatory to wrap this allocator by a mutex or a similar primitive, if it
+/// should be used in a global context.
+#[derive(Debug)]
+pub struct BuddyAllocator<'a, const BLOCK_SIZE: usize> {
+    heap: &'a mut [u8],
+    metadata: &'a mut [u8],
+    /// Order of the root of the tree of blocks.
+    max_order: usize,
+    free_lists: [Option<NonNull<FreeBlock>>; MAX_ORDERS],
+    /// Number of blocks of order 0 that are allocated.
+    used_blocks: usize,
+    /// See [`ChunkAllocatorStats::peak_used_chunks`].
+    peak_used_blocks: usize,
+    /// See [`ChunkAllocatorStats::alloc_count`].
+    alloc_count: usize,
+    /// See [`ChunkAllocatorStats::dealloc_count`].
+    dealloc_count: usize,
+}
+
+impl<'a, const BLOCK_SIZE: usize> BuddyAllocator<'a, BLOCK_SIZE> {
+    /// Returns the order of the root of the tree of blocks for a heap of the given size.
+    const fn max_order(heap_size: usize) -> usize {
+        let blocks = heap_size / BLOCK_SIZE;
+        let mut order = 0;
+        while (1 << order) < blocks {
+            order += 1;
+        }
+        order
+    }
+
+    /// Returns the required size of the metadata storage for a heap of the given size.
+    pub const fn metadata_size(heap_size: usize) -> usize {
+        // the tree is indexed from 1, like a binary heap
+        let nodes = 2 << Self::max_order(heap_size);
+        // one bitmap for the free bits and one for the allocated bits
+        2 * ((nodes + 7) / 8)
+    }
+
+    /// Creates a new allocator object. Verifies that the provided memory has the correct
+    /// properties.
+    /// - heap length must be a multiple of `BLOCK_SIZE`
+    /// - the heap must be aligned to `BLOCK_SIZE`
+    /// - the metadata length must be [`Self::metadata_size`]
+    pub fn new(heap: &'a mut [u8], metadata: &'a mut [u8]) -> Result<Self, ChunkAllocatorError> {
+        let is_bad_block_size =
+            !BLOCK_SIZE.is_power_of_two() || BLOCK_SIZE < core::mem::size_of::<FreeBlock>();
+        let is_empty = heap.is_empty();
+        let is_not_multiple_of_block_size = heap.len() % BLOCK_SIZE != 0;
+        let is_unaligned = heap.as_ptr() as usize % BLOCK_SIZE != 0;
+        if is_bad_block_size || is_empty || is_not_multiple_of_block_size || is_unaligned {
+            return Err(ChunkAllocatorError::BadHeapMemory);
+        }
+        if metadata.len() != Self::metadata_size(heap.len()) {
+            return Err(ChunkAllocatorError::BadBitmapMemory);
+        }
+        metadata.fill(0);
+
+        let mut alloc = Self {
+            max_order: Self::max_order(heap.len()),
+            heap,
+            metadata,
+            free_lists: [None; MAX_ORDERS],
+            used_blocks: 0,
+            peak_used_blocks: 0,
+            alloc_count: 0,
+            dealloc_count: 0,
+        };
+
+        // split the heap into the biggest blocks that fit
+        let mut offset = 0;
+        while offset < alloc.heap.len() {
+            let mut order = alloc.max_order;
+            while offset % Self::block_size(order) != 0
+                || offset + Self::block_size(order) > alloc.heap.len()
+            {
+                order -= 1;
+            }
+            unsafe { alloc.push_free(order, offset) };
+            offset += Self::block_size(order);
+        }
+        Ok(alloc)
+    }
+
+    /// Creates a new allocator object that manages the given memory region. The metadata
+    /// is carved out of the beginning of the region, the remaining memory becomes the
+    /// heap. The heap begins page-aligned, because blocks are only aligned relative to
+    /// the beginning of the heap. Hence, alignments up to a page are supported.
+    pub fn new_in_region(region: &'a mut [u8]) -> Result<Self, ChunkAllocatorError> {
+        let region_begin = region.as_ptr() as usize;
+        let region_end = region_begin + region.len();
+        // upper bound; the heap is smaller than the region
+        let metadata_size = Self::metadata_size(region.len());
+        let heap_align = core::cmp::max(BLOCK_SIZE, PAGE_SIZE);
+        let heap_begin = (region_begin + metadata_size + heap_align - 1) / heap_align * heap_align;
+        if heap_begin + BLOCK_SIZE > region_end {
+            return Err(ChunkAllocatorError::BadHeapMemory);
+        }
+        let heap_len = (region_end - heap_begin) / BLOCK_SIZE * BLOCK_SIZE;
+
+        let (metadata, heap) = region.split_at_mut(heap_begin - region_begin);
+        let metadata = &mut metadata[..Self::metadata_size(heap_len)];
+        Self::new(&mut heap[..heap_len], metadata)
+    }
+
+    /// Returns whether the pointer points into the heap memory of this allocator.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let heap_begin = self.heap.as_ptr() as usize;
+        (heap_begin..heap_begin + self.heap.len()).contains(&(ptr as usize))
+    }
+
+    /// Capacity in bytes of the allocator.
+    pub fn capacity(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Returns the size in bytes of blocks of the given order.
+    const fn block_size(order: usize) -> usize {
+        BLOCK_SIZE << order
+    }
+
+    /// Returns the smallest order whose blocks can hold the layout. As blocks are
+    /// naturally aligned, the alignment is covered as well.
+    fn order_for(&self, layout: Layout) -> Result<usize, ChunkAllocatorError> {
+        let size = core::cmp::max(layout.size(), layout.align());
+        let blocks = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let order = blocks.next_power_of_two().trailing_zeros() as usize;
+        if order > self.max_order {
+            Err(ChunkAllocatorError::OutOfMemory)
+        } else {
+            Ok(order)
+        }
+    }
+
+    /// Returns the index of the block in the tree of blocks.
+    fn node_index(&self, order: usize, offset: usize) -> usize {
+        (1 << (self.max_order - order)) + offset / Self::block_size(order)
+    }
+
+    fn metadata_bit(&self, bitmap: usize, node: usize) -> bool {
+        let byte = bitmap * self.metadata.len() / 2 + node / 8;
+        (self.metadata[byte] >> (node % 8)) & 1 == 1
+    }
+
+    fn set_metadata_bit(&mut self, bitmap: usize, node: usize, value: bool) {
+        let byte = bitmap * self.metadata.len() / 2 + node / 8;
+        if value {
+            self.metadata[byte] |= 1 << (node % 8);
+        } else {
+            self.metadata[byte] &= !(1 << (node % 8));
+        }
+    }
+
+    fn is_free(&self, order: usize, offset: usize) -> bool {
+        self.metadata_bit(0, self.node_index(order, offset))
+    }
+
+    fn is_allocated(&self, order: usize, offset: usize) -> bool {
+        self.metadata_bit(1, self.node_index(order, offset))
+    }
+
+    fn set_allocated(&mut self, order: usize, offset: usize, allocated: bool) {
+        self.set_metadata_bit(1, self.node_index(order, offset), allocated);
+    }
+
+    /// Adds the block to the free list of its order.
+    unsafe fn push_free(&mut self, order: usize, offset: usize) {
+        let block = NonNull::new_unchecked(self.heap.as_mut_ptr().add(offset)).cast::<FreeBlock>();
+        let head = self.free_lists[order];
+        block.as_ptr().write(FreeBlock {
+            prev: None,
+            next: head,
+        });
+        if let Some(head) = head {
+            (*head.as_ptr()).prev = Some(block);
+        }
+        self.free_lists[order] = Some(block);
+        self.set_metadata_bit(0, self.node_index(order, offset), true);
+    }
+
+    /// Removes the block from the free list of its order.
+    unsafe fn remove_free(&mut self, order: usize, offset: usize) {
+        let block = self.heap.as_mut_ptr().add(offset).cast::<FreeBlock>();
+        let FreeBlock { prev, next } = block.read();
+        match prev {
+            Some(prev) => (*prev.as_ptr()).next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            (*next.as_ptr()).prev = prev;
+        }
+        self.set_metadata_bit(0, self.node_index(order, offset), false);
+    }
+
+    fn block_offset(&self, block: NonNull<FreeBlock>) -> usize {
+        block.as_ptr() as usize - self.heap.as_ptr() as usize
+    }
+
+    /// Verifies that `ptr` and `layout` describe a live allocation of this allocator.
+    /// Returns the offset and the order of the block.
+    fn check_allocation(
+        &self,
+        ptr: *const u8,
+        layout: Layout,
+    ) -> Result<(usize, usize), ChunkAllocatorError> {
+        if !self.contains(ptr) {
+            return Err(ChunkAllocatorError::InvalidPointer);
+        }
+        let offset = ptr as usize - self.heap.as_ptr() as usize;
+        if offset % BLOCK_SIZE != 0 {
+            return Err(ChunkAllocatorError::InvalidPointer);
+        }
+        let order = self
+            .order_for(layout)
+            .map_err(|_| ChunkAllocatorError::LayoutMismatch)?;
+        if offset % Self::block_size(order) == 0 && self.is_allocated(order, offset) {
+            return Ok((offset, order));
+        }
+
+        // the block or a block that contains it is free
+        let is_free = (0..=self.max_order).any(|order| {
+            let block_offset = offset / Self::block_size(order) * Self::block_size(order);
+            self.is_free(order, block_offset)
+        });
+        if is_free {
+            Err(ChunkAllocatorError::DoubleFree)
+        } else {
+            Err(ChunkAllocatorError::LayoutMismatch)
+        }
+    }
+
+    /// Returns a pointer to the beginning of the allocation or
+    /// [`ChunkAllocatorError::OutOfMemory`], if there is no free block that is big enough.
+    /// Alignments beyond the alignment of the heap itself result in
+    /// [`ChunkAllocatorError::UnsupportedAlignment`].
+    pub fn try_alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        if self.heap.as_ptr() as usize % layout.align() != 0 {
+            return Err(ChunkAllocatorError::UnsupportedAlignment);
+        }
+        let order = self.order_for(layout)?;
+        let (mut block_order, block) = (order..=self.max_order)
+            .find_map(|order| self.free_lists[order].map(|block| (order, block)))
+            .ok_or(ChunkAllocatorError::OutOfMemory)?;
+        let offset = self.block_offset(block);
+
+        unsafe {
+            self.remove_free(block_order, offset);
+            // split until the block has the right size; the upper halves become free
+            while block_order > order {
+                block_order -= 1;
+                self.push_free(block_order, offset + Self::block_size(block_order));
+            }
+        }
+        self.set_allocated(order, offset, true);
+
+        self.used_blocks += 1 << order;
+        self.peak_used_blocks = core::cmp::max(self.peak_used_blocks, self.used_blocks);
+        self.alloc_count += 1;
+        Ok(unsafe { NonNull::new_unchecked(self.heap.as_mut_ptr().add(offset)) })
+    }
+
+    /// Frees the allocation and merges it with its buddies as far as possible. Verifies
+    /// that `ptr` and `layout` describe an allocation of this allocator before. On error,
+    /// the state of the allocator is unchanged.
+    ///
+    /// # Safety
+    /// The memory of the allocation must not be used after this call.
+    pub unsafe fn try_dealloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<(), ChunkAllocatorError> {
+        let (mut offset, mut order) = self.check_allocation(ptr, layout)?;
+        self.set_allocated(order, offset, false);
+        self.used_blocks -= 1 << order;
+        self.dealloc_count += 1;
+
+        while order < self.max_order {
+            let buddy = offset ^ Self::block_size(order);
+            // buddies beyond the end of the heap are never free
+            if buddy >= self.heap.len() || !self.is_free(order, buddy) {
+                break;
+            }
+            self.remove_free(order, buddy);
+            offset = core::cmp::min(offset, buddy);
+            order += 1;
+        }
+        self.push_free(order, offset);
+        Ok(())
+    }
+
+    /// Resizes the allocation `ptr` with `layout` to `new_size` bytes. Shrinking happens
+    /// in place, the released upper halves become free. If the new size needs a bigger
+    /// block, the data gets moved. On error, the old allocation stays untouched.
+    ///
+    /// # Safety
+    /// Same contract as [`core::alloc::GlobalAlloc::realloc`].
+    pub unsafe fn try_realloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        let (offset, order) = self.check_allocation(ptr, layout)?;
+        let new_layout = Layout::from_size_align(new_size, layout.align())
+            .map_err(|_| ChunkAllocatorError::OutOfMemory)?;
+        let new_order = self.order_for(new_layout)?;
+
+        if new_order <= order {
+            self.set_allocated(order, offset, false);
+            self.set_allocated(new_order, offset, true);
+            for order in new_order..order {
+                self.push_free(order, offset + Self::block_size(order));
+            }
+            self.used_blocks -= (1 << order) - (1 << new_order);
+            return Ok(NonNull::new_unchecked(ptr));
+        }
+
+        let new_ptr = self.try_alloc(new_layout)?;
+        ptr::copy_nonoverlapping(ptr, new_ptr.as_ptr(), layout.size());
+        self.try_dealloc(ptr, layout).unwrap();
+        Ok(new_ptr)
+    }
+
+    /// Returns a snapshot of the usage and the fragmentation of the heap. A chunk in
+    /// the stats is a block of order 0. The free runs are the free blocks.
+    pub fn stats(&self) -> ChunkAllocatorStats {
+        let mut free_runs = 0;
+        let mut largest_free_run = 0;
+        for (order, head) in self.free_lists.iter().enumerate() {
+            let mut block = *head;
+            while let Some(free_block) = block {
+                free_runs += 1;
+                largest_free_run = 1 << order;
+                block = unsafe { free_block.as_ref().next };
+            }
+        }
+
+        ChunkAllocatorStats {
+            chunk_size: BLOCK_SIZE,
+            used_chunks: self.used_blocks,
+            free_chunks: self.heap.len() / BLOCK_SIZE - self.used_blocks,
+            largest_free_run,
+            free_runs,
+            peak_used_chunks: self.peak_used_blocks,
+            alloc_count: self.alloc_count,
+            dealloc_count: self.dealloc_count,
+        }
+    }
+}
+
+impl<'a, const BLOCK_SIZE: usize> HeapAllocator<'a> for BuddyAllocator<'a, BLOCK_SIZE> {
+    const BLOCK_SIZE: usize = BLOCK_SIZE;
+
+    fn new(heap: &'a mut [u8], metadata: &'a mut [u8]) -> Result<Self, ChunkAllocatorError> {
+        BuddyAllocator::new(heap, metadata)
+    }
+
+    fn new_in_region(region: &'a mut [u8]) -> Result<Self, ChunkAllocatorError> {
+        BuddyAllocator::new_in_region(region)
+    }
+
+    fn contains(&self, ptr: *const u8) -> bool {
+        BuddyAllocator::contains(self, ptr)
+    }
+
+    fn capacity(&self) -> usize {
+        BuddyAllocator::capacity(self)
+    }
+
+    fn stats(&self) -> ChunkAllocatorStats {
+        BuddyAllocator::stats(self)
+    }
+
+    fn try_alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        BuddyAllocator::try_alloc(self, layout)
+    }
+
+    unsafe fn try_dealloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<(), ChunkAllocatorError> {
+        BuddyAllocator::try_dealloc(self, ptr, layout)
+    }
+
+    unsafe fn try_realloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        BuddyAllocator::try_realloc(self, ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::PageAlignedByteBuf;
+
+    const BLOCK: usize = DEFAULT_BUDDY_BLOCK_SIZE;
+
+    fn new_allocator<const HEAP_SIZE: usize>() -> BuddyAllocator<'static, BLOCK> {
+        let heap = Box::leak(Box::new(PageAlignedByteBuf::<HEAP_SIZE>::new_zeroed()));
+        let metadata_size = BuddyAllocator::<BLOCK>::metadata_size(HEAP_SIZE);
+        let metadata = Box::leak(vec![0_u8; metadata_size].into_boxed_slice());
+        BuddyAllocator::new(heap.get_mut(), metadata).unwrap()
+    }
+
+    #[test]
+    fn test_metadata_size() {
+        assert_eq!(BuddyAllocator::<BLOCK>::metadata_size(BLOCK), 2);
+        // 16 blocks => 31 nodes
+        assert_eq!(BuddyAllocator::<BLOCK>::metadata_size(16 * BLOCK), 8);
+        // rounded up to 16 blocks
+        assert_eq!(BuddyAllocator::<BLOCK>::metadata_size(9 * BLOCK), 8);
+
+        let mut heap = PageAlignedByteBuf::<{ 16 * BLOCK }>::new_zeroed();
+        let mut metadata = vec![0_u8; 7];
+        assert_eq!(
+            BuddyAllocator::<BLOCK>::new(heap.get_mut(), &mut metadata).unwrap_err(),
+            ChunkAllocatorError::BadBitmapMemory
+        );
+    }
+
+    #[test]
+    fn test_alloc_split_and_merge() {
+        let mut alloc = new_allocator::<{ 16 * BLOCK }>();
+        assert_eq!(alloc.stats().free_runs, 1);
+        assert_eq!(alloc.stats().largest_free_run, 16);
+
+        let layout = Layout::from_size_align(BLOCK, 1).unwrap();
+        let a = alloc.try_alloc(layout).unwrap();
+        // 1 + 1 + 2 + 4 + 8
+        let stats = alloc.stats();
+        assert_eq!(stats.free_runs, 4);
+        assert_eq!(stats.used_chunks, 1);
+        assert_eq!(stats.largest_free_run, 8);
+
+        let big = Layout::from_size_align(5 * BLOCK, 1).unwrap();
+        let b = alloc.try_alloc(big).unwrap();
+        assert_eq!(b.as_ptr() as usize - a.as_ptr() as usize, 8 * BLOCK);
+        assert_eq!(
+            alloc.try_alloc(big).unwrap_err(),
+            ChunkAllocatorError::OutOfMemory
+        );
+
+        unsafe {
+            alloc.try_dealloc(b.as_ptr(), big).unwrap();
+            alloc.try_dealloc(a.as_ptr(), layout).unwrap();
+        }
+        // everything merged again
+        let stats = alloc.stats();
+        assert_eq!(stats.free_runs, 1);
+        assert_eq!(stats.largest_free_run, 16);
+        assert_eq!(stats.peak_used_chunks, 9);
+        let whole = Layout::from_size_align(16 * BLOCK, 1).unwrap();
+        assert!(alloc.try_alloc(whole).is_ok());
+    }
+
+    #[test]
+    fn test_alignment() {
+        let mut alloc = new_allocator::<{ 16 * BLOCK }>();
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let _ = alloc.try_alloc(small).unwrap();
+        let aligned = Layout::from_size_align(BLOCK, 4 * BLOCK).unwrap();
+        let ptr = alloc.try_alloc(aligned).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % (4 * BLOCK), 0);
+
+        // blocks of two pages exist, but the heap itself is only aligned to one page
+        let memory = Box::leak(Box::new(
+            PageAlignedByteBuf::<{ 4 * PAGE_SIZE }>::new_zeroed(),
+        ));
+        let memory = memory.get_mut();
+        let offset = if memory.as_ptr() as usize % (2 * PAGE_SIZE) == 0 {
+            PAGE_SIZE
+        } else {
+            0
+        };
+        let heap = &mut memory[offset..offset + 2 * PAGE_SIZE];
+        let metadata_size = BuddyAllocator::<BLOCK>::metadata_size(heap.len());
+        let metadata = Box::leak(vec![0_u8; metadata_size].into_boxed_slice());
+        let mut alloc = BuddyAllocator::<BLOCK>::new(heap, metadata).unwrap();
+        let over_aligned = Layout::from_size_align(8, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(
+            alloc.try_alloc(over_aligned),
+            Err(ChunkAllocatorError::UnsupportedAlignment)
+        );
+    }
+
+    #[test]
+    fn test_dealloc_errors() {
+        let mut alloc = new_allocator::<{ 16 * BLOCK }>();
+        let layout = Layout::from_size_align(2 * BLOCK, 1).unwrap();
+        let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+        unsafe {
+            assert_eq!(
+                alloc.try_dealloc(ptr.add(1), layout),
+                Err(ChunkAllocatorError::InvalidPointer)
+            );
+            assert_eq!(
+                alloc.try_dealloc(ptr, Layout::from_size_align(BLOCK, 1).unwrap()),
+                Err(ChunkAllocatorError::LayoutMismatch)
+            );
+            assert_eq!(
+                alloc.try_dealloc(ptr.add(4 * BLOCK), layout),
+                Err(ChunkAllocatorError::DoubleFree)
+            );
+            alloc.try_dealloc(ptr, layout).unwrap();
+            assert_eq!(
+                alloc.try_dealloc(ptr, layout),
+                Err(ChunkAllocatorError::DoubleFree)
+            );
+        }
+    }
+
+    #[test]
+    fn test_realloc() {
+        let mut alloc = new_allocator::<{ 16 * BLOCK }>();
+        let layout = Layout::from_size_align(4 * BLOCK, 1).unwrap();
+        unsafe {
+            let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+            ptr.write_bytes(0xab, 4 * BLOCK);
+
+            // shrink in place
+            let shrunk = alloc.try_realloc(ptr, layout, BLOCK).unwrap().as_ptr();
+            assert_eq!(ptr, shrunk);
+            assert_eq!(alloc.stats().used_chunks, 1);
+            let layout = Layout::from_size_align(BLOCK, 1).unwrap();
+
+            // grow by moving
+            let grown = alloc
+                .try_realloc(shrunk, layout, 3 * BLOCK)
+                .unwrap()
+                .as_ptr();
+            assert!(core::slice::from_raw_parts(grown, BLOCK)
+                .iter()
+                .all(|b| *b == 0xab));
+            let layout = Layout::from_size_align(3 * BLOCK, 1).unwrap();
+            alloc.try_dealloc(grown, layout).unwrap();
+            assert_eq!(alloc.stats().free_runs, 1);
+        }
+    }
+
+    #[test]
+    fn test_new_in_region() {
+        // not a power of two; up to a page is lost to the alignment of the heap
+        let region = Box::leak(vec![0_u8; 23 * BLOCK + PAGE_SIZE].into_boxed_slice());
+        let region_range = region.as_ptr_range();
+        let mut alloc = BuddyAllocator::<BLOCK>::new_in_region(region).unwrap();
+        let capacity = alloc.capacity();
+        assert!(capacity >= 21 * BLOCK && capacity % BLOCK == 0);
+        assert_eq!(alloc.stats().free_chunks, capacity / BLOCK);
+
+        // allocate everything in single blocks
+        let layout = Layout::from_size_align(BLOCK, 1).unwrap();
+        let ptrs = (0..capacity / BLOCK)
+            .map(|_| alloc.try_alloc(layout).unwrap().as_ptr())
+            .collect::<Vec<_>>();
+        assert!(ptrs
+            .iter()
+            .all(|ptr| region_range.contains(&(*ptr as *const u8))));
+        assert!(alloc.try_alloc(layout).is_err());
+        for ptr in ptrs {
+            unsafe { alloc.try_dealloc(ptr, layout).unwrap() };
+        }
+        assert_eq!(alloc.stats().used_chunks, 0);
+
+        let region = Box::leak(vec![0_u8; BLOCK].into_boxed_slice());
+        assert_eq!(
+            BuddyAllocator::<BLOCK>::new_in_region(region).unwrap_err(),
+            ChunkAllocatorError::BadHeapMemory
+        );
+    }
+
+    /// The heap of a region at an arbitrary address supports page-aligned allocations.
+    #[test]
+    fn test_new_in_region_page_aligned() {
+        let memory = Box::leak(Box::new(
+            PageAlignedByteBuf::<{ 8 * PAGE_SIZE }>::new_zeroed(),
+        ));
+        // neither page- nor block-aligned
+        let region = &mut memory.get_mut()[BLOCK + 8..];
+        let mut alloc = BuddyAllocator::<BLOCK>::new_in_region(region).unwrap();
+
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let _ = alloc.try_alloc(small).unwrap();
+        for align in [2 * BLOCK, PAGE_SIZE] {
+            let layout = Layout::from_size_align(8, align).unwrap();
+            let ptr = alloc.try_alloc(layout).unwrap();
+            assert_eq!(ptr.as_ptr() as usize % align, 0);
+        }
+        let page = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let ptr = alloc.try_alloc(page).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % PAGE_SIZE, 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! Module for [`BumpArena`].
+
+use core::alloc::{AllocError, Allocator, Layout};
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+
+/// Arena that hands out memory by bumping an offset into borrowed memory. Individual
+/// deallocations are no-ops, except for the most recent allocation, which is rolled
+/// back. The whole arena is freed at once with [`Self::reset`] or when the borrowed
+/// memory is released.
+///
+/// It implements [`Allocator`], hence data of a boot phase can live in collections
+/// like `Vec<T, &BumpArena>`. Such collections borrow the arena, so they can't outlive
+/// it.
+///
+/// The arena is not [`Sync`]. It is meant to be used by a single core during one
+/// phase of the boot process.
+#[derive(Debug)]
+pub struct BumpArena<'a> {
+    begin: *mut u8,
+    size: usize,
+    /// Offset of the first free byte.
+    offset: Cell<usize>,
+    /// Offset of the most recent allocation. Used to roll it back or grow it in place.
+    last: Cell<usize>,
+    _memory: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> BumpArena<'a> {
+    /// Creates a new arena that hands out the given memory.
+    pub fn new(memory: &'a mut [u8]) -> Self {
+        Self {
+            begin: memory.as_mut_ptr(),
+            size: memory.len(),
+            offset: Cell::new(0),
+            last: Cell::new(0),
+            _memory: PhantomData,
+        }
+    }
+
+    /// Capacity in bytes of the arena.
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+
+    /// Number of bytes that are currently in use, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.offset.get()
+    }
+
+    /// Number of bytes that are still available.
+    pub fn remaining(&self) -> usize {
+        self.size - self.offset.get()
+    }
+
+    /// Frees all allocations at once. The exclusive borrow guarantees that no
+    /// collection uses the arena anymore.
+    pub fn reset(&mut self) {
+        self.offset.set(0);
+        self.last.set(0);
+    }
+
+    /// Returns whether the pointer and the layout describe the most recent allocation.
+    /// The size is compared as well, because a zero-sized allocation and the following
+    /// allocation may begin at the same address.
+    fn is_last(&self, ptr: NonNull<u8>, layout: Layout) -> bool {
+        ptr.as_ptr() as usize == self.begin as usize + self.last.get()
+            && self.offset.get() - self.last.get() == layout.size()
+    }
+
+    /// Builds the slice pointer that [`Allocator`] returns.
+    fn block(&self, offset: usize, size: usize) -> NonNull<[u8]> {
+        let ptr = unsafe { self.begin.add(offset) };
+        NonNull::new(ptr::slice_from_raw_parts_mut(ptr, size)).unwrap()
+    }
+}
+
+unsafe impl<'a> Allocator for BumpArena<'a> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let addr = self.begin as usize + self.offset.get();
+        let padding = (layout.align() - addr % layout.align()) % layout.align();
+        let offset = self.offset.get() + padding;
+        if layout.size() > self.size - core::cmp::min(offset, self.size) {
+            return Err(AllocError);
+        }
+        self.offset.set(offset + layout.size());
+        self.last.set(offset);
+        Ok(self.block(offset, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.is_last(ptr, layout) {
+            self.offset.set(self.last.get());
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let aligned = ptr.as_ptr() as usize % new_layout.align() == 0;
+        if self.is_last(ptr, old_layout)
+            && aligned
+            && new_layout.size() <= self.size - self.last.get()
+        {
+            self.offset.set(self.last.get() + new_layout.size());
+            return Ok(self.block(self.last.get(), new_layout.size()));
+        }
+
+        let new_ptr = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.as_ptr().cast::<u8>(),
+            old_layout.size(),
+        );
+        Ok(new_ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc() {
+        let memory = Box::leak(vec![0_u8; 256].into_boxed_slice());
+        let arena = BumpArena::new(memory);
+
+        let a = arena.allocate(Layout::new::<u8>()).unwrap();
+        let b = arena.allocate(Layout::new::<u64>()).unwrap();
+        assert_eq!(b.as_ptr().cast::<u8>() as usize % 8, 0);
+        assert!(b.as_ptr().cast::<u8>() > a.as_ptr().cast::<u8>());
+        assert_eq!(b.len(), 8);
+
+        // only the most recent allocation is rolled back
+        let used = arena.used();
+        unsafe { arena.deallocate(a.cast(), Layout::new::<u8>()) };
+        assert_eq!(arena.used(), used);
+        unsafe { arena.deallocate(b.cast(), Layout::new::<u64>()) };
+        assert!(arena.used() < used);
+
+        assert_eq!(
+            arena.allocate(Layout::from_size_align(arena.remaining() + 1, 1).unwrap()),
+            Err(AllocError)
+        );
+        assert!(arena
+            .allocate(Layout::from_size_align(arena.remaining(), 1).unwrap())
+            .is_ok());
+        assert_eq!(arena.remaining(), 0);
+    }
+
+    #[test]
+    fn test_zero_sized_alloc() {
+        let memory = Box::leak(vec![0_u8; 256].into_boxed_slice());
+        let arena = BumpArena::new(memory);
+
+        // both allocations begin at the same address
+        let zst = arena.allocate(Layout::new::<()>()).unwrap();
+        let a = arena.allocate(Layout::new::<[u8; 16]>()).unwrap();
+        assert_eq!(zst.as_ptr().cast::<u8>(), a.as_ptr().cast::<u8>());
+
+        // the zero-sized allocation doesn't roll back the live bytes
+        let used = arena.used();
+        unsafe { arena.deallocate(zst.cast(), Layout::new::<()>()) };
+        assert_eq!(arena.used(), used);
+        unsafe { arena.deallocate(a.cast(), Layout::new::<[u8; 16]>()) };
+        assert_eq!(arena.used(), 0);
+    }
+
+    #[test]
+    fn test_vec_in_arena() {
+        let memory = Box::leak(vec![0_u8; 1024].into_boxed_slice());
+        let mut arena = BumpArena::new(memory);
+        {
+            let mut vec = Vec::new_in(&arena);
+            for i in 0..100_u32 {
+                vec.push(i);
+            }
+            assert_eq!(vec.iter().sum::<u32>(), 4950);
+            // the vector always was the most recent allocation and grew in place
+            assert!(arena.used() < (vec.capacity() + 1) * 4);
+        }
+        arena.reset();
+        assert_eq!(arena.used(), 0);
+        assert_eq!(arena.remaining(), 1024);
+    }
+}
@@ -1,10 +1,12 @@
 //! Module for [`ChunkAllocator`].
 
+use crate::kernelheap::heap_allocator::HeapAllocator;
 use core::alloc::Layout;
+use core::ptr::{self, NonNull};
 
 /// Possible errors for [`ChunkAllocator`].
 /// TODO make more generic ?! later use in roottask and native hedron app with different allocator frontends?
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ChunkAllocatorError {
     /// The backing memory for the heap must be
     /// - an even multiple of [`DEFAULT_ALLOCATOR_CHUNK_SIZE`], and
@@ -13,10 +15,100 @@ pub enum ChunkAllocatorError {
     /// The number of bits in the backing memory for the heap bitmap
     /// must match the number of chunks in the heap.
     BadBitmapMemory,
+    /// There is no coherent range of free chunks that satisfies the requested layout.
+    OutOfMemory,
+    /// The pointer is outside of the backing memory or doesn't point to the beginning
+    /// of a chunk.
+    InvalidPointer,
+    /// The chunk the pointer points to is already free.
+    DoubleFree,
+    /// The layout doesn't match the allocation the pointer belongs to, i.e. the
+    /// pointer is not properly aligned or the allocation covers less chunks.
+    LayoutMismatch,
+    /// The guard bytes around the allocation were overwritten. Only detected with
+    /// the `heap-debug` feature.
+    CorruptedRedZone,
+    /// The alignment of the layout is bigger than the alignment of the heap memory.
+    /// Only reported by allocators that can't compensate for this.
+    UnsupportedAlignment,
 }
 
 pub const DEFAULT_ALLOCATOR_CHUNK_SIZE: usize = 256;
 
+/// Whether the `heap-debug` feature is active. In this mode, the allocator
+/// - fills freed chunks with [`POISON_BYTE`],
+/// - surrounds each allocation with [`RED_ZONE_SIZE`] guard bytes of [`RED_ZONE_BYTE`]
+///   and verifies them on dealloc, and
+/// - stores the layout of each allocation in front of it and verifies it on dealloc.
+pub const HEAP_DEBUG: bool = cfg!(feature = "heap-debug");
+/// Byte pattern of freed memory in `heap-debug` mode.
+pub const POISON_BYTE: u8 = 0xde;
+/// Byte pattern of the guard bytes around allocations in `heap-debug` mode.
+pub const RED_ZONE_BYTE: u8 = 0xfd;
+/// Minimal number of guard bytes on each side of an allocation in `heap-debug` mode.
+pub const RED_ZONE_SIZE: usize = 16;
+
+/// Metadata in front of each allocation in `heap-debug` mode. It is followed
+/// by the front red zone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+struct DebugHeader {
+    size: usize,
+    align: usize,
+}
+
+/// Snapshot of the usage and the fragmentation of a [`ChunkAllocator`].
+/// See [`ChunkAllocator::stats`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ChunkAllocatorStats {
+    /// Size of a chunk in bytes.
+    pub chunk_size: usize,
+    /// Number of chunks that are currently in use.
+    pub used_chunks: usize,
+    /// Number of chunks that are currently free.
+    pub free_chunks: usize,
+    /// Length in chunks of the longest range of coherent free chunks. This is an
+    /// upper bound for the biggest allocation that can currently succeed.
+    pub largest_free_run: usize,
+    /// Number of ranges of coherent free chunks. The more runs for the same amount
+    /// of free chunks, the higher the fragmentation.
+    pub free_runs: usize,
+    /// Highest number of chunks that were in use at the same time.
+    pub peak_used_chunks: usize,
+    /// Number of successful allocations. A reallocation that moves the data counts
+    /// as one allocation and one deallocation.
+    pub alloc_count: usize,
+    /// Number of successful deallocations.
+    pub dealloc_count: usize,
+}
+
+impl ChunkAllocatorStats {
+    /// Returns the number of bytes in use.
+    pub const fn used_bytes(&self) -> usize {
+        self.used_chunks * self.chunk_size
+    }
+
+    /// Returns the number of free bytes.
+    pub const fn free_bytes(&self) -> usize {
+        self.free_chunks * self.chunk_size
+    }
+
+    /// Combines the stats of two allocators, e.g. of multiple heap regions. The peak
+    /// usage of the combination is the sum of both peaks, hence only an upper bound.
+    pub fn combine(&self, other: &Self) -> Self {
+        Self {
+            chunk_size: core::cmp::max(self.chunk_size, other.chunk_size),
+            used_chunks: self.used_chunks + other.used_chunks,
+            free_chunks: self.free_chunks + other.free_chunks,
+            largest_free_run: core::cmp::max(self.largest_free_run, other.largest_free_run),
+            free_runs: self.free_runs + other.free_runs,
+            peak_used_chunks: self.peak_used_chunks + other.peak_used_chunks,
+            alloc_count: self.alloc_count + other.alloc_count,
+            dealloc_count: self.dealloc_count + other.dealloc_count,
+        }
+    }
+}
+
 /// First-fit allocator that takes mutable references to arbitrary external memory
 /// backing storages. It uses them to manage memory. It is mandatory to wrap
 /// this allocator by a mutex or a similar primitive, if it should be used
@@ -34,6 +126,18 @@ pub const DEFAULT_ALLOCATOR_CHUNK_SIZE: usize = 256;
 pub struct ChunkAllocator<'a, const CHUNK_SIZE: usize> {
     heap: &'a mut [u8],
     bitmap: &'a mut [u8],
+    /// Index of the first chunk of the tail of the heap, that was never handed out.
+    /// All chunks starting at this index are known to contain zeroes. This way,
+    /// [`Self::try_alloc_zeroed`] doesn't need to zero memory that is still zeroed.
+    zeroed_from: usize,
+    /// Number of chunks that are marked as used in the bitmap.
+    used_chunks: usize,
+    /// See [`ChunkAllocatorStats::peak_used_chunks`].
+    peak_used_chunks: usize,
+    /// See [`ChunkAllocatorStats::alloc_count`].
+    alloc_count: usize,
+    /// See [`ChunkAllocatorStats::dealloc_count`].
+    dealloc_count: usize,
 }
 
 impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
@@ -53,6 +157,9 @@ impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
     /// Creates a new allocator object. Verifies that the provided memory has the correct properties.
     /// - heap length must be a multiple of `CHUNK_SIZE`
     /// - the heap must be >= 0
+    ///
+    /// The content of the heap memory is unknown, hence [`Self::try_alloc_zeroed`]
+    /// always zeroes the memory. See [`Self::new_zeroed`].
     pub const fn new(
         heap: &'a mut [u8],
         bitmap: &'a mut [u8],
@@ -70,7 +177,78 @@ impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
             return Err(ChunkAllocatorError::BadBitmapMemory);
         }
 
-        Ok(Self { heap, bitmap })
+        // the caller may hand in a bitmap with chunks that are already used
+        let mut used_chunks = 0;
+        let mut i = 0;
+        while i < bitmap.len() {
+            used_chunks += bitmap[i].count_ones() as usize;
+            i += 1;
+        }
+
+        let zeroed_from = heap.len() / CHUNK_SIZE;
+        Ok(Self {
+            heap,
+            bitmap,
+            zeroed_from,
+            used_chunks,
+            peak_used_chunks: used_chunks,
+            alloc_count: 0,
+            dealloc_count: 0,
+        })
+    }
+
+    /// Like [`Self::new`] but the allocator knows that the heap memory is zeroed.
+    /// Therefore, [`Self::try_alloc_zeroed`] can skip zeroing of chunks, that were
+    /// never handed out before. This is useful for heaps in the `.bss` section.
+    ///
+    /// # Safety
+    /// The whole heap memory must contain zeroes.
+    pub const unsafe fn new_zeroed(
+        heap: &'a mut [u8],
+        bitmap: &'a mut [u8],
+    ) -> Result<Self, ChunkAllocatorError> {
+        match Self::new(heap, bitmap) {
+            Ok(mut alloc) => {
+                alloc.zeroed_from = 0;
+                Ok(alloc)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Creates a new allocator object that manages the given memory region. The bitmap
+    /// is carved out of the beginning of the region, the remaining chunk-aligned memory
+    /// becomes the heap. The content of the region is unknown, hence the memory is not
+    /// considered as zeroed.
+    pub fn new_in_region(region: &'a mut [u8]) -> Result<Self, ChunkAllocatorError> {
+        let region_begin = region.as_ptr() as usize;
+        // Each byte of the bitmap covers 8 chunks. The alignment of the heap may cost
+        // some additional bytes, hence the number can be slightly lower.
+        let mut bitmap_len = region.len() / (8 * CHUNK_SIZE + 1);
+        let heap_offset = loop {
+            if bitmap_len == 0 {
+                return Err(ChunkAllocatorError::BadHeapMemory);
+            }
+            let heap_begin = (region_begin + bitmap_len + CHUNK_SIZE - 1) / CHUNK_SIZE * CHUNK_SIZE;
+            let heap_offset = heap_begin - region_begin;
+            if heap_offset + bitmap_len * 8 * CHUNK_SIZE <= region.len() {
+                break heap_offset;
+            }
+            bitmap_len -= 1;
+        };
+
+        let (bitmap, heap) = region.split_at_mut(heap_offset);
+        let bitmap = &mut bitmap[..bitmap_len];
+        // all chunks are free
+        bitmap.fill(0);
+        let heap = &mut heap[..bitmap_len * 8 * CHUNK_SIZE];
+        Self::new(heap, bitmap)
+    }
+
+    /// Returns whether the pointer points into the heap memory of this allocator.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let heap_begin = self.heap.as_ptr() as usize;
+        (heap_begin..heap_begin + self.heap.len()).contains(&(ptr as usize))
     }
 
     /// Capacity in bytes of the allocator.
@@ -108,6 +286,8 @@ impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
         let (byte_i, bit) = self.chunk_index_to_bitmap_indices(chunk_index);
         // xor => keep all bits, except bitflip at relevant position
         self.bitmap[byte_i] = self.bitmap[byte_i] ^ (1 << bit);
+        self.used_chunks += 1;
+        self.peak_used_chunks = core::cmp::max(self.peak_used_chunks, self.used_chunks);
     }
 
     /// Marks a chunk as free, i.e. write a 0 into the bitmap at the right position.
@@ -123,6 +303,55 @@ impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
         // xor => keep all bits, except bitflip at relevant position
         let updated_byte = self.bitmap[byte_i] ^ (1 << bit);
         self.bitmap[byte_i] = updated_byte;
+        self.used_chunks -= 1;
+    }
+
+    /// Returns a snapshot of the usage and the fragmentation of the heap. The free runs
+    /// are determined by scanning the bitmap word by word.
+    pub fn stats(&self) -> ChunkAllocatorStats {
+        let mut largest_free_run = 0;
+        let mut free_runs = 0;
+        let mut run = 0;
+        let mut end_run = |run: &mut usize| {
+            if *run > 0 {
+                free_runs += 1;
+                largest_free_run = core::cmp::max(largest_free_run, *run);
+                *run = 0;
+            }
+        };
+
+        let word_count = (self.chunk_count() + 63) / 64;
+        for word_index in 0..word_count {
+            // chunks beyond the end of the heap appear as used and end the last run
+            let mut word = self.bitmap_word(word_index);
+            let mut remaining_bits = 64;
+            while remaining_bits > 0 {
+                // zeroes shifted in from the left are not part of the word
+                let free = core::cmp::min(word.trailing_zeros(), remaining_bits);
+                run += free as usize;
+                word = word.checked_shr(free).unwrap_or(0);
+                remaining_bits -= free;
+                if remaining_bits == 0 {
+                    break;
+                }
+                end_run(&mut run);
+                let used = word.trailing_ones();
+                word = word.checked_shr(used).unwrap_or(0);
+                remaining_bits -= used;
+            }
+        }
+        end_run(&mut run);
+
+        ChunkAllocatorStats {
+            chunk_size: CHUNK_SIZE,
+            used_chunks: self.used_chunks,
+            free_chunks: self.chunk_count() - self.used_chunks,
+            largest_free_run,
+            free_runs,
+            peak_used_chunks: self.peak_used_chunks,
+            alloc_count: self.alloc_count,
+            dealloc_count: self.dealloc_count,
+        }
     }
 
     /// Returns the indices into the bitmap array of a given chunk index.
@@ -145,43 +374,83 @@ impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
         chunk_index
     }
 
-    /// Returns the index of the next free chunk of memory that is correctly aligned.
-    ///
-    /// # Parameters
-    /// - `start_chunk` describes the start chunk; i.e. the search space inside the backing storage
-    /// - `alignment` required alignment of the chunk in memory
-    ///
-    /// # Return
-    /// Returns the index of the chunk or `Err` for out of memory.
-    fn find_next_free_chunk_aligned(
-        &self,
-        start_chunk: Option<usize>,
-        alignment: u32,
-    ) -> Result<usize, ()> {
-        let start_chunk = start_chunk.unwrap_or(0);
+    /// Returns the 64-bit word of the bitmap with the given index. Bit `n` of the word
+    /// describes the chunk `64 * word_index + n`. Chunks beyond the end of the heap
+    /// appear as used.
+    fn bitmap_word(&self, word_index: usize) -> u64 {
+        let byte_begin = word_index * 8;
+        if let Some(bytes) = self.bitmap.get(byte_begin..byte_begin + 8) {
+            return u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        let mut bytes = [0xff; 8];
+        if byte_begin < self.bitmap.len() {
+            let tail = &self.bitmap[byte_begin..];
+            bytes[..tail.len()].copy_from_slice(tail);
+        }
+        u64::from_le_bytes(bytes)
+    }
 
-        if start_chunk >= self.chunk_count() {
-            log::debug!("chunk_index out of range!");
-            return Err(());
+    /// Returns the index of the first chunk whose address fulfills the alignment and the
+    /// stride in chunks between two such chunks. Returns `None`, if no chunk of the heap
+    /// can fulfill the alignment.
+    fn alignment_stride(&self, alignment: usize) -> Option<(usize, usize)> {
+        debug_assert!(alignment.is_power_of_two());
+        // greatest common divisor of the power of two `alignment` and `CHUNK_SIZE`
+        let gcd = 1 << core::cmp::min(alignment.trailing_zeros(), CHUNK_SIZE.trailing_zeros());
+        let stride = alignment / gcd;
+        let heap_begin = self.heap.as_ptr() as usize;
+        let first = (0..stride).find(|i| (heap_begin + i * CHUNK_SIZE) % alignment == 0)?;
+        if first < self.chunk_count() {
+            Some((first, stride))
+        } else {
+            None
         }
+    }
 
-        for i in start_chunk..self.chunk_count() {
-            if self.chunk_is_free(i) {
-                let addr = unsafe { self.chunk_index_to_ptr(i) } as u32;
-                let is_aligned = addr % alignment == 0;
-                if is_aligned {
-                    return Ok(i);
-                }
-            }
+    /// Returns the smallest chunk index `>= chunk_index` that is reachable from `first`
+    /// by steps of `stride`.
+    fn align_chunk_index_up(chunk_index: usize, (first, stride): (usize, usize)) -> usize {
+        if chunk_index <= first {
+            first
+        } else {
+            first + (chunk_index - first + stride - 1) / stride * stride
+        }
+    }
+
+    /// Returns a mask of the chunks in the bitmap word beginning at chunk `word_begin`,
+    /// that fulfill the alignment. `pattern` has a bit set every `stride` bits,
+    /// beginning at bit 0.
+    fn aligned_chunks_mask(word_begin: usize, stride: (usize, usize), pattern: u64) -> u64 {
+        let offset = Self::align_chunk_index_up(word_begin, stride) - word_begin;
+        if offset < 64 {
+            pattern << offset
+        } else {
+            0
         }
+    }
 
-        // out of memory
-        Err(())
+    /// Returns a mask where bit `i` is set, if the bits `i..i + n` are all set in `word`.
+    /// Needs `log2(n)` steps: Each step doubles the length of the runs in the mask.
+    fn runs_of_ones_mask(word: u64, n: usize) -> u64 {
+        debug_assert!(n > 0 && n <= 64);
+        let mut mask = word;
+        let mut len = 1;
+        while len < n {
+            let shift = core::cmp::min(len, n - len);
+            mask &= mask >> shift;
+            len += shift;
+        }
+        mask
     }
 
     /// Finds the next available chain of available chunks. Returns the
     /// beginning index.
     ///
+    /// The bitmap is processed word by word. Free runs that span multiple words are
+    /// tracked by counting the free bits at both ends of each word. Runs inside a
+    /// single word are found with bit operations. Only chunks that fulfill the
+    /// alignment are considered as beginning of the chain.
+    ///
     /// # Parameters
     /// - `chunk_num` number of chunks that must be all free without gap in-between; greater than 0
     /// - `alignment` required alignment of the chunk in memory
@@ -194,32 +463,55 @@ impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
             chunk_num > 0,
             "chunk_num must be greater than 0! Allocating 0 blocks makes no sense"
         );
-        let mut begin_chunk_i = self.find_next_free_chunk_aligned(Some(0), alignment)?;
-        let out_of_mem_cond = begin_chunk_i + (chunk_num - 1) >= self.chunk_count();
-        while !out_of_mem_cond {
-            // this var counts how many coherent chunks we found while iterating the bitmap
-            let mut coherent_chunk_count = 1;
-            for chunk_chain_i in 1..=chunk_num {
-                if coherent_chunk_count == chunk_num {
-                    return Ok(begin_chunk_i);
-                } else if self.chunk_is_free(begin_chunk_i + chunk_chain_i) {
-                    coherent_chunk_count += 1;
-                } else {
-                    break;
+        let stride = self.alignment_stride(alignment as usize).ok_or(())?;
+        let pattern = (0..64)
+            .step_by(stride.1)
+            .fold(0_u64, |pattern, bit| pattern | (1 << bit));
+        let aligned_begin = |begin| Self::align_chunk_index_up(begin, stride);
+        let fits = |begin, end| aligned_begin(begin) + chunk_num <= end;
+
+        // beginning of the free run that reaches the end of the previous word
+        let mut run_begin = None;
+        for word_index in 0..(self.chunk_count() + 63) / 64 {
+            let word_begin = word_index * 64;
+            let free = !self.bitmap_word(word_index);
+
+            if free == u64::MAX {
+                let begin = *run_begin.get_or_insert(word_begin);
+                if fits(begin, word_begin + 64) {
+                    return Ok(aligned_begin(begin));
                 }
+                continue;
             }
 
-            // check again at next free block
-            // "+1" because we want to skip the just discovered non-free block
-            begin_chunk_i = self
-                .find_next_free_chunk_aligned(
-                    Some(begin_chunk_i + coherent_chunk_count + 1),
-                    alignment,
-                )
-                .unwrap();
+            // the run from the previous words ends in this word
+            if let Some(begin) = run_begin.take() {
+                if fits(begin, word_begin + free.trailing_ones() as usize) {
+                    return Ok(aligned_begin(begin));
+                }
+            }
+
+            // runs inside this word
+            if chunk_num <= 64 {
+                let candidates = Self::runs_of_ones_mask(free, chunk_num)
+                    & Self::aligned_chunks_mask(word_begin, stride, pattern);
+                if candidates != 0 {
+                    return Ok(word_begin + candidates.trailing_zeros() as usize);
+                }
+            }
+
+            // run that may continue in the next word
+            let top_free = free.leading_ones() as usize;
+            if top_free > 0 {
+                run_begin = Some(word_begin + 64 - top_free);
+            }
+        }
+
+        match run_begin {
+            Some(begin) if fits(begin, self.chunk_count()) => Ok(aligned_begin(begin)),
+            // out of memory
+            _ => Err(()),
         }
-        // out of memory
-        Err(())
     }
 
     /// Returns the pointer to the beginning of the chunk.
@@ -232,74 +524,511 @@ impl<'a, const CHUNK_SIZE: usize> ChunkAllocator<'a, CHUNK_SIZE> {
     }
 
     /// Returns the chunk index of the given pointer (which points to the beginning of a chunk).
-    unsafe fn ptr_to_chunk_index(&self, ptr: *const u8) -> usize {
-        let heap_begin_inclusive = self.heap.as_ptr();
-        let heap_end_exclusive = self.heap.as_ptr().add(self.heap.len());
-        assert!(
-            heap_begin_inclusive <= ptr && ptr < heap_end_exclusive,
-            "pointer {:?} is out of range {:?}..{:?} of the allocators backing storage",
-            ptr,
-            heap_begin_inclusive,
-            heap_end_exclusive
-        );
-        (ptr as usize - heap_begin_inclusive as usize) / CHUNK_SIZE
+    /// Fails if the pointer is outside of the backing storage or not at the beginning of a chunk.
+    fn ptr_to_chunk_index(&self, ptr: *const u8) -> Result<usize, ChunkAllocatorError> {
+        let heap_begin_inclusive = self.heap.as_ptr() as usize;
+        let heap_end_exclusive = heap_begin_inclusive + self.heap.len();
+        let ptr = ptr as usize;
+        if ptr < heap_begin_inclusive || ptr >= heap_end_exclusive {
+            return Err(ChunkAllocatorError::InvalidPointer);
+        }
+        let offset = ptr - heap_begin_inclusive;
+        if offset % CHUNK_SIZE != 0 {
+            return Err(ChunkAllocatorError::InvalidPointer);
+        }
+        Ok(offset / CHUNK_SIZE)
     }
 
-    #[track_caller]
-    pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        let layout = if layout.size() == 0 {
-            Layout::from_size_align(1, layout.align()).unwrap()
+    /// Returns the number of chunks that are required to fulfill the given layout.
+    /// Zero-sized layouts still occupy one chunk, so that each allocation gets a
+    /// unique address.
+    const fn required_chunks(layout: Layout) -> usize {
+        let chunks = (layout.size() + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        if chunks == 0 {
+            1
         } else {
-            layout
-        };
+            chunks
+        }
+    }
 
-        let mut required_chunks = layout.size() / CHUNK_SIZE;
-        let modulo = layout.size() % CHUNK_SIZE;
+    /// Marks all chunks up to `chunk_index_end` (exclusive) as handed out, i.e. their
+    /// content may no longer be zero.
+    fn mark_as_handed_out(&mut self, chunk_index_end: usize) {
+        if chunk_index_end > self.zeroed_from {
+            self.zeroed_from = chunk_index_end;
+        }
+    }
+
+    /// Finds coherent chunks for the given layout and marks them as used.
+    /// Returns the index of the first chunk.
+    fn alloc_chunks(&mut self, layout: Layout) -> Result<usize, ChunkAllocatorError> {
+        let required_chunks = Self::required_chunks(layout);
 
         // log::debug!("alloc: layout={:?} ({} chunks]", layout, required_chunks);
 
-        if modulo != 0 {
-            required_chunks += 1;
+        let index = self
+            .find_free_coherent_chunks_aligned(required_chunks, layout.align() as u32)
+            .map_err(|_| ChunkAllocatorError::OutOfMemory)?;
+
+        for i in index..index + required_chunks {
+            self.mark_chunk_as_used(i);
+        }
+        self.alloc_count += 1;
+        Ok(index)
+    }
+
+    /// Marks the chunks of an allocation as free.
+    fn free_chunks(&mut self, index: usize, chunks: usize) {
+        for i in index..index + chunks {
+            self.mark_chunk_as_free(i);
         }
+        self.dealloc_count += 1;
+    }
 
-        let index = self.find_free_coherent_chunks_aligned(required_chunks, layout.align() as u32);
+    /// Returns the non-null pointer to the beginning of the chunk.
+    fn chunk_index_to_non_null(&self, chunk_index: usize) -> NonNull<u8> {
+        let ptr = unsafe { self.chunk_index_to_ptr(chunk_index) };
+        // the backing storage is a valid slice, hence the pointer is never null
+        NonNull::new(ptr).unwrap()
+    }
 
-        if let Err(_) = index {
-            panic!(
-                "Out of Memory. Can't fulfill the requested layout: {:?}",
-                layout,
+    /// Verifies that `ptr` and `layout` describe a live allocation of this allocator.
+    /// Returns the index of the first chunk of the allocation.
+    fn check_allocation(
+        &self,
+        ptr: *const u8,
+        layout: Layout,
+    ) -> Result<usize, ChunkAllocatorError> {
+        let required_chunks = Self::required_chunks(layout);
+        let index = self.ptr_to_chunk_index(ptr)?;
+        if ptr as usize % layout.align() != 0 || index + required_chunks > self.chunk_count() {
+            return Err(ChunkAllocatorError::LayoutMismatch);
+        }
+        if self.chunk_is_free(index) {
+            return Err(ChunkAllocatorError::DoubleFree);
+        }
+        if (index + 1..index + required_chunks).any(|i| self.chunk_is_free(i)) {
+            return Err(ChunkAllocatorError::LayoutMismatch);
+        }
+        Ok(index)
+    }
+
+    /// Fallible version of [`Self::alloc`]. Returns a pointer to the beginning of
+    /// the allocation or [`ChunkAllocatorError::OutOfMemory`], if there is no
+    /// coherent range of free chunks that satisfies the layout.
+    pub fn try_alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        if HEAP_DEBUG {
+            self.debug_alloc(layout, false)
+        } else {
+            self.alloc_plain(layout)
+        }
+    }
+
+    /// Allocates chunks for the layout without any debug measures.
+    fn alloc_plain(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        let index = self.alloc_chunks(layout)?;
+        self.mark_as_handed_out(index + Self::required_chunks(layout));
+        Ok(self.chunk_index_to_non_null(index))
+    }
+
+    /// Like [`Self::try_alloc`] but the memory is guaranteed to contain zeroes.
+    /// Only the part of the allocation, that was handed out before, gets zeroed.
+    pub fn try_alloc_zeroed(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        if HEAP_DEBUG {
+            return self.debug_alloc(layout, true);
+        }
+
+        let index = self.alloc_chunks(layout)?;
+        let ptr = self.chunk_index_to_non_null(index);
+
+        let dirty_bytes = self.zeroed_from.saturating_sub(index) * CHUNK_SIZE;
+        let zero_bytes = core::cmp::min(layout.size(), dirty_bytes);
+        unsafe { ptr::write_bytes(ptr.as_ptr(), 0, zero_bytes) };
+
+        self.mark_as_handed_out(index + Self::required_chunks(layout));
+        Ok(ptr)
+    }
+
+    /// Fallible version of [`Self::dealloc`]. Verifies that `ptr` and `layout` describe
+    /// an allocation of this allocator before any chunk gets marked as free. On error,
+    /// the state of the allocator is unchanged.
+    ///
+    /// # Safety
+    /// The memory of the allocation must not be used after this call. The allocator can
+    /// only detect misuse, that is visible in its bitmap.
+    pub unsafe fn try_dealloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<(), ChunkAllocatorError> {
+        if HEAP_DEBUG {
+            return self.debug_dealloc(ptr, layout);
+        }
+
+        // log::debug!("dealloc: layout={:?} ({} chunks]", layout, required_chunks);
+        let index = self.check_allocation(ptr, layout)?;
+        self.free_chunks(index, Self::required_chunks(layout));
+        Ok(())
+    }
+
+    /// Resizes the allocation `ptr` with `layout` to `new_size` bytes. Shrinking always
+    /// happens in place. Growing happens in place, if the chunks right after the allocation
+    /// are free. Otherwise, the data gets moved to a new allocation. On error, the old
+    /// allocation stays untouched.
+    ///
+    /// In `heap-debug` mode, the data is always moved. Thus, stale pointers to the old
+    /// allocation point to poisoned memory.
+    ///
+    /// # Safety
+    /// Same contract as [`core::alloc::GlobalAlloc::realloc`].
+    pub unsafe fn try_realloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        if HEAP_DEBUG {
+            return self.debug_realloc(ptr, layout, new_size);
+        }
+
+        let index = self.check_allocation(ptr, layout)?;
+        let new_layout = Layout::from_size_align(new_size, layout.align())
+            .map_err(|_| ChunkAllocatorError::OutOfMemory)?;
+        let old_chunks = Self::required_chunks(layout);
+        let new_chunks = Self::required_chunks(new_layout);
+
+        // shrink in place
+        if new_chunks <= old_chunks {
+            for i in index + new_chunks..index + old_chunks {
+                self.mark_chunk_as_free(i);
+            }
+            return Ok(self.chunk_index_to_non_null(index));
+        }
+
+        // grow in place
+        let can_grow_in_place = index + new_chunks <= self.chunk_count()
+            && (index + old_chunks..index + new_chunks).all(|i| self.chunk_is_free(i));
+        if can_grow_in_place {
+            for i in index + old_chunks..index + new_chunks {
+                self.mark_chunk_as_used(i);
+            }
+            self.mark_as_handed_out(index + new_chunks);
+            return Ok(self.chunk_index_to_non_null(index));
+        }
+
+        // move
+        let new_ptr = self.try_alloc(new_layout)?;
+        ptr::copy_nonoverlapping(ptr, new_ptr.as_ptr(), layout.size());
+        self.free_chunks(index, old_chunks);
+        Ok(new_ptr)
+    }
+
+    /// Returns the number of bytes in front of the user data of an allocation in
+    /// `heap-debug` mode: the [`DebugHeader`] and the front red zone. It is a multiple
+    /// of `align`, so that the user data keeps the alignment of the chunks.
+    const fn debug_prefix_size(align: usize) -> usize {
+        let min_size = core::mem::size_of::<DebugHeader>() + RED_ZONE_SIZE;
+        (min_size + align - 1) / align * align
+    }
+
+    /// Returns the number of bytes that the allocator adds to each allocation with
+    /// `align`: the header and the red zones in `heap-debug` mode, otherwise nothing.
+    pub const fn allocation_overhead(align: usize) -> usize {
+        if HEAP_DEBUG {
+            Self::debug_prefix_size(align) + RED_ZONE_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// Returns the layout of the chunks that back an allocation in `heap-debug` mode.
+    fn debug_backing_layout(layout: Layout) -> Result<Layout, ChunkAllocatorError> {
+        let size = Self::debug_prefix_size(layout.align())
+            .checked_add(layout.size())
+            .and_then(|size| size.checked_add(RED_ZONE_SIZE))
+            .ok_or(ChunkAllocatorError::OutOfMemory)?;
+        Layout::from_size_align(size, layout.align()).map_err(|_| ChunkAllocatorError::OutOfMemory)
+    }
+
+    /// `heap-debug` version of [`Self::try_alloc`] and [`Self::try_alloc_zeroed`].
+    /// Writes the header and the red zones around the user data.
+    fn debug_alloc(
+        &mut self,
+        layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        let backing_layout = Self::debug_backing_layout(layout)?;
+        let begin = self.alloc_plain(backing_layout)?.as_ptr();
+        let header_size = core::mem::size_of::<DebugHeader>();
+        let prefix_size = Self::debug_prefix_size(layout.align());
+        unsafe {
+            ptr::write_unaligned(
+                begin.cast::<DebugHeader>(),
+                DebugHeader {
+                    size: layout.size(),
+                    align: layout.align(),
+                },
+            );
+            ptr::write_bytes(
+                begin.add(header_size),
+                RED_ZONE_BYTE,
+                prefix_size - header_size,
             );
+            let user_ptr = begin.add(prefix_size);
+            if zeroed {
+                ptr::write_bytes(user_ptr, 0, layout.size());
+            }
+            ptr::write_bytes(user_ptr.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+            Ok(NonNull::new_unchecked(user_ptr))
         }
-        let index = index.unwrap();
+    }
 
-        for i in index..index + required_chunks {
-            self.mark_chunk_as_used(i);
+    /// Verifies the header and the red zones of an allocation in `heap-debug` mode.
+    /// Returns the begin of the backing chunks and their layout.
+    unsafe fn debug_check_allocation(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<(*mut u8, Layout), ChunkAllocatorError> {
+        let header_size = core::mem::size_of::<DebugHeader>();
+        let prefix_size = Self::debug_prefix_size(layout.align());
+        let begin = (ptr as usize)
+            .checked_sub(prefix_size)
+            .ok_or(ChunkAllocatorError::InvalidPointer)? as *mut u8;
+
+        // the first chunk must be in use before the header can be read
+        self.check_allocation(begin, Layout::new::<u8>())?;
+        let header = ptr::read_unaligned(begin.cast::<DebugHeader>());
+        if header.size != layout.size() || header.align != layout.align() {
+            return Err(ChunkAllocatorError::LayoutMismatch);
+        }
+        let backing_layout = Self::debug_backing_layout(layout)?;
+        self.check_allocation(begin, backing_layout)?;
+
+        let front_red_zone =
+            core::slice::from_raw_parts(begin.add(header_size), prefix_size - header_size);
+        let back_red_zone = core::slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE_SIZE);
+        let is_intact = |zone: &[u8]| zone.iter().all(|b| *b == RED_ZONE_BYTE);
+        if !is_intact(front_red_zone) || !is_intact(back_red_zone) {
+            return Err(ChunkAllocatorError::CorruptedRedZone);
         }
+        Ok((begin, backing_layout))
+    }
 
-        self.chunk_index_to_ptr(index)
+    /// `heap-debug` version of [`Self::try_dealloc`]. Poisons the chunks before
+    /// they are marked as free.
+    unsafe fn debug_dealloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<(), ChunkAllocatorError> {
+        let (begin, backing_layout) = self.debug_check_allocation(ptr, layout)?;
+        let index = self.ptr_to_chunk_index(begin)?;
+        let chunks = Self::required_chunks(backing_layout);
+        ptr::write_bytes(begin, POISON_BYTE, chunks * CHUNK_SIZE);
+        self.free_chunks(index, chunks);
+        Ok(())
+    }
+
+    /// `heap-debug` version of [`Self::try_realloc`]. Always moves the data.
+    unsafe fn debug_realloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        self.debug_check_allocation(ptr, layout)?;
+        let new_layout = Layout::from_size_align(new_size, layout.align())
+            .map_err(|_| ChunkAllocatorError::OutOfMemory)?;
+        let new_ptr = self.debug_alloc(new_layout, false)?;
+        ptr::copy_nonoverlapping(
+            ptr,
+            new_ptr.as_ptr(),
+            core::cmp::min(layout.size(), new_size),
+        );
+        self.debug_dealloc(ptr, layout)?;
+        Ok(new_ptr)
     }
 
+    /// Allocates memory for the given layout. Panics, if the allocation fails.
+    /// See [`Self::try_alloc`] for a fallible version.
+    ///
+    /// # Safety
+    /// Same contract as [`core::alloc::GlobalAlloc::alloc`].
     #[track_caller]
-    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        let mut required_chunks = layout.size() / CHUNK_SIZE;
-        let modulo = layout.size() % CHUNK_SIZE;
-        if modulo != 0 {
-            required_chunks += 1;
+    pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.try_alloc(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(e) => panic!(
+                "Out of Memory. Can't fulfill the requested layout: {:?} ({:?})",
+                layout, e
+            ),
         }
-        // log::debug!("dealloc: layout={:?} ({} chunks]", layout, required_chunks);
+    }
 
-        let index = self.ptr_to_chunk_index(ptr as *const u8);
-        for i in index..index + required_chunks {
-            self.mark_chunk_as_free(i);
+    /// Frees the memory of the given allocation. Panics, if `ptr` and `layout` don't
+    /// describe a valid allocation. See [`Self::try_dealloc`] for a fallible version.
+    ///
+    /// # Safety
+    /// Same contract as [`core::alloc::GlobalAlloc::dealloc`].
+    #[track_caller]
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Err(e) = self.try_dealloc(ptr, layout) {
+            panic!(
+                "Can't free pointer {:?} with layout {:?}: {:?}",
+                ptr, layout, e
+            );
         }
     }
 }
 
+impl<'a, const CHUNK_SIZE: usize> HeapAllocator<'a> for ChunkAllocator<'a, CHUNK_SIZE> {
+    const BLOCK_SIZE: usize = CHUNK_SIZE;
+
+    fn new(heap: &'a mut [u8], bitmap: &'a mut [u8]) -> Result<Self, ChunkAllocatorError> {
+        ChunkAllocator::new(heap, bitmap)
+    }
+
+    unsafe fn new_zeroed(
+        heap: &'a mut [u8],
+        bitmap: &'a mut [u8],
+    ) -> Result<Self, ChunkAllocatorError> {
+        ChunkAllocator::new_zeroed(heap, bitmap)
+    }
+
+    fn new_in_region(region: &'a mut [u8]) -> Result<Self, ChunkAllocatorError> {
+        ChunkAllocator::new_in_region(region)
+    }
+
+    fn contains(&self, ptr: *const u8) -> bool {
+        ChunkAllocator::contains(self, ptr)
+    }
+
+    fn capacity(&self) -> usize {
+        ChunkAllocator::capacity(self)
+    }
+
+    fn stats(&self) -> ChunkAllocatorStats {
+        ChunkAllocator::stats(self)
+    }
+
+    fn try_alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        ChunkAllocator::try_alloc(self, layout)
+    }
+
+    fn try_alloc_zeroed(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        ChunkAllocator::try_alloc_zeroed(self, layout)
+    }
+
+    unsafe fn try_dealloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<(), ChunkAllocatorError> {
+        ChunkAllocator::try_dealloc(self, ptr, layout)
+    }
+
+    unsafe fn try_realloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        ChunkAllocator::try_realloc(self, ptr, layout, new_size)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::mem::{PageAlignedByteBuf, PAGE_SIZE};
 
+    type TestAllocator<'a> = ChunkAllocator<'a, DEFAULT_ALLOCATOR_CHUNK_SIZE>;
+
+    /// Returns the size of an allocation with `align` that occupies exactly `chunks`
+    /// chunks, including the overhead of `heap-debug` mode.
+    fn size_of_chunks(chunks: usize, align: usize) -> usize {
+        chunks * DEFAULT_ALLOCATOR_CHUNK_SIZE - TestAllocator::allocation_overhead(align)
+    }
+
+    /// Returns the offset of the user data from the begin of its first chunk.
+    fn data_offset(align: usize) -> usize {
+        if HEAP_DEBUG {
+            TestAllocator::debug_prefix_size(align)
+        } else {
+            0
+        }
+    }
+
+    /// Returns the index of the first chunk of the allocation at `ptr`.
+    fn first_chunk_of(alloc: &TestAllocator, ptr: *mut u8, layout: Layout) -> usize {
+        let begin = ptr as usize - data_offset(layout.align());
+        alloc.ptr_to_chunk_index(begin as *const u8).unwrap()
+    }
+
+    /// Returns the number of chunks that an allocation with `layout` occupies.
+    fn chunks_of(layout: Layout) -> usize {
+        TestAllocator::required_chunks(
+            Layout::from_size_align(
+                layout.size() + TestAllocator::allocation_overhead(layout.align()),
+                layout.align(),
+            )
+            .unwrap(),
+        )
+    }
+
+    /// Returns the index of the first free chunk in `from..alloc.chunk_count()`. The
+    /// scanners of the previous allocator serve as reference for
+    /// [`ChunkAllocator::find_free_coherent_chunks_aligned`].
+    fn find_next_free_chunk(alloc: &TestAllocator, from: usize) -> Option<usize> {
+        if from >= alloc.chunk_count() {
+            return None;
+        }
+        let mut word_index = from / 64;
+        // ignore chunks before `from` by treating them as used
+        let mut word = alloc.bitmap_word(word_index) | ((1 << (from % 64)) - 1);
+        while word == u64::MAX {
+            word_index += 1;
+            if word_index * 64 >= alloc.chunk_count() {
+                return None;
+            }
+            word = alloc.bitmap_word(word_index);
+        }
+        let index = word_index * 64 + word.trailing_ones() as usize;
+        if index < alloc.chunk_count() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the index of the next free chunk at or after `start_chunk` that is
+    /// correctly aligned, or `Err` for out of memory.
+    fn find_next_free_chunk_aligned(
+        alloc: &TestAllocator,
+        start_chunk: Option<usize>,
+        alignment: u32,
+    ) -> Result<usize, ()> {
+        let start_chunk = start_chunk.unwrap_or(0);
+        if start_chunk >= alloc.chunk_count() {
+            return Err(());
+        }
+
+        let stride = alloc.alignment_stride(alignment as usize).ok_or(())?;
+        let mut candidate = TestAllocator::align_chunk_index_up(start_chunk, stride);
+        loop {
+            let free = find_next_free_chunk(alloc, candidate).ok_or(())?;
+            candidate = TestAllocator::align_chunk_index_up(free, stride);
+            if candidate >= alloc.chunk_count() {
+                // out of memory
+                return Err(());
+            }
+            if alloc.chunk_is_free(candidate) {
+                return Ok(candidate);
+            }
+        }
+    }
+
     #[test]
     fn test_compiles() {
         // must be a multiple of 8
@@ -386,19 +1115,15 @@ mod tests {
         let alloc =
             ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::new(&mut heap, &mut bitmap).unwrap();
 
-        assert_eq!(4, alloc.find_next_free_chunk_aligned(None, 1).unwrap());
-        assert_eq!(4, alloc.find_next_free_chunk_aligned(Some(0), 1).unwrap());
+        assert_eq!(4, find_next_free_chunk_aligned(&alloc, None, 1).unwrap());
+        assert_eq!(4, find_next_free_chunk_aligned(&alloc, Some(0), 1).unwrap());
 
         // the very last chunk is available
         assert_eq!(
             15,
-            alloc
-                .find_next_free_chunk_aligned(Some(alloc.chunk_count() - 1), 1)
-                .unwrap()
+            find_next_free_chunk_aligned(&alloc, Some(alloc.chunk_count() - 1), 1).unwrap()
         );
-        assert!(alloc
-            .find_next_free_chunk_aligned(Some(alloc.chunk_count()), 1)
-            .is_err());
+        assert!(find_next_free_chunk_aligned(&alloc, Some(alloc.chunk_count()), 1).is_err());
     }
 
     #[test]
@@ -420,6 +1145,129 @@ mod tests {
         assert_eq!(12, alloc.find_free_coherent_chunks_aligned(5, 1).unwrap());
     }
 
+    #[test]
+    fn test_find_free_coherent_chunks_across_words() {
+        // 256 chunks => four 64-bit words in the bitmap
+        let heap_size: usize = 256 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let mut heap = vec![0_u8; heap_size];
+        let mut bitmap = vec![0xff_u8; heap_size / DEFAULT_ALLOCATOR_CHUNK_SIZE / 8];
+        // chunks 60..70 and 128..256 are free
+        bitmap[7] = 0x0f;
+        bitmap[8] = 0xc0;
+        bitmap[16..].fill(0);
+
+        let alloc =
+            ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::new(&mut heap, &mut bitmap).unwrap();
+
+        assert_eq!(60, find_next_free_chunk_aligned(&alloc, None, 1).unwrap());
+        assert_eq!(60, alloc.find_free_coherent_chunks_aligned(10, 1).unwrap());
+        assert_eq!(128, alloc.find_free_coherent_chunks_aligned(11, 1).unwrap());
+        assert_eq!(
+            128,
+            alloc.find_free_coherent_chunks_aligned(128, 1).unwrap()
+        );
+        assert!(alloc.find_free_coherent_chunks_aligned(129, 1).is_err());
+    }
+
+    #[test]
+    fn test_find_free_coherent_chunks_stride() {
+        const CHUNK: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let heap_size: usize = 64 * CHUNK;
+        let mut heap = PageAlignedByteBuf::<{ 64 * CHUNK }>::new_zeroed();
+        let mut bitmap = vec![0_u8; heap_size / CHUNK / 8];
+        // chunk 0 is used, chunks 1.. are free
+        bitmap[0] = 0x01;
+        let alloc = ChunkAllocator::<CHUNK>::new(heap.get_mut(), &mut bitmap).unwrap();
+
+        // the heap is page-aligned => every 16th chunk is page-aligned
+        assert_eq!(
+            Some((0, PAGE_SIZE / CHUNK)),
+            alloc.alignment_stride(PAGE_SIZE)
+        );
+        assert_eq!(Some((0, 1)), alloc.alignment_stride(8));
+        assert_eq!(
+            16,
+            find_next_free_chunk_aligned(&alloc, None, PAGE_SIZE as u32).unwrap()
+        );
+        assert_eq!(
+            16,
+            alloc
+                .find_free_coherent_chunks_aligned(1, PAGE_SIZE as u32)
+                .unwrap()
+        );
+        assert_eq!(1, alloc.find_free_coherent_chunks_aligned(1, 64).unwrap());
+    }
+
+    #[test]
+    fn test_find_free_coherent_chunks_matches_linear_scan() {
+        // not a multiple of 64 => the last bitmap word is incomplete
+        const CHUNK_COUNT: usize = 520;
+        let heap_size: usize = CHUNK_COUNT * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let mut heap = vec![0_u8; heap_size];
+        let mut bitmap = vec![0_u8; CHUNK_COUNT / 8];
+
+        // simple LCG to get a reproducible pseudo-random fragmentation
+        let mut seed = 0x1234_5678_u32;
+        for byte in bitmap.iter_mut() {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            // sparse used bits => runs of various lengths
+            *byte = ((seed >> 16) as u8) & ((seed >> 8) as u8) & ((seed >> 4) as u8);
+        }
+        let reference_bitmap = bitmap.clone();
+        let is_free = |i: usize| (reference_bitmap[i / 8] >> (i % 8)) & 1 == 0;
+
+        let alloc =
+            ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::new(&mut heap, &mut bitmap).unwrap();
+
+        for alignment in [1, 64, 512, 2048] {
+            let is_aligned =
+                |i: usize| (unsafe { alloc.chunk_index_to_ptr(i) } as usize) % alignment == 0;
+            for chunk_num in (1..20).chain([63, 64, 65, 100]) {
+                let expected = (0..=CHUNK_COUNT - chunk_num)
+                    .find(|begin| is_aligned(*begin) && (*begin..*begin + chunk_num).all(is_free))
+                    .ok_or(());
+                assert_eq!(
+                    expected,
+                    alloc.find_free_coherent_chunks_aligned(chunk_num, alignment as u32),
+                    "chunk_num={}, alignment={}",
+                    chunk_num,
+                    alignment
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_in_region() {
+        const CHUNK: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        // unaligned region with space for 16 chunks + bitmap, but not for 24 chunks
+        let mut memory = PageAlignedByteBuf::<{ 24 * CHUNK }>::new(0xff);
+        let region = &mut memory.get_mut()[1..17 * CHUNK + 1];
+        let region_range = region.as_ptr_range();
+
+        let mut alloc = ChunkAllocator::<CHUNK>::new_in_region(region).unwrap();
+        assert_eq!(16, alloc.chunk_count());
+        assert_eq!(
+            unsafe { alloc.chunk_index_to_ptr(0) } as usize % CHUNK,
+            0,
+            "the heap must be chunk-aligned"
+        );
+        assert!((0..16).all(|i| alloc.chunk_is_free(i)));
+
+        let layout = Layout::from_size_align(size_of_chunks(16, 1), 1).unwrap();
+        let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+        assert!(alloc.contains(ptr));
+        let begin = unsafe { ptr.sub(data_offset(1)) };
+        assert!(region_range.contains(&(begin as *const u8)));
+        assert!(region_range.contains(&(unsafe { begin.add(16 * CHUNK - 1) } as *const u8)));
+
+        let mut too_small = [0_u8; 8 * CHUNK];
+        assert_eq!(
+            ChunkAllocator::<CHUNK>::new_in_region(&mut too_small).err(),
+            Some(ChunkAllocatorError::BadHeapMemory)
+        );
+    }
+
     #[test]
     fn test_chunk_index_to_ptr() {
         let heap_size: usize = 8 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
@@ -440,8 +1288,10 @@ mod tests {
 
     #[test]
     fn test_alloc() {
-        // must be a multiple of 8; 32 is equivalent to two pages
-        const CHUNK_COUNT: usize = 32;
+        // must be a multiple of 8; 32 is equivalent to two pages. In `heap-debug` mode, a
+        // page-aligned page additionally needs a page in front of it for the header and a
+        // chunk behind it for the red zone.
+        const CHUNK_COUNT: usize = if HEAP_DEBUG { 128 } else { 32 };
         const HEAP_SIZE: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE * CHUNK_COUNT;
         static mut HEAP: PageAlignedByteBuf<HEAP_SIZE> = PageAlignedByteBuf::new_zeroed();
         const BITMAP_SIZE: usize = HEAP_SIZE / DEFAULT_ALLOCATOR_CHUNK_SIZE / 8;
@@ -455,15 +1305,16 @@ mod tests {
 
         let layout1_single_byte = Layout::from_size_align(1, 1).unwrap();
         let layout_page = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let page_chunks = chunks_of(layout_page);
 
         // allocate 1 single byte
         let ptr1 = {
             unsafe {
                 let ptr = alloc.alloc(layout1_single_byte.clone());
                 assert_eq!(
-                    ptr as u64 % PAGE_SIZE as u64,
+                    first_chunk_of(&alloc, ptr, layout1_single_byte),
                     0,
-                    "the first allocation must be always page-aligned"
+                    "the first allocation must always begin at the page-aligned heap begin"
                 );
                 assert!(!alloc.chunk_is_free(0), "the first chunk is taken now!");
                 assert!(
@@ -486,32 +1337,32 @@ mod tests {
                 );
             }
 
-            (0..CHUNK_COUNT)
-                .into_iter()
-                .skip(CHUNK_COUNT / 2)
-                .for_each(|i| {
-                    assert!(!alloc.chunk_is_free(i), "chunk must be in use!");
-                });
+            let first_chunk = first_chunk_of(&alloc, ptr, layout_page);
+            (first_chunk..first_chunk + page_chunks).for_each(|i| {
+                assert!(!alloc.chunk_is_free(i), "chunk must be in use!");
+            });
             ptr
         };
 
         // free the very first allocation; allocate again; now we should have two allocations
         // of two full pages
-        {
-            unsafe {
-                alloc.dealloc(ptr1, layout1_single_byte);
-                let ptr3 = alloc.alloc(layout_page);
-                assert_eq!(ptr1, ptr3);
-            }
-
-            // assert that all chunks are taken
-            for i in 0..CHUNK_COUNT {
-                assert!(!alloc.chunk_is_free(i), "all chunks must be in use!");
-            }
+        let ptr3 = unsafe {
+            alloc.dealloc(ptr1, layout1_single_byte);
+            alloc.alloc(layout_page)
+        };
+        assert_eq!(ptr3 as u64 % PAGE_SIZE as u64, 0, "must be page-aligned!");
+        let used_chunks = (0..CHUNK_COUNT)
+            .filter(|i| !alloc.chunk_is_free(*i))
+            .count();
+        assert_eq!(used_chunks, 2 * page_chunks, "both pages must be in use!");
+        if !HEAP_DEBUG {
+            // otherwise, the header page in front of the second allocation occupies the
+            // chunks that the first allocation freed
+            assert_eq!(ptr1, ptr3);
         }
 
         unsafe {
-            alloc.dealloc(ptr1, layout_page);
+            alloc.dealloc(ptr3, layout_page);
             alloc.dealloc(ptr2, layout_page);
         }
     }
@@ -533,6 +1384,239 @@ mod tests {
         assert_eq!(ptr as usize % TWO_MIB, 0, "must be aligned!");
     }
 
+    #[test]
+    fn test_try_alloc_out_of_memory() {
+        let heap_size: usize = 8 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let mut heap = vec![0_u8; heap_size];
+        let mut bitmap = vec![0_u8; heap_size / DEFAULT_ALLOCATOR_CHUNK_SIZE / 8];
+        let mut alloc =
+            ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::new(&mut heap, &mut bitmap).unwrap();
+
+        let layout = Layout::from_size_align(size_of_chunks(4, 1), 1).unwrap();
+        let ptr1 = alloc.try_alloc(layout).unwrap();
+        let ptr2 = alloc.try_alloc(layout).unwrap();
+        assert_eq!(
+            alloc.try_alloc(Layout::new::<u8>()),
+            Err(ChunkAllocatorError::OutOfMemory)
+        );
+
+        // the failed allocation didn't change the state
+        unsafe {
+            alloc.try_dealloc(ptr1.as_ptr(), layout).unwrap();
+            alloc.try_dealloc(ptr2.as_ptr(), layout).unwrap();
+        }
+        assert!(alloc.try_alloc(Layout::new::<u8>()).is_ok());
+    }
+
+    #[test]
+    fn test_try_dealloc_errors() {
+        let heap_size: usize = 8 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let mut heap = vec![0_u8; heap_size];
+        let mut bitmap = vec![0_u8; heap_size / DEFAULT_ALLOCATOR_CHUNK_SIZE / 8];
+        let mut alloc =
+            ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::new(&mut heap, &mut bitmap).unwrap();
+
+        let layout = Layout::from_size_align(size_of_chunks(2, 1), 1).unwrap();
+        let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+
+        unsafe {
+            let mut outside = 0_u8;
+            assert_eq!(
+                alloc.try_dealloc(&mut outside, layout),
+                Err(ChunkAllocatorError::InvalidPointer)
+            );
+            assert_eq!(
+                alloc.try_dealloc(ptr.add(1), layout),
+                Err(ChunkAllocatorError::InvalidPointer)
+            );
+            let too_big = Layout::from_size_align(size_of_chunks(3, 1), 1).unwrap();
+            assert_eq!(
+                alloc.try_dealloc(ptr, too_big),
+                Err(ChunkAllocatorError::LayoutMismatch)
+            );
+            assert_eq!(
+                alloc.try_dealloc(ptr.add(2 * DEFAULT_ALLOCATOR_CHUNK_SIZE), layout),
+                Err(ChunkAllocatorError::DoubleFree)
+            );
+
+            assert_eq!(alloc.try_dealloc(ptr, layout), Ok(()));
+            assert_eq!(
+                alloc.try_dealloc(ptr, layout),
+                Err(ChunkAllocatorError::DoubleFree)
+            );
+        }
+    }
+
+    #[test]
+    fn test_realloc() {
+        const CHUNK: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let heap_size: usize = 8 * CHUNK;
+        let mut heap = vec![0_u8; heap_size];
+        let mut bitmap = vec![0_u8; heap_size / CHUNK / 8];
+        let mut alloc = ChunkAllocator::<CHUNK>::new(&mut heap, &mut bitmap).unwrap();
+        let layout_of_chunks =
+            |chunks| Layout::from_size_align(size_of_chunks(chunks, 1), 1).unwrap();
+        // `heap-debug` mode never reallocates in place
+        let in_place = !HEAP_DEBUG;
+
+        let layout = layout_of_chunks(1);
+        let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+        unsafe {
+            ptr.write_bytes(0x42, layout.size());
+
+            // grow in place: the following chunks are free
+            let grown = alloc
+                .try_realloc(ptr, layout, layout_of_chunks(3).size())
+                .unwrap()
+                .as_ptr();
+            assert_eq!(ptr == grown, in_place);
+            let layout = layout_of_chunks(3);
+            let index = first_chunk_of(&alloc, grown, layout);
+            assert!((index..index + 3).all(|i| !alloc.chunk_is_free(i)));
+
+            // shrink in place: the tail gets freed
+            let shrunk = alloc
+                .try_realloc(grown, layout, layout_of_chunks(2).size())
+                .unwrap()
+                .as_ptr();
+            assert_eq!(grown == shrunk, in_place);
+            let layout = layout_of_chunks(2);
+            let index = first_chunk_of(&alloc, shrunk, layout);
+            assert!(!alloc.chunk_is_free(index + 1));
+            assert!(alloc.chunk_is_free(index + 2));
+
+            // move: the next chunk is occupied
+            alloc.mark_chunk_as_used(index + 2);
+            let moved = alloc
+                .try_realloc(shrunk, layout, layout_of_chunks(3).size())
+                .unwrap()
+                .as_ptr();
+            assert_ne!(moved, shrunk);
+            assert!(alloc.chunk_is_free(index) && alloc.chunk_is_free(index + 1));
+            assert!(core::slice::from_raw_parts(moved, size_of_chunks(1, 1))
+                .iter()
+                .all(|x| *x == 0x42));
+            alloc.mark_chunk_as_free(index + 2);
+
+            // no space left to move: the old allocation stays valid
+            let layout = layout_of_chunks(3);
+            assert_eq!(
+                alloc.try_realloc(moved, layout, heap_size),
+                Err(ChunkAllocatorError::OutOfMemory)
+            );
+            assert_eq!(alloc.try_dealloc(moved, layout), Ok(()));
+        }
+    }
+
+    /// Chunks that are marked as used in the bitmap of the caller count as used.
+    #[test]
+    fn test_prepopulated_bitmap() {
+        const CHUNK: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let mut heap = vec![0_u8; 64 * CHUNK];
+        let mut bitmap = vec![0_u8; 8];
+        bitmap[0] = 0b0000_0101;
+        let mut alloc = ChunkAllocator::<CHUNK>::new(&mut heap, &mut bitmap).unwrap();
+
+        let stats = alloc.stats();
+        assert_eq!(stats.used_chunks, 2);
+        assert_eq!(stats.free_chunks, 62);
+        assert_eq!(stats.peak_used_chunks, 2);
+
+        // the owner of the pre-marked chunk releases it
+        let chunk = alloc.chunk_index_to_non_null(2);
+        alloc.mark_chunk_as_free(2);
+        assert_eq!(alloc.stats().used_chunks, 1);
+        assert!(alloc.contains(chunk.as_ptr()));
+    }
+
+    #[test]
+    fn test_stats() {
+        const CHUNK: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let heap_size: usize = 128 * CHUNK;
+        let mut heap = vec![0_u8; heap_size];
+        let mut bitmap = vec![0_u8; heap_size / CHUNK / 8];
+        let mut alloc = ChunkAllocator::<CHUNK>::new(&mut heap, &mut bitmap).unwrap();
+
+        let stats = alloc.stats();
+        assert_eq!(stats.chunk_size, CHUNK);
+        assert_eq!(stats.used_chunks, 0);
+        assert_eq!(stats.free_chunks, 128);
+        assert_eq!(stats.largest_free_run, 128);
+        assert_eq!(stats.free_runs, 1);
+        assert_eq!(stats.free_bytes(), heap_size);
+
+        // 10 allocations of 3 chunks each
+        let layout = Layout::from_size_align(size_of_chunks(3, 1), 1).unwrap();
+        let ptrs = (0..10)
+            .map(|_| alloc.try_alloc(layout).unwrap())
+            .collect::<Vec<_>>();
+        // free every second allocation: 5 holes of 3 chunks and the tail
+        for ptr in ptrs.iter().step_by(2) {
+            unsafe { alloc.try_dealloc(ptr.as_ptr(), layout).unwrap() };
+        }
+
+        let stats = alloc.stats();
+        assert_eq!(stats.used_chunks, 15);
+        assert_eq!(stats.used_bytes(), 15 * CHUNK);
+        assert_eq!(stats.free_chunks, 128 - 15);
+        assert_eq!(stats.largest_free_run, 128 - 30);
+        assert_eq!(stats.free_runs, 6);
+        assert_eq!(stats.peak_used_chunks, 30);
+        assert_eq!(stats.alloc_count, 10);
+        assert_eq!(stats.dealloc_count, 5);
+
+        // a free run across the word boundary at chunk 64 and a used last chunk
+        alloc.mark_chunk_as_used(127);
+        alloc.mark_chunk_as_used(64);
+        let stats = alloc.stats();
+        assert_eq!(stats.free_runs, 7);
+        assert_eq!(stats.largest_free_run, 127 - 65);
+        alloc.mark_chunk_as_free(64);
+        alloc.mark_chunk_as_free(127);
+        let stats = alloc.stats();
+        assert_eq!(stats.free_runs, 6);
+        assert_eq!(stats.largest_free_run, 128 - 30);
+
+        let combined = stats.combine(&stats);
+        assert_eq!(combined.free_chunks, 2 * stats.free_chunks);
+        assert_eq!(combined.largest_free_run, stats.largest_free_run);
+    }
+
+    #[test]
+    fn test_alloc_zeroed() {
+        const CHUNK: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let heap_size: usize = 8 * CHUNK;
+        let mut heap = vec![0_u8; heap_size];
+        // Violate the contract of `new_zeroed` on purpose to verify that the allocator
+        // doesn't touch chunks that were never handed out.
+        heap[4 * CHUNK] = 0xff;
+        let mut bitmap = vec![0_u8; heap_size / CHUNK / 8];
+        let mut alloc =
+            unsafe { ChunkAllocator::<CHUNK>::new_zeroed(&mut heap, &mut bitmap).unwrap() };
+
+        let layout = Layout::from_size_align(size_of_chunks(4, 1), 1).unwrap();
+        let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+        unsafe {
+            ptr.write_bytes(0xff, layout.size());
+            alloc.try_dealloc(ptr, layout).unwrap();
+        }
+
+        let layout = Layout::from_size_align(size_of_chunks(5, 1), 1).unwrap();
+        let ptr = alloc.try_alloc_zeroed(layout).unwrap().as_ptr();
+        let data = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+        let pristine = 4 * CHUNK - data_offset(1);
+        assert!(
+            data[..pristine].iter().all(|x| *x == 0),
+            "chunks that were handed out before must be zeroed"
+        );
+        // `heap-debug` mode zeroes the whole allocation, because freed chunks are poisoned
+        let expected = if HEAP_DEBUG { 0 } else { 0xff };
+        assert_eq!(
+            data[pristine], expected,
+            "pristine chunk must not be touched"
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_alloc_out_of_memory() {
@@ -553,4 +1637,97 @@ mod tests {
             let _ = alloc.alloc(Layout::from_size_align(16384, PAGE_SIZE).unwrap());
         }
     }
+
+    #[cfg(feature = "heap-debug")]
+    fn new_debug_allocator() -> ChunkAllocator<'static, DEFAULT_ALLOCATOR_CHUNK_SIZE> {
+        const HEAP_SIZE: usize = 32 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let heap = Box::leak(Box::new(PageAlignedByteBuf::<HEAP_SIZE>::new_zeroed()));
+        let bitmap = Box::leak(vec![0_u8; 4].into_boxed_slice());
+        ChunkAllocator::new(heap.get_mut(), bitmap).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    fn test_debug_red_zones() {
+        let mut alloc = new_debug_allocator();
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        unsafe {
+            let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+            assert_eq!(ptr as usize % 64, 0);
+            assert_eq!(*ptr.sub(1), RED_ZONE_BYTE);
+            assert_eq!(*ptr.add(100), RED_ZONE_BYTE);
+            ptr::write_bytes(ptr, 0xaa, 100);
+            alloc.try_dealloc(ptr, layout).unwrap();
+
+            // overflow by one byte
+            let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+            *ptr.add(100) = 0;
+            assert_eq!(
+                alloc.try_dealloc(ptr, layout),
+                Err(ChunkAllocatorError::CorruptedRedZone)
+            );
+            // underflow by one byte
+            *ptr.add(100) = RED_ZONE_BYTE;
+            *ptr.sub(1) = 0;
+            assert_eq!(
+                alloc.try_dealloc(ptr, layout),
+                Err(ChunkAllocatorError::CorruptedRedZone)
+            );
+            *ptr.sub(1) = RED_ZONE_BYTE;
+            alloc.try_dealloc(ptr, layout).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    fn test_debug_layout_mismatch() {
+        let mut alloc = new_debug_allocator();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+            assert_eq!(
+                alloc.try_dealloc(ptr, Layout::from_size_align(99, 8).unwrap()),
+                Err(ChunkAllocatorError::LayoutMismatch)
+            );
+            assert_eq!(
+                alloc.try_realloc(ptr, Layout::from_size_align(101, 8).unwrap(), 200),
+                Err(ChunkAllocatorError::LayoutMismatch)
+            );
+            alloc.try_dealloc(ptr, layout).unwrap();
+            assert_eq!(
+                alloc.try_dealloc(ptr, layout),
+                Err(ChunkAllocatorError::DoubleFree)
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    fn test_debug_poison() {
+        let mut alloc = new_debug_allocator();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = alloc.try_alloc(layout).unwrap().as_ptr();
+            ptr::write_bytes(ptr, 0xaa, 100);
+
+            // realloc always moves and poisons the old allocation
+            let new_ptr = alloc.try_realloc(ptr, layout, 50).unwrap().as_ptr();
+            assert_ne!(ptr, new_ptr);
+            assert!(core::slice::from_raw_parts(ptr, 100)
+                .iter()
+                .all(|b| *b == POISON_BYTE));
+            assert!(core::slice::from_raw_parts(new_ptr, 50)
+                .iter()
+                .all(|b| *b == 0xaa));
+
+            let layout = Layout::from_size_align(50, 8).unwrap();
+            alloc.try_dealloc(new_ptr, layout).unwrap();
+            assert!(core::slice::from_raw_parts(new_ptr, 50)
+                .iter()
+                .all(|b| *b == POISON_BYTE));
+
+            let ptr = alloc.try_alloc_zeroed(layout).unwrap().as_ptr();
+            assert!(core::slice::from_raw_parts(ptr, 50).iter().all(|b| *b == 0));
+        }
+    }
 }
@@ -1,40 +1,85 @@
-//! See [`GlobalStaticChunkAllocator`].
+//! See [`GlobalStaticAllocator`] and [`GlobalStaticSlabAllocator`].
 
+use crate::kernelheap::buddy_allocator::{BuddyAllocator, DEFAULT_BUDDY_BLOCK_SIZE};
 use crate::kernelheap::chunk_allocator::{
-    ChunkAllocator, ChunkAllocatorError, DEFAULT_ALLOCATOR_CHUNK_SIZE,
+    ChunkAllocator, ChunkAllocatorError, ChunkAllocatorStats, DEFAULT_ALLOCATOR_CHUNK_SIZE,
+    HEAP_DEBUG,
 };
-use crate::mutex::SimpleMutex;
+use crate::kernelheap::heap_allocator::HeapAllocator;
+use crate::kernelheap::slab_allocator::SlabAllocator;
+use crate::kernelheap::tracer::AllocTracer;
+use crate::mutex::{Mutex, RawLock, RawSpinLock};
+use crate::once::Once;
 use core::alloc::{GlobalAlloc, Layout};
+use core::panic::Location;
+use core::ptr::{self, NonNull};
 
 #[derive(Debug)]
 pub enum GlobalStaticChunkAllocatorError {
     Uninitialized,
     AlreadyInitialized,
+    /// All [`GlobalStaticAllocator::MAX_REGIONS`] regions are in use.
+    TooManyRegions,
     /// Error in the inner allocator object.
     Inner(ChunkAllocatorError),
 }
 
-/// Wrapping struct around [`ChunkAllocator`] which enables the usage
-/// of this allocator in a global context, i.e. as global allocator.
+/// See [`GlobalStaticAllocator::MAX_REGIONS`].
+const MAX_REGIONS: usize = 32;
+
+/// [`GlobalStaticAllocator`] with a [`ChunkAllocator`] for each region.
+pub type GlobalStaticChunkAllocator<'a, R = RawSpinLock> =
+    GlobalStaticAllocator<'a, ChunkAllocator<'a, DEFAULT_ALLOCATOR_CHUNK_SIZE>, R>;
+
+/// [`GlobalStaticAllocator`] with a [`BuddyAllocator`] for each region.
+pub type GlobalStaticBuddyAllocator<'a, R = RawSpinLock> =
+    GlobalStaticAllocator<'a, BuddyAllocator<'a, DEFAULT_BUDDY_BLOCK_SIZE>, R>;
+
+/// Wrapping struct around a [`HeapAllocator`], such as [`ChunkAllocator`], which
+/// enables the usage of this allocator in a global context, i.e. as global allocator.
 /// Memory is allocated in blocks/chunks with a size of
-/// [`GlobalStaticChunkAllocator::CHUNK_SIZE`].
+/// [`GlobalStaticAllocator::CHUNK_SIZE`].
+///
+/// The backing memory can consist of up to [`GlobalStaticAllocator::MAX_REGIONS`]
+/// regions. Each region is managed by its own [`HeapAllocator`]. Allocations are
+/// served by the first region that has enough memory left. Additional regions can be
+/// added at runtime with [`Self::add_region`], e.g. from the memory map of the firmware,
+/// or on demand, when the heap is full. See [`Self::set_grow_fn`].
 ///
-/// The struct synchronized accesses to the underlying memory.
+/// The struct synchronized accesses to the underlying memory. The kind of lock is
+/// selected by the [`RawLock`] `R`, e.g. [`crate::irq_mutex::RawIrqSafeLock`], if
+/// interrupt handlers allocate.
 /// It must be initialized by calling [`Self::init`], otherwise allocations
-/// result in panics.
+/// result in panics. If there is not enough memory left, allocations return
+/// a null pointer as [`GlobalAlloc`] demands. Therefore, fallible APIs such as
+/// `Vec::try_reserve` work and the `alloc_error_handler` gets invoked otherwise.
 #[derive(Debug)]
-pub struct GlobalStaticChunkAllocator<'a> {
-    inner_allocator: SimpleMutex<Option<ChunkAllocator<'a, DEFAULT_ALLOCATOR_CHUNK_SIZE>>>,
+pub struct GlobalStaticAllocator<'a, A, R = RawSpinLock> {
+    /// Guards the first region, see [`Self::init`].
+    initialized: Once,
+    regions: Mutex<R, [Option<A>; MAX_REGIONS]>,
+    /// Optional hook that gets informed about all heap operations.
+    tracer: Mutex<R, Option<&'a dyn AllocTracer>>,
+    /// Optional function that adds memory, when the heap is full.
+    grow_fn: Mutex<R, Option<fn(Layout) -> bool>>,
 }
 
-impl<'a> GlobalStaticChunkAllocator<'a> {
-    /// Publicly make the default chunk size available.
-    pub const CHUNK_SIZE: usize = DEFAULT_ALLOCATOR_CHUNK_SIZE;
+impl<'a, A: HeapAllocator<'a>, R: RawLock> GlobalStaticAllocator<'a, A, R> {
+    /// Publicly make the chunk size of the backend available.
+    pub const CHUNK_SIZE: usize = A::BLOCK_SIZE;
+
+    /// Maximum number of memory regions that back the heap.
+    pub const MAX_REGIONS: usize = MAX_REGIONS;
+
+    const NO_REGION: Option<A> = None;
 
     /// Constructor.
     pub const fn new() -> Self {
         Self {
-            inner_allocator: SimpleMutex::new(None),
+            initialized: Once::new(),
+            regions: Mutex::new([Self::NO_REGION; MAX_REGIONS]),
+            tracer: Mutex::new(None),
+            grow_fn: Mutex::new(None),
         }
     }
 
@@ -45,37 +90,212 @@ impl<'a> GlobalStaticChunkAllocator<'a> {
         heap: &'a mut [u8],
         bitmap: &'a mut [u8],
     ) -> Result<(), GlobalStaticChunkAllocatorError> {
-        let mut lock = self.inner_allocator.lock();
-        if lock.is_some() {
-            log::error!("Allocator already initialized!");
-            Err(GlobalStaticChunkAllocatorError::AlreadyInitialized)
-        } else {
-            let alloc = ChunkAllocator::new(heap, bitmap)
-                .map_err(|e| GlobalStaticChunkAllocatorError::Inner(e))?;
+        let alloc = A::new(heap, bitmap).map_err(GlobalStaticChunkAllocatorError::Inner)?;
+        self.init_with(alloc)
+    }
+
+    /// Like [`Self::init`] but for heap memory that is known to be zeroed, such as
+    /// static memory in the `.bss` section. See [`HeapAllocator::new_zeroed`].
+    ///
+    /// # Safety
+    /// The whole heap memory must contain zeroes.
+    pub unsafe fn init_zeroed(
+        &self,
+        heap: &'a mut [u8],
+        bitmap: &'a mut [u8],
+    ) -> Result<(), GlobalStaticChunkAllocatorError> {
+        let alloc = A::new_zeroed(heap, bitmap).map_err(GlobalStaticChunkAllocatorError::Inner)?;
+        self.init_with(alloc)
+    }
+
+    fn init_with(&self, alloc: A) -> Result<(), GlobalStaticChunkAllocatorError> {
+        let initialized = self.initialized.call_once(|| {
             log::debug!("initialized the allocator:");
-            log::debug!("  chunks: {}", alloc.chunk_count());
+            log::debug!("  chunks: {}", alloc.capacity() / A::BLOCK_SIZE);
             log::debug!("  heap: {} bytes", alloc.capacity());
-            lock.replace(alloc);
+            self.regions.lock()[0].replace(alloc);
+        });
+        if initialized {
             Ok(())
+        } else {
+            log::error!("Allocator already initialized!");
+            Err(GlobalStaticChunkAllocatorError::AlreadyInitialized)
         }
     }
+
+    /// Adds another memory region to the heap. The bitmap is carved out of the region
+    /// itself, see [`HeapAllocator::new_in_region`]. The allocator must be initialized.
+    ///
+    /// # Safety
+    /// The memory must be unused for the rest of the lifetime of the allocator.
+    pub unsafe fn add_region(
+        &self,
+        region: &'a mut [u8],
+    ) -> Result<(), GlobalStaticChunkAllocatorError> {
+        if !self.initialized.is_completed() {
+            return Err(GlobalStaticChunkAllocatorError::Uninitialized);
+        }
+        let mut regions = self.regions.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(GlobalStaticChunkAllocatorError::TooManyRegions)?;
+        let alloc = A::new_in_region(region).map_err(GlobalStaticChunkAllocatorError::Inner)?;
+        log::debug!(
+            "added heap region: {} chunks, {} bytes",
+            alloc.capacity() / A::BLOCK_SIZE,
+            alloc.capacity()
+        );
+        slot.replace(alloc);
+        Ok(())
+    }
+
+    /// Sets the tracer that gets informed about all allocations, deallocations and
+    /// reallocations. Replaces the previous tracer.
+    pub fn set_tracer(&self, tracer: &'a dyn AllocTracer) {
+        self.tracer.lock().replace(tracer);
+    }
+
+    fn tracer(&self) -> Option<&'a dyn AllocTracer> {
+        *self.tracer.lock()
+    }
+
+    /// Sets the function that is called, when no region can serve an allocation. It
+    /// should add a region with enough memory for the layout, e.g. from a frame
+    /// allocator, with [`Self::add_region`] and return whether it did. Then, the
+    /// allocation is retried. The heap isn't locked during the call, but the function
+    /// shouldn't allocate memory, because the heap is full.
+    pub fn set_grow_fn(&self, grow: fn(Layout) -> bool) {
+        self.grow_fn.lock().replace(grow);
+    }
+
+    /// Runs `alloc` on the locked regions. If it returns null, grows the heap with the
+    /// function of [`Self::set_grow_fn`] and tries again, as long as the heap grows.
+    fn alloc_or_grow(
+        &self,
+        layout: Layout,
+        mut alloc: impl FnMut(&mut [Option<A>]) -> *mut u8,
+    ) -> *mut u8 {
+        loop {
+            let ptr = alloc(self.regions.lock().as_mut_slice());
+            if !ptr.is_null() {
+                return ptr;
+            }
+            let grow = *self.grow_fn.lock();
+            match grow {
+                Some(grow) if grow(layout) => {}
+                _ => return ptr,
+            }
+        }
+    }
+
+    /// Returns the total capacity in bytes of all regions.
+    pub fn capacity(&self) -> usize {
+        self.regions.lock().iter().flatten().map(A::capacity).sum()
+    }
+
+    /// Returns the combined stats of all regions. See [`ChunkAllocatorStats::combine`].
+    pub fn stats(&self) -> ChunkAllocatorStats {
+        Self::combined_stats(self.regions.lock().as_slice())
+    }
+
+    /// Like [`Self::stats`] but returns `None` instead of waiting, if the allocator is
+    /// currently locked. Use this in a panic handler, as the panic may have happened
+    /// while the lock was held.
+    pub fn try_stats(&self) -> Option<ChunkAllocatorStats> {
+        self.regions
+            .try_lock()
+            .map(|regions| Self::combined_stats(regions.as_slice()))
+    }
+
+    fn combined_stats(regions: &[Option<A>]) -> ChunkAllocatorStats {
+        regions
+            .iter()
+            .flatten()
+            .map(A::stats)
+            .fold(ChunkAllocatorStats::default(), |acc, stats| {
+                acc.combine(&stats)
+            })
+    }
+
+    /// Returns the initialized regions. Panics, if the allocator is uninitialized.
+    fn initialized_regions<'r>(regions: &'r mut [Option<A>]) -> impl Iterator<Item = &'r mut A> {
+        assert!(regions[0].is_some(), "allocator is uninitialized");
+        regions.iter_mut().flatten()
+    }
+
+    /// Implementation of [`GlobalAlloc::realloc`]. Moves the allocation into another
+    /// region, if its own region is full.
+    #[track_caller]
+    unsafe fn realloc_in_regions(
+        regions: &mut [Option<A>],
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let region = Self::region_of(regions, ptr);
+        match region.try_realloc(ptr, layout, new_size) {
+            Ok(new_ptr) => new_ptr.as_ptr(),
+            // the region of the allocation is full => move to another region
+            Err(ChunkAllocatorError::OutOfMemory) => {
+                let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+                    Ok(new_layout) => new_layout,
+                    Err(_) => return ptr::null_mut(),
+                };
+                let new_ptr = match Self::initialized_regions(regions)
+                    .find_map(|r| r.try_alloc(new_layout).ok())
+                {
+                    Some(new_ptr) => new_ptr.as_ptr(),
+                    None => return ptr::null_mut(),
+                };
+                ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+                Self::dealloc_in_region(Self::region_of(regions, ptr), ptr, layout);
+                new_ptr
+            }
+            Err(e) => panic!(
+                "Can't realloc pointer {:?} with layout {:?}: {:?}",
+                ptr, layout, e
+            ),
+        }
+    }
+
+    /// Frees the allocation. Panics, if `ptr` and `layout` don't describe a valid
+    /// allocation of the region.
+    #[track_caller]
+    unsafe fn dealloc_in_region(region: &mut A, ptr: *mut u8, layout: Layout) {
+        if let Err(e) = region.try_dealloc(ptr, layout) {
+            panic!(
+                "Can't dealloc pointer {:?} with layout {:?}: {:?}",
+                ptr, layout, e
+            );
+        }
+    }
+
+    /// Returns the region the allocation belongs to. Panics, if there is none.
+    #[track_caller]
+    fn region_of<'r>(regions: &'r mut [Option<A>], ptr: *mut u8) -> &'r mut A {
+        Self::initialized_regions(regions)
+            .find(|r| r.contains(ptr))
+            .unwrap_or_else(|| panic!("pointer {:?} doesn't belong to the heap", ptr))
+    }
 }
 
-unsafe impl<'a> GlobalAlloc for GlobalStaticChunkAllocator<'a> {
+unsafe impl<'a, A: HeapAllocator<'a>, R: RawLock> GlobalAlloc for GlobalStaticAllocator<'a, A, R> {
     #[track_caller]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // DON'T USE RECURSIVE ALLOCATING HERE
         // LIKE format!().. otherwise infinite loop because of the (dead)lock
 
-        let mut lock = self.inner_allocator.lock();
-        let lock = lock.as_mut().expect("allocator is uninitialized");
-        let x = lock.alloc(layout);
-        /*log::debug!(
-            "allocated {} bytes at address 0x{:x}",
-            layout.size(),
-            x as usize
-        );*/
-        x
+        let location = Location::caller();
+        let ptr = self.alloc_or_grow(layout, |regions| {
+            Self::initialized_regions(regions)
+                .find_map(|r| r.try_alloc(layout).ok())
+                .map_or(ptr::null_mut(), NonNull::as_ptr)
+        });
+        if let Some(tracer) = self.tracer() {
+            tracer.on_alloc(layout, ptr, location);
+        }
+        ptr
     }
 
     #[track_caller]
@@ -83,10 +303,240 @@ unsafe impl<'a> GlobalAlloc for GlobalStaticChunkAllocator<'a> {
         // DON'T USE RECURSIVE ALLOCATING HERE
         // LIKE format!().. otherwise infinite loop because of the (dead)lock
 
-        // log::debug!("dealloc: ptr={:?}, layout={:?}", ptr, layout);
-        let mut lock = self.inner_allocator.lock();
-        let lock = lock.as_mut().expect("allocator is uninitialized");
-        lock.dealloc(ptr, layout)
+        let location = Location::caller();
+        let mut regions = self.regions.lock();
+        Self::dealloc_in_region(Self::region_of(regions.as_mut_slice(), ptr), ptr, layout);
+        if let Some(tracer) = self.tracer() {
+            tracer.on_dealloc(layout, ptr, location);
+        }
+    }
+
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let location = Location::caller();
+        let ptr = self.alloc_or_grow(layout, |regions| {
+            Self::initialized_regions(regions)
+                .find_map(|r| r.try_alloc_zeroed(layout).ok())
+                .map_or(ptr::null_mut(), NonNull::as_ptr)
+        });
+        if let Some(tracer) = self.tracer() {
+            tracer.on_alloc(layout, ptr, location);
+        }
+        ptr
+    }
+
+    #[track_caller]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let location = Location::caller();
+        let new_ptr = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => self.alloc_or_grow(new_layout, |regions| {
+                Self::realloc_in_regions(regions, ptr, layout, new_size)
+            }),
+            Err(_) => ptr::null_mut(),
+        };
+        if let Some(tracer) = self.tracer() {
+            tracer.on_realloc(layout, ptr, new_size, new_ptr, location);
+        }
+        new_ptr
+    }
+}
+
+/// Drop-in replacement for [`GlobalStaticAllocator`] that serves small objects
+/// from a [`SlabAllocator`]. The slabs and all bigger allocations come from an inner
+/// [`GlobalStaticAllocator`]. It has the same API, so that switching between both
+/// is a single type swap.
+///
+/// In [`HEAP_DEBUG`] mode, the slabs are bypassed and all objects come from the inner
+/// allocator, so that small objects also get red zones, poisoning and layout checks.
+#[derive(Debug)]
+pub struct GlobalStaticSlabAllocator<
+    'a,
+    A = ChunkAllocator<'a, DEFAULT_ALLOCATOR_CHUNK_SIZE>,
+    R = RawSpinLock,
+> {
+    slabs: Mutex<R, SlabAllocator>,
+    chunks: GlobalStaticAllocator<'a, A, R>,
+    /// Optional hook that gets informed about all heap operations.
+    tracer: Mutex<R, Option<&'a dyn AllocTracer>>,
+}
+
+impl<'a, A: HeapAllocator<'a>, R: RawLock> GlobalStaticSlabAllocator<'a, A, R> {
+    /// See [`GlobalStaticAllocator::CHUNK_SIZE`].
+    pub const CHUNK_SIZE: usize = A::BLOCK_SIZE;
+
+    /// See [`GlobalStaticAllocator::MAX_REGIONS`].
+    pub const MAX_REGIONS: usize = MAX_REGIONS;
+
+    /// Constructor.
+    pub const fn new() -> Self {
+        Self {
+            slabs: Mutex::new(SlabAllocator::new()),
+            chunks: GlobalStaticAllocator::new(),
+            tracer: Mutex::new(None),
+        }
+    }
+
+    /// See [`GlobalStaticAllocator::init`].
+    pub fn init(
+        &self,
+        heap: &'a mut [u8],
+        bitmap: &'a mut [u8],
+    ) -> Result<(), GlobalStaticChunkAllocatorError> {
+        self.chunks.init(heap, bitmap)
+    }
+
+    /// See [`GlobalStaticAllocator::init_zeroed`].
+    ///
+    /// # Safety
+    /// The whole heap memory must contain zeroes.
+    pub unsafe fn init_zeroed(
+        &self,
+        heap: &'a mut [u8],
+        bitmap: &'a mut [u8],
+    ) -> Result<(), GlobalStaticChunkAllocatorError> {
+        self.chunks.init_zeroed(heap, bitmap)
+    }
+
+    /// See [`GlobalStaticAllocator::add_region`].
+    ///
+    /// # Safety
+    /// The memory must be unused for the rest of the lifetime of the allocator.
+    pub unsafe fn add_region(
+        &self,
+        region: &'a mut [u8],
+    ) -> Result<(), GlobalStaticChunkAllocatorError> {
+        self.chunks.add_region(region)
+    }
+
+    /// See [`GlobalStaticAllocator::set_tracer`]. Slabs that are requested from
+    /// the inner allocator are not traced.
+    pub fn set_tracer(&self, tracer: &'a dyn AllocTracer) {
+        self.tracer.lock().replace(tracer);
+    }
+
+    fn tracer(&self) -> Option<&'a dyn AllocTracer> {
+        *self.tracer.lock()
+    }
+
+    /// See [`GlobalStaticAllocator::set_grow_fn`].
+    pub fn set_grow_fn(&self, grow: fn(Layout) -> bool) {
+        self.chunks.set_grow_fn(grow);
+    }
+
+    /// See [`GlobalStaticAllocator::capacity`].
+    pub fn capacity(&self) -> usize {
+        self.chunks.capacity()
+    }
+
+    /// See [`GlobalStaticAllocator::stats`]. Slabs count as used chunks, even
+    /// if all of their objects are free.
+    pub fn stats(&self) -> ChunkAllocatorStats {
+        self.chunks.stats()
+    }
+
+    /// See [`GlobalStaticAllocator::try_stats`].
+    pub fn try_stats(&self) -> Option<ChunkAllocatorStats> {
+        self.chunks.try_stats()
+    }
+
+    /// Returns the size class of the slabs that serves `layout` or `None`, if the
+    /// allocation comes from the chunks.
+    fn size_class(layout: Layout) -> Option<usize> {
+        if HEAP_DEBUG {
+            None
+        } else {
+            SlabAllocator::size_class(layout)
+        }
+    }
+
+    /// Allocates a small object from the slabs or a big one from the chunks.
+    unsafe fn alloc_untraced(&self, layout: Layout) -> *mut u8 {
+        if Self::size_class(layout).is_none() {
+            return self.chunks.alloc(layout);
+        }
+        self.slabs
+            .lock()
+            .try_alloc(layout, |slab_layout| {
+                NonNull::new(self.chunks.alloc(slab_layout))
+            })
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    /// Counterpart of [`Self::alloc_untraced`].
+    #[track_caller]
+    unsafe fn dealloc_untraced(&self, ptr: *mut u8, layout: Layout) {
+        match NonNull::new(ptr) {
+            Some(ptr) if Self::size_class(layout).is_some() => {
+                self.slabs.lock().dealloc(ptr, layout)
+            }
+            _ => self.chunks.dealloc(ptr, layout),
+        }
+    }
+}
+
+unsafe impl<'a, A: HeapAllocator<'a>, R: RawLock> GlobalAlloc
+    for GlobalStaticSlabAllocator<'a, A, R>
+{
+    #[track_caller]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let location = Location::caller();
+        let ptr = self.alloc_untraced(layout);
+        if let Some(tracer) = self.tracer() {
+            tracer.on_alloc(layout, ptr, location);
+        }
+        ptr
+    }
+
+    #[track_caller]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let location = Location::caller();
+        self.dealloc_untraced(ptr, layout);
+        if let Some(tracer) = self.tracer() {
+            tracer.on_dealloc(layout, ptr, location);
+        }
+    }
+
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let location = Location::caller();
+        let ptr = if Self::size_class(layout).is_none() {
+            self.chunks.alloc_zeroed(layout)
+        } else {
+            let ptr = self.alloc_untraced(layout);
+            if !ptr.is_null() {
+                ptr::write_bytes(ptr, 0, layout.size());
+            }
+            ptr
+        };
+        if let Some(tracer) = self.tracer() {
+            tracer.on_alloc(layout, ptr, location);
+        }
+        ptr
+    }
+
+    #[track_caller]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let location = Location::caller();
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let old_class = Self::size_class(layout);
+        let new_class = Self::size_class(new_layout);
+        let new_ptr = if old_class.is_none() && new_class.is_none() {
+            self.chunks.realloc(ptr, layout, new_size)
+        } else if old_class == new_class {
+            ptr
+        } else {
+            // move between a size class and another size class or the chunks
+            let new_ptr = self.alloc_untraced(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+                self.dealloc_untraced(ptr, layout);
+            }
+            new_ptr
+        };
+        if let Some(tracer) = self.tracer() {
+            tracer.on_realloc(layout, ptr, new_size, new_ptr, location);
+        }
+        new_ptr
     }
 }
 
@@ -95,6 +545,7 @@ unsafe impl<'a> GlobalAlloc for GlobalStaticChunkAllocator<'a> {
 mod tests {
     use super::*;
     use crate::mem::{PageAlignedByteBuf, PAGE_SIZE};
+    use core::sync::atomic::{AtomicUsize, Ordering};
 
     // must be a multiple of 8; 32 is equivalent to two pages
     const CHUNK_COUNT: usize = 32;
@@ -105,6 +556,41 @@ mod tests {
 
     static ALLOCATOR: GlobalStaticChunkAllocator = GlobalStaticChunkAllocator::new();
 
+    /// Returns the size of an allocation that occupies exactly `chunks` chunks, including
+    /// the overhead of `heap-debug` mode.
+    fn size_of_chunks(chunks: usize) -> usize {
+        chunks * DEFAULT_ALLOCATOR_CHUNK_SIZE
+            - ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::allocation_overhead(1)
+    }
+
+    /// Returns leaked, page-aligned heap memory of `chunks` chunks and the matching
+    /// bitmap. The slabs need page-aligned memory.
+    fn new_memory(chunks: usize) -> (&'static mut [u8], &'static mut [u8]) {
+        let layout =
+            Layout::from_size_align(chunks * DEFAULT_ALLOCATOR_CHUNK_SIZE, PAGE_SIZE).unwrap();
+        let heap = unsafe {
+            core::slice::from_raw_parts_mut(std::alloc::alloc_zeroed(layout), layout.size())
+        };
+        let bitmap = Box::leak(vec![0_u8; chunks / 8].into_boxed_slice());
+        (heap, bitmap)
+    }
+
+    /// Returns an initialized allocator with a heap of `chunks` chunks.
+    fn new_allocator(chunks: usize) -> GlobalStaticChunkAllocator<'static> {
+        let (heap, bitmap) = new_memory(chunks);
+        let allocator = GlobalStaticChunkAllocator::new();
+        allocator.init(heap, bitmap).unwrap();
+        allocator
+    }
+
+    /// Like [`new_allocator`] but with slabs.
+    fn new_slab_allocator(chunks: usize) -> GlobalStaticSlabAllocator<'static> {
+        let (heap, bitmap) = new_memory(chunks);
+        let allocator = GlobalStaticSlabAllocator::new();
+        allocator.init(heap, bitmap).unwrap();
+        allocator
+    }
+
     #[test]
     fn test_compiles() {
         unsafe {
@@ -113,4 +599,317 @@ mod tests {
             assert_eq!(ptr as u64 % PAGE_SIZE as u64, 0, "must be 4096-bit-aligned");
         };
     }
+
+    #[test]
+    fn test_out_of_memory_returns_null() {
+        let allocator = new_allocator(8);
+
+        unsafe {
+            let layout = Layout::from_size_align(size_of_chunks(8), 1).unwrap();
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            assert!(allocator.alloc(Layout::new::<u8>()).is_null());
+            allocator.dealloc(ptr, layout);
+            assert!(!allocator.alloc(Layout::new::<u8>()).is_null());
+        }
+    }
+
+    #[test]
+    fn test_realloc_in_place() {
+        let heap_size = 8 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let allocator = new_allocator(8);
+
+        unsafe {
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            let ptr = allocator.alloc_zeroed(layout);
+            assert!(!ptr.is_null());
+            let grown = allocator.realloc(ptr, layout, 4 * DEFAULT_ALLOCATOR_CHUNK_SIZE);
+            assert!(!grown.is_null());
+            // `heap-debug` mode never reallocates in place
+            assert_eq!(ptr == grown, !HEAP_DEBUG);
+            let layout = Layout::from_size_align(4 * DEFAULT_ALLOCATOR_CHUNK_SIZE, 8).unwrap();
+            assert!(allocator.realloc(grown, layout, heap_size + 1).is_null());
+            allocator.dealloc(grown, layout);
+        }
+    }
+
+    #[test]
+    fn test_multiple_regions() {
+        let heap_size = 8 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let (heap, bitmap) = new_memory(8);
+        let allocator: GlobalStaticChunkAllocator = GlobalStaticChunkAllocator::new();
+
+        unsafe {
+            let region =
+                Box::leak(vec![0_u8; 32 * DEFAULT_ALLOCATOR_CHUNK_SIZE].into_boxed_slice());
+            assert!(matches!(
+                allocator.add_region(region),
+                Err(GlobalStaticChunkAllocatorError::Uninitialized)
+            ));
+
+            allocator.init(heap, bitmap).unwrap();
+            let region =
+                Box::leak(vec![0_u8; 32 * DEFAULT_ALLOCATOR_CHUNK_SIZE].into_boxed_slice());
+            let region_range = region.as_ptr_range();
+            allocator.add_region(region).unwrap();
+            assert!(allocator.capacity() > heap_size);
+
+            // fills the first region
+            let layout = Layout::from_size_align(size_of_chunks(8), 1).unwrap();
+            let ptr1 = allocator.alloc(layout);
+            assert!(!ptr1.is_null());
+            assert!(!region_range.contains(&(ptr1 as *const u8)));
+
+            // served by the second region
+            let small = Layout::from_size_align(16, 8).unwrap();
+            let ptr2 = allocator.alloc(small);
+            assert!(region_range.contains(&(ptr2 as *const u8)));
+
+            // the first region is full: realloc moves the allocation into the second region
+            let ptr1 = allocator.realloc(ptr1, layout, heap_size + 1);
+            assert!(region_range.contains(&(ptr1 as *const u8)));
+            let layout = Layout::from_size_align(heap_size + 1, 1).unwrap();
+
+            allocator.dealloc(ptr2, small);
+            allocator.dealloc(ptr1, layout);
+            assert!(!allocator
+                .alloc(Layout::from_size_align(size_of_chunks(8), 1).unwrap())
+                .is_null());
+
+            for _ in 1..<GlobalStaticChunkAllocator>::MAX_REGIONS - 1 {
+                let region =
+                    Box::leak(vec![0_u8; 16 * DEFAULT_ALLOCATOR_CHUNK_SIZE].into_boxed_slice());
+                allocator.add_region(region).unwrap();
+            }
+            let region =
+                Box::leak(vec![0_u8; 16 * DEFAULT_ALLOCATOR_CHUNK_SIZE].into_boxed_slice());
+            assert!(matches!(
+                allocator.add_region(region),
+                Err(GlobalStaticChunkAllocatorError::TooManyRegions)
+            ));
+        }
+    }
+
+    static GROWING_ALLOCATOR: GlobalStaticChunkAllocator = GlobalStaticChunkAllocator::new();
+
+    /// Adds a region to [`GROWING_ALLOCATOR`] that can serve `layout`.
+    fn grow(layout: Layout) -> bool {
+        let size = layout.size() + 16 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let region = Box::leak(vec![0_u8; size].into_boxed_slice());
+        unsafe { GROWING_ALLOCATOR.add_region(region).is_ok() }
+    }
+
+    #[test]
+    fn test_grow() {
+        let heap_size = 8 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let (heap, bitmap) = new_memory(8);
+        GROWING_ALLOCATOR.init(heap, bitmap).unwrap();
+        GROWING_ALLOCATOR.set_grow_fn(grow);
+
+        unsafe {
+            // fills the first region
+            let layout = Layout::from_size_align(size_of_chunks(8), 1).unwrap();
+            let ptr1 = GROWING_ALLOCATOR.alloc(layout);
+            assert!(!ptr1.is_null());
+            assert_eq!(GROWING_ALLOCATOR.capacity(), heap_size);
+
+            let ptr2 = GROWING_ALLOCATOR.alloc(layout);
+            assert!(!ptr2.is_null());
+            assert!(GROWING_ALLOCATOR.capacity() > heap_size);
+
+            // too big for all regions
+            let capacity = GROWING_ALLOCATOR.capacity();
+            let ptr1 = GROWING_ALLOCATOR.realloc(ptr1, layout, capacity);
+            assert!(!ptr1.is_null());
+            assert!(GROWING_ALLOCATOR.capacity() > capacity);
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let heap_size = 8 * DEFAULT_ALLOCATOR_CHUNK_SIZE;
+        let allocator = new_allocator(8);
+        let region = Box::leak(vec![0_u8; 32 * DEFAULT_ALLOCATOR_CHUNK_SIZE].into_boxed_slice());
+        unsafe { allocator.add_region(region).unwrap() };
+
+        let stats = allocator.stats();
+        assert_eq!(stats.used_chunks, 0);
+        assert_eq!(stats.free_bytes(), allocator.capacity());
+        assert_eq!(stats.free_runs, 2);
+
+        unsafe {
+            let layout = Layout::from_size_align(size_of_chunks(8), 1).unwrap();
+            let ptr = allocator.alloc(layout);
+            let stats = allocator.try_stats().unwrap();
+            assert_eq!(stats.used_bytes(), heap_size);
+            assert_eq!(stats.free_runs, 1);
+            assert_eq!(stats.alloc_count, 1);
+
+            let lock = allocator.regions.lock();
+            assert!(allocator.try_stats().is_none());
+            drop(lock);
+
+            allocator.dealloc(ptr, layout);
+            let stats = allocator.stats();
+            assert_eq!(stats.used_chunks, 0);
+            assert_eq!(stats.peak_used_chunks, 8);
+            assert_eq!(stats.dealloc_count, 1);
+        }
+    }
+
+    #[test]
+    fn test_buddy_backend() {
+        const HEAP_SIZE: usize = 16 * DEFAULT_BUDDY_BLOCK_SIZE;
+        let heap = Box::leak(Box::new(PageAlignedByteBuf::<HEAP_SIZE>::new_zeroed()));
+        let metadata = Box::leak(
+            vec![0_u8; BuddyAllocator::<DEFAULT_BUDDY_BLOCK_SIZE>::metadata_size(HEAP_SIZE)]
+                .into_boxed_slice(),
+        );
+        let allocator: GlobalStaticBuddyAllocator = GlobalStaticBuddyAllocator::new();
+        allocator.init(heap.get_mut(), metadata).unwrap();
+        // the metadata comes first, the heap begins at the next page
+        let region = Box::leak(Box::new(
+            PageAlignedByteBuf::<{ HEAP_SIZE + PAGE_SIZE }>::new_zeroed(),
+        ));
+        unsafe { allocator.add_region(region.get_mut()).unwrap() };
+        assert_eq!(
+            <GlobalStaticBuddyAllocator>::CHUNK_SIZE,
+            DEFAULT_BUDDY_BLOCK_SIZE
+        );
+
+        unsafe {
+            // the first region is full, the rest comes from the second one
+            let layout = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+            let first = allocator.alloc(layout);
+            assert!(!first.is_null());
+            let small = Layout::from_size_align(100, 8).unwrap();
+            let second = allocator.alloc(small);
+            assert!(!second.is_null());
+            assert_eq!(allocator.stats().used_chunks, 16 + 1);
+
+            let second = allocator.realloc(second, small, 1000);
+            assert!(!second.is_null());
+            allocator.dealloc(second, Layout::from_size_align(1000, 8).unwrap());
+            allocator.dealloc(first, layout);
+            assert_eq!(allocator.stats().used_chunks, 0);
+        }
+    }
+
+    /// Counts the events and remembers the line of the last caller.
+    #[derive(Debug, Default)]
+    struct CountingTracer {
+        allocs: AtomicUsize,
+        deallocs: AtomicUsize,
+        reallocs: AtomicUsize,
+        last_line: AtomicUsize,
+    }
+
+    impl AllocTracer for CountingTracer {
+        fn on_alloc(&self, _layout: Layout, ptr: *mut u8, location: &'static Location<'static>) {
+            assert!(!ptr.is_null());
+            self.allocs.fetch_add(1, Ordering::SeqCst);
+            self.last_line
+                .store(location.line() as usize, Ordering::SeqCst);
+        }
+
+        fn on_dealloc(&self, _layout: Layout, _ptr: *mut u8, location: &'static Location<'static>) {
+            self.deallocs.fetch_add(1, Ordering::SeqCst);
+            self.last_line
+                .store(location.line() as usize, Ordering::SeqCst);
+        }
+
+        fn on_realloc(
+            &self,
+            _layout: Layout,
+            _ptr: *mut u8,
+            _new_size: usize,
+            _new_ptr: *mut u8,
+            location: &'static Location<'static>,
+        ) {
+            self.reallocs.fetch_add(1, Ordering::SeqCst);
+            self.last_line
+                .store(location.line() as usize, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_tracer() {
+        let allocator = new_allocator(8);
+        let tracer = Box::leak(Box::new(CountingTracer::default()));
+        allocator.set_tracer(tracer);
+
+        unsafe {
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            let ptr = allocator.alloc(layout);
+            let line = line!() - 1;
+            assert_eq!(tracer.last_line.load(Ordering::SeqCst), line as usize);
+            let ptr = allocator.realloc(ptr, layout, 32);
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            allocator.dealloc(ptr, layout);
+            let ptr = allocator.alloc_zeroed(layout);
+            allocator.dealloc(ptr, layout);
+        }
+
+        assert_eq!(tracer.allocs.load(Ordering::SeqCst), 2);
+        assert_eq!(tracer.reallocs.load(Ordering::SeqCst), 1);
+        assert_eq!(tracer.deallocs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_slab_allocator() {
+        // in `heap-debug` mode, each small object occupies its own chunk
+        let allocator = new_slab_allocator(if HEAP_DEBUG { 128 } else { 16 });
+        let tracer = Box::leak(Box::new(CountingTracer::default()));
+        allocator.set_tracer(tracer);
+
+        unsafe {
+            // many small objects share one slab
+            let small = Layout::new::<u64>();
+            let ptrs = (0..64).map(|_| allocator.alloc(small)).collect::<Vec<_>>();
+            assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+            let expected_chunk_allocs = if HEAP_DEBUG { 64 } else { 1 };
+            assert_eq!(allocator.stats().alloc_count, expected_chunk_allocs);
+
+            // big allocations are served by the chunks
+            let big = Layout::from_size_align(1000, 8).unwrap();
+            let big_ptr = allocator.alloc_zeroed(big);
+            assert!(!big_ptr.is_null());
+
+            // grow from a size class to the chunks and shrink back
+            ptrs[0].cast::<u64>().write(0x1234);
+            let grown = allocator.realloc(ptrs[0], small, 500);
+            assert_eq!(grown.cast::<u64>().read(), 0x1234);
+            let shrunk = allocator.realloc(grown, Layout::from_size_align(500, 8).unwrap(), 7);
+            assert_eq!(shrunk.cast::<u64>().read() & 0xff_ffff_ffff_ffff, 0x1234);
+            // stays in the same size class; `heap-debug` mode always moves
+            let same = allocator.realloc(shrunk, Layout::from_size_align(7, 8).unwrap(), 8);
+            assert_eq!(same == shrunk, !HEAP_DEBUG);
+
+            allocator.dealloc(same, small);
+            for ptr in &ptrs[1..] {
+                allocator.dealloc(*ptr, small);
+            }
+            allocator.dealloc(big_ptr, big);
+            assert!(allocator.alloc(Layout::from_size_align(8, 256).unwrap()) as usize % 256 == 0);
+        }
+
+        assert_eq!(tracer.allocs.load(Ordering::SeqCst), 64 + 2);
+        assert_eq!(tracer.reallocs.load(Ordering::SeqCst), 3);
+        assert_eq!(tracer.deallocs.load(Ordering::SeqCst), 64 + 1);
+    }
+
+    /// Small objects get red zones in `heap-debug` mode, although they fit into a slab.
+    #[cfg(feature = "heap-debug")]
+    #[test]
+    #[should_panic(expected = "CorruptedRedZone")]
+    fn test_debug_small_object_overflow() {
+        let allocator = new_slab_allocator(16);
+
+        unsafe {
+            let layout = Layout::new::<u64>();
+            let ptr = allocator.alloc(layout);
+            ptr.add(layout.size()).write(0);
+            allocator.dealloc(ptr, layout);
+        }
+    }
 }
@@ -0,0 +1,154 @@
+//! Module for [`HeapAllocator`].
+
+use crate::kernelheap::chunk_allocator::{ChunkAllocatorError, ChunkAllocatorStats};
+use crate::mutex::{Mutex, RawLock};
+use core::alloc::{AllocError, Allocator, Layout};
+use core::fmt::Debug;
+use core::ptr::{self, NonNull};
+
+/// Common interface of the heap backends, such as
+/// [`super::chunk_allocator::ChunkAllocator`] and
+/// [`super::buddy_allocator::BuddyAllocator`]. The global allocators in
+/// [`super::global_static_allocator`] are generic over it.
+///
+/// All backends manage borrowed external memory plus a borrowed metadata storage
+/// and share [`ChunkAllocatorError`] as error type. The stats count in units of
+/// [`Self::BLOCK_SIZE`].
+pub trait HeapAllocator<'a>: Sized + Debug {
+    /// Granularity of allocations in bytes.
+    const BLOCK_SIZE: usize;
+
+    /// Creates a new allocator object that manages `heap` and keeps its bookkeeping
+    /// in `metadata`.
+    fn new(heap: &'a mut [u8], metadata: &'a mut [u8]) -> Result<Self, ChunkAllocatorError>;
+
+    /// Like [`Self::new`] but the heap memory is known to be zeroed. Backends can use
+    /// this to speed up [`Self::try_alloc_zeroed`].
+    ///
+    /// # Safety
+    /// The whole heap memory must contain zeroes.
+    unsafe fn new_zeroed(
+        heap: &'a mut [u8],
+        metadata: &'a mut [u8],
+    ) -> Result<Self, ChunkAllocatorError> {
+        Self::new(heap, metadata)
+    }
+
+    /// Creates a new allocator object that manages the given memory region. The
+    /// metadata is carved out of the region itself.
+    fn new_in_region(region: &'a mut [u8]) -> Result<Self, ChunkAllocatorError>;
+
+    /// Returns whether the pointer points into the heap memory of this allocator.
+    fn contains(&self, ptr: *const u8) -> bool;
+
+    /// Capacity in bytes of the allocator.
+    fn capacity(&self) -> usize;
+
+    /// Returns a snapshot of the usage and the fragmentation of the heap.
+    fn stats(&self) -> ChunkAllocatorStats;
+
+    /// Returns a pointer to the beginning of the allocation.
+    fn try_alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError>;
+
+    /// Like [`Self::try_alloc`] but the memory is guaranteed to contain zeroes.
+    fn try_alloc_zeroed(&mut self, layout: Layout) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        let ptr = self.try_alloc(layout)?;
+        unsafe { ptr::write_bytes(ptr.as_ptr(), 0, layout.size()) };
+        Ok(ptr)
+    }
+
+    /// Frees the allocation. On error, the state of the allocator is unchanged.
+    ///
+    /// # Safety
+    /// Same contract as [`core::alloc::GlobalAlloc::dealloc`].
+    unsafe fn try_dealloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Result<(), ChunkAllocatorError>;
+
+    /// Resizes the allocation to `new_size` bytes. By default, the data is always moved.
+    /// On error, the old allocation stays untouched.
+    ///
+    /// # Safety
+    /// Same contract as [`core::alloc::GlobalAlloc::realloc`].
+    unsafe fn try_realloc(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ChunkAllocatorError> {
+        let new_layout = Layout::from_size_align(new_size, layout.align())
+            .map_err(|_| ChunkAllocatorError::OutOfMemory)?;
+        let new_ptr = self.try_alloc(new_layout)?;
+        ptr::copy_nonoverlapping(
+            ptr,
+            new_ptr.as_ptr(),
+            core::cmp::min(layout.size(), new_size),
+        );
+        if let Err(e) = self.try_dealloc(ptr, layout) {
+            // restore the old state
+            self.try_dealloc(new_ptr.as_ptr(), new_layout).unwrap();
+            return Err(e);
+        }
+        Ok(new_ptr)
+    }
+}
+
+/// Makes a locked [`HeapAllocator`] usable as [`Allocator`], e.g. for collections like
+/// `Vec<T, &SimpleMutex<ChunkAllocator>>` that should not use the global heap. Works with
+/// each kind of [`Mutex`].
+unsafe impl<'a, R: RawLock, A: HeapAllocator<'a>> Allocator for Mutex<R, A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.lock().try_alloc(layout).map_err(|_| AllocError)?;
+        Ok(NonNull::new(ptr::slice_from_raw_parts_mut(ptr.as_ptr(), layout.size())).unwrap())
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self
+            .lock()
+            .try_alloc_zeroed(layout)
+            .map_err(|_| AllocError)?;
+        Ok(NonNull::new(ptr::slice_from_raw_parts_mut(ptr.as_ptr(), layout.size())).unwrap())
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if let Err(e) = self.lock().try_dealloc(ptr.as_ptr(), layout) {
+            panic!(
+                "Can't dealloc pointer {:?} with layout {:?}: {:?}",
+                ptr, layout, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernelheap::chunk_allocator::{ChunkAllocator, DEFAULT_ALLOCATOR_CHUNK_SIZE};
+    use crate::mutex::SimpleMutex;
+
+    #[test]
+    fn test_locked_allocator() {
+        const CHUNK_COUNT: usize = 16;
+        let heap =
+            Box::leak(vec![0_u8; CHUNK_COUNT * DEFAULT_ALLOCATOR_CHUNK_SIZE].into_boxed_slice());
+        let bitmap = Box::leak(vec![0_u8; CHUNK_COUNT / 8].into_boxed_slice());
+        let alloc = SimpleMutex::new(
+            ChunkAllocator::<DEFAULT_ALLOCATOR_CHUNK_SIZE>::new(heap, bitmap).unwrap(),
+        );
+
+        {
+            let mut vec = Vec::new_in(&alloc);
+            vec.extend(0..100_u64);
+            assert_eq!(vec.iter().sum::<u64>(), 4950);
+            assert!(alloc.lock().stats().used_chunks > 0);
+            let boxed = Box::new_in(7_u32, &alloc);
+            assert_eq!(*boxed, 7);
+        }
+        assert_eq!(alloc.lock().stats().used_chunks, 0);
+
+        let too_big = Layout::from_size_align(CHUNK_COUNT * DEFAULT_ALLOCATOR_CHUNK_SIZE + 1, 1);
+        assert_eq!(alloc.allocate(too_big.unwrap()), Err(AllocError));
+    }
+}
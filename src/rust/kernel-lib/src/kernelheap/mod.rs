@@ -1,10 +1,20 @@
-//! Module for the kernel heap. So far, my solution is simple. I have all the heap
-//! already inside the binary as static array. Therefore, I don't have to work with
-//! page tables, find free frames from the memory map etc.
+//! Module for the kernel heap. The heap consists of regions: usually a static array
+//! inside the binary for the early boot and more regions from free physical memory,
+//! which are added at runtime. See [`global_static_allocator::GlobalStaticAllocator`].
 //!
 //! My chunk allocator is the heart of the functionality. It gets a slice of memory,
 //! a second slice as management storage, and then can manage the memory. It manages
-//! the memory in chunks of 256 bytes.
+//! the memory in chunks of 256 bytes. The buddy allocator is an alternative backend
+//! with the same interface, see [`heap_allocator::HeapAllocator`].
+//!
+//! Both can also serve as [`core::alloc::Allocator`] when wrapped in a
+//! [`crate::mutex::SimpleMutex`]. Short-lived data of a boot phase can use a
+//! [`bump_arena::BumpArena`] instead.
 
+pub mod buddy_allocator;
+pub mod bump_arena;
 pub mod chunk_allocator;
 pub mod global_static_allocator;
+pub mod heap_allocator;
+pub mod slab_allocator;
+pub mod tracer;
@@ -0,0 +1,181 @@
+//! Module for [`SlabAllocator`].
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+/// Object sizes that are served by the [`SlabAllocator`]. Bigger allocations must be
+/// served by the backing allocator.
+pub const SIZE_CLASSES: [usize; 5] = [8, 16, 32, 64, 128];
+
+/// Size of the memory that the [`SlabAllocator`] requests from the backing allocator,
+/// when a size class runs out of free objects. Equals four chunks of the
+/// [`super::chunk_allocator::ChunkAllocator`] with the default chunk size.
+pub const SLAB_SIZE: usize = 1024;
+
+/// Possible errors for [`SlabAllocator`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlabAllocatorError {
+    /// The layout doesn't fit into any size class.
+    TooLarge,
+    /// The backing allocator couldn't provide a new slab.
+    OutOfMemory,
+}
+
+/// Free object of a size class. The free objects of each size class build an
+/// intrusive singly linked list.
+#[derive(Debug)]
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Size-class allocator for small objects in front of a chunk-based allocator.
+/// Small allocations waste most of a chunk otherwise. Each size class keeps a list
+/// of free objects. If the list is empty, the class gets a new slab of [`SLAB_SIZE`]
+/// bytes from the backing allocator and splits it into objects.
+///
+/// Slabs are never returned to the backing allocator. Freed objects stay reserved
+/// for their size class.
+///
+/// It is mandatory to wrap this allocator by a mutex or a similar primitive, if it
+/// should be used in a global context.
+#[derive(Debug)]
+pub struct SlabAllocator {
+    free_lists: [Option<NonNull<FreeObject>>; SIZE_CLASSES.len()],
+}
+
+impl SlabAllocator {
+    /// Constructor.
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [None; SIZE_CLASSES.len()],
+        }
+    }
+
+    /// Returns the index of the smallest size class that can hold the layout or `None`,
+    /// if the layout is too large. Objects are aligned to their size, because slabs are
+    /// aligned to the biggest size class.
+    pub fn size_class(layout: Layout) -> Option<usize> {
+        let size = core::cmp::max(layout.size(), layout.align());
+        SIZE_CLASSES.iter().position(|class| size <= *class)
+    }
+
+    /// Allocates an object for the layout. If the size class has no free objects,
+    /// `new_slab` is called with the layout of a new slab.
+    pub fn try_alloc(
+        &mut self,
+        layout: Layout,
+        new_slab: impl FnOnce(Layout) -> Option<NonNull<u8>>,
+    ) -> Result<NonNull<u8>, SlabAllocatorError> {
+        let class = Self::size_class(layout).ok_or(SlabAllocatorError::TooLarge)?;
+        if self.free_lists[class].is_none() {
+            let slab = new_slab(Self::slab_layout()).ok_or(SlabAllocatorError::OutOfMemory)?;
+            unsafe { self.add_slab(class, slab) };
+        }
+
+        let object = self.free_lists[class].unwrap();
+        self.free_lists[class] = unsafe { object.as_ref().next };
+        Ok(object.cast())
+    }
+
+    /// Returns the object to its size class.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`Self::try_alloc`] with the same layout.
+    /// The memory of the object must not be used after this call.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let class = Self::size_class(layout).expect("layout doesn't belong to a size class");
+        self.push(class, ptr.cast());
+    }
+
+    /// Returns the layout of the slabs, that are requested from the backing allocator.
+    pub fn slab_layout() -> Layout {
+        Layout::from_size_align(SLAB_SIZE, SIZE_CLASSES[SIZE_CLASSES.len() - 1]).unwrap()
+    }
+
+    /// Splits the slab into objects of the size class and adds them to the free list.
+    unsafe fn add_slab(&mut self, class: usize, slab: NonNull<u8>) {
+        let object_size = SIZE_CLASSES[class];
+        // push in reverse order, so that objects get handed out in ascending order
+        for offset in (0..SLAB_SIZE).step_by(object_size).rev() {
+            let object = NonNull::new_unchecked(slab.as_ptr().add(offset));
+            self.push(class, object.cast());
+        }
+    }
+
+    unsafe fn push(&mut self, class: usize, object: NonNull<FreeObject>) {
+        object.as_ptr().write(FreeObject {
+            next: self.free_lists[class],
+        });
+        self.free_lists[class] = Some(object);
+    }
+}
+
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::PageAlignedByteBuf;
+
+    #[test]
+    fn test_size_class() {
+        let class =
+            |size, align| SlabAllocator::size_class(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(0, 1), Some(0));
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(8, 8), Some(0));
+        assert_eq!(class(9, 1), Some(1));
+        assert_eq!(class(4, 64), Some(3));
+        assert_eq!(class(128, 8), Some(4));
+        assert_eq!(class(129, 8), None);
+        assert_eq!(class(8, 256), None);
+    }
+
+    #[test]
+    fn test_alloc_dealloc() {
+        let slabs = Box::leak(Box::new(
+            PageAlignedByteBuf::<{ 2 * SLAB_SIZE }>::new_zeroed(),
+        ));
+        let mut slabs = slabs.get_mut().chunks_exact_mut(SLAB_SIZE);
+        let mut new_slab = |layout: Layout| {
+            assert_eq!(layout, SlabAllocator::slab_layout());
+            slabs
+                .next()
+                .map(|slab| NonNull::new(slab.as_mut_ptr()).unwrap())
+        };
+        let mut alloc = SlabAllocator::new();
+
+        let layout = Layout::from_size_align(100, 4).unwrap();
+        let objects = (0..SLAB_SIZE / 128)
+            .map(|_| alloc.try_alloc(layout, &mut new_slab).unwrap())
+            .collect::<Vec<_>>();
+        for (i, object) in objects.iter().enumerate().skip(1) {
+            assert_eq!(
+                object.as_ptr() as usize - objects[0].as_ptr() as usize,
+                i * 128
+            );
+        }
+
+        // freed objects are reused first
+        unsafe { alloc.dealloc(objects[3], layout) };
+        assert_eq!(alloc.try_alloc(layout, &mut new_slab), Ok(objects[3]));
+
+        // the second slab
+        let small = Layout::new::<u64>();
+        let object = alloc.try_alloc(small, &mut new_slab).unwrap();
+        assert_eq!(object.as_ptr() as usize % 8, 0);
+        // no more slabs
+        assert_eq!(
+            alloc.try_alloc(layout, &mut new_slab),
+            Err(SlabAllocatorError::OutOfMemory)
+        );
+        assert_eq!(
+            alloc.try_alloc(Layout::new::<[u8; 256]>(), &mut new_slab),
+            Err(SlabAllocatorError::TooLarge)
+        );
+    }
+}
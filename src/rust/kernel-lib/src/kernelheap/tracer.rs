@@ -0,0 +1,39 @@
+//! Module for [`AllocTracer`].
+
+use core::alloc::Layout;
+use core::fmt::Debug;
+use core::panic::Location;
+
+/// Hook that gets informed about every operation of the [`GlobalStaticAllocator`] or
+/// the [`GlobalStaticSlabAllocator`]. It can be used to record heap timelines, e.g. to
+/// find leaks offline. See [`GlobalStaticAllocator::set_tracer`] and
+/// [`GlobalStaticSlabAllocator::set_tracer`].
+///
+/// The methods may be called while the heap is locked. Hence, they must not allocate
+/// memory, otherwise the system deadlocks.
+///
+/// The location is the innermost caller that is not `#[track_caller]`. For allocations
+/// of collections, such as `Vec`, this is usually a location inside `liballoc`.
+///
+/// [`GlobalStaticAllocator`]: super::global_static_allocator::GlobalStaticAllocator
+/// [`GlobalStaticAllocator::set_tracer`]: super::global_static_allocator::GlobalStaticAllocator::set_tracer
+/// [`GlobalStaticSlabAllocator`]: super::global_static_allocator::GlobalStaticSlabAllocator
+/// [`GlobalStaticSlabAllocator::set_tracer`]: super::global_static_allocator::GlobalStaticSlabAllocator::set_tracer
+pub trait AllocTracer: Debug + Sync {
+    /// Called after an allocation. `ptr` is null, if the allocation failed.
+    fn on_alloc(&self, layout: Layout, ptr: *mut u8, location: &'static Location<'static>);
+
+    /// Called after a deallocation.
+    fn on_dealloc(&self, layout: Layout, ptr: *mut u8, location: &'static Location<'static>);
+
+    /// Called after a reallocation of `ptr` with `layout` to `new_size` bytes. `new_ptr`
+    /// is null, if the reallocation failed. In this case, `ptr` is still valid.
+    fn on_realloc(
+        &self,
+        layout: Layout,
+        ptr: *mut u8,
+        new_size: usize,
+        new_ptr: *mut u8,
+        location: &'static Location<'static>,
+    );
+}
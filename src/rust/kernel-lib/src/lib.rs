@@ -1,8 +1,15 @@
+#![feature(allocator_api)]
 #![feature(const_mut_refs)]
 #![cfg_attr(not(test), no_std)]
 
 pub mod fakelock;
+pub mod irq_mutex;
 pub mod kernelheap;
+pub mod log_levels;
 pub mod mem;
 pub mod mutex;
+pub mod once;
+pub mod paging;
+pub mod ring_buffer;
 pub mod rwlock;
+pub mod ticket_mutex;
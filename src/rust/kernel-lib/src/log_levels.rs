@@ -0,0 +1,150 @@
+//! Module for [`ModuleLevels`].
+
+use log::LevelFilter;
+
+/// Error of [`ModuleLevels::set`]: all entries are in use.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ModuleLevelsFull;
+
+/// Log levels of single modules that override the level of a single sink, such as a
+/// serial port, for messages of that module. Sinks are identified by their index, so
+/// that each logger can define its own sinks. Holds up to `N` overrides.
+#[derive(Debug)]
+pub struct ModuleLevels<const N: usize> {
+    entries: [Option<ModuleLevel>; N],
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ModuleLevel {
+    sink: usize,
+    module: &'static str,
+    level: LevelFilter,
+}
+
+impl<const N: usize> ModuleLevels<N> {
+    pub const fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Overrides the level of `sink` for messages whose target, usually the module path,
+    /// is `module` or a submodule of it. Replaces a previous override of the same sink
+    /// and module.
+    pub fn set(
+        &mut self,
+        sink: usize,
+        module: &'static str,
+        level: LevelFilter,
+    ) -> Result<(), ModuleLevelsFull> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|e| e.sink == sink && e.module == module);
+        if let Some(entry) = entry {
+            entry.level = level;
+            return Ok(());
+        }
+        let free = self
+            .entries
+            .iter_mut()
+            .find(|e| e.is_none())
+            .ok_or(ModuleLevelsFull)?;
+        free.replace(ModuleLevel {
+            sink,
+            module,
+            level,
+        });
+        Ok(())
+    }
+
+    /// Returns the level of `sink` for messages of `target`, if an override applies.
+    /// The most specific module wins.
+    pub fn get(&self, sink: usize, target: &str) -> Option<LevelFilter> {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(|e| e.sink == sink && is_module_or_submodule(target, e.module))
+            .max_by_key(|e| e.module.len())
+            .map(|e| e.level)
+    }
+
+    /// Returns the most verbose level of all overrides.
+    pub fn max(&self) -> LevelFilter {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|e| e.level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+impl<const N: usize> Default for ModuleLevels<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `target` is `module` or one of its submodules.
+fn is_module_or_submodule(target: &str, module: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBUGCON: usize = 0;
+    const SCREEN: usize = 1;
+
+    #[test]
+    fn test_override_only_affects_its_sink() {
+        let mut levels = ModuleLevels::<4>::new();
+        levels
+            .set(DEBUGCON, "kernel_bin::paging", LevelFilter::Trace)
+            .unwrap();
+        assert_eq!(
+            levels.get(DEBUGCON, "kernel_bin::paging"),
+            Some(LevelFilter::Trace)
+        );
+        // the screen keeps its own level
+        assert_eq!(levels.get(SCREEN, "kernel_bin::paging"), None);
+        assert_eq!(levels.max(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_most_specific_module_wins() {
+        let mut levels = ModuleLevels::<4>::new();
+        levels.set(SCREEN, "kernel_bin", LevelFilter::Warn).unwrap();
+        levels
+            .set(SCREEN, "kernel_bin::paging", LevelFilter::Debug)
+            .unwrap();
+        assert_eq!(
+            levels.get(SCREEN, "kernel_bin::paging::builder"),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            levels.get(SCREEN, "kernel_bin::logger"),
+            Some(LevelFilter::Warn)
+        );
+        // only whole path segments match
+        assert_eq!(levels.get(SCREEN, "kernel_bin_x"), None);
+    }
+
+    #[test]
+    fn test_full() {
+        let mut levels = ModuleLevels::<1>::new();
+        assert_eq!(levels.max(), LevelFilter::Off);
+        levels.set(SCREEN, "a", LevelFilter::Info).unwrap();
+        // replacing needs no new entry
+        levels.set(SCREEN, "a", LevelFilter::Error).unwrap();
+        assert_eq!(levels.get(SCREEN, "a"), Some(LevelFilter::Error));
+        assert_eq!(
+            levels.set(DEBUGCON, "a", LevelFilter::Info),
+            Err(ModuleLevelsFull)
+        );
+    }
+}
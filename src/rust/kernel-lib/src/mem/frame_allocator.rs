@@ -0,0 +1,348 @@
+//! Module for [`FrameAllocator`].
+
+use crate::mem::PAGE_SIZE;
+use core::ops::Range;
+
+/// Size of a regular frame.
+pub const FRAME_SIZE: u64 = PAGE_SIZE as u64;
+
+/// Size of a huge frame. Used for 2 MiB pages and for big allocations.
+pub const HUGE_FRAME_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Number of regular frames in a huge frame.
+const FRAMES_PER_HUGE_FRAME: usize = (HUGE_FRAME_SIZE / FRAME_SIZE) as usize;
+
+/// Number of frames that are covered by one word of the bitmap.
+const FRAMES_PER_WORD: usize = u64::BITS as usize;
+
+/// Possible errors for [`FrameAllocator`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameAllocatorError {
+    /// The bitmap can't cover the highest usable address.
+    BitmapTooSmall,
+    /// There is no free frame of the requested size.
+    OutOfMemory,
+    /// The address is not aligned to the frame size.
+    Unaligned,
+    /// The frame is outside the memory that is covered by the bitmap.
+    OutOfRange,
+    /// The frame is already free.
+    DoubleFree,
+}
+
+/// Supported frame sizes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameSize {
+    /// See [`FRAME_SIZE`].
+    Size4KiB,
+    /// See [`HUGE_FRAME_SIZE`].
+    Size2MiB,
+}
+
+impl FrameSize {
+    /// Returns the size in bytes.
+    pub const fn bytes(self) -> u64 {
+        match self {
+            FrameSize::Size4KiB => FRAME_SIZE,
+            FrameSize::Size2MiB => HUGE_FRAME_SIZE,
+        }
+    }
+
+    /// Returns the number of regular frames.
+    const fn frames(self) -> usize {
+        (self.bytes() / FRAME_SIZE) as usize
+    }
+}
+
+/// Physical memory region of the firmware memory map, e.g. a UEFI memory descriptor.
+/// Only regions backed by RAM are relevant. MMIO regions should be left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhysMemoryRegion {
+    /// Physical address range.
+    pub range: Range<u64>,
+    /// Whether the memory is free to use by the kernel. Otherwise, it is still in use,
+    /// e.g. by ACPI tables or the firmware runtime services.
+    pub usable: bool,
+}
+
+/// Allocator for physical memory frames, built from the memory map of the firmware.
+/// It hands out frames of 4 KiB and 2 MiB, see [`FrameSize`].
+///
+/// A bitmap keeps track of all frames from address zero up to the end of the highest
+/// usable region. A set bit marks a free frame. The bitmap is borrowed, so that the
+/// caller decides where it lives. See [`Self::bitmap_len`].
+///
+/// It is mandatory to wrap this allocator by a mutex or a similar primitive, if it
+/// should be used in a global context.
+#[derive(Debug)]
+pub struct FrameAllocator<'a> {
+    bitmap: &'a mut [u64],
+    total_ram: u64,
+    usable_ram: u64,
+    free_frames: usize,
+    /// Index of the first word that may contain a free frame.
+    next_word: usize,
+}
+
+impl<'a> FrameAllocator<'a> {
+    /// Returns the number of words the bitmap needs to cover the physical memory
+    /// up to `phys_end`.
+    pub const fn bitmap_len(phys_end: u64) -> usize {
+        let frames = ((phys_end + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        (frames + FRAMES_PER_WORD - 1) / FRAMES_PER_WORD
+    }
+
+    /// Creates a new frame allocator. All frames of the usable `regions` are free,
+    /// except for those that overlap with a `reserved` range, e.g. the kernel image,
+    /// the boot modules or the boot information. Usable regions are shrunk to full
+    /// frames, reserved ranges are extended to full frames.
+    pub fn new(
+        regions: &[PhysMemoryRegion],
+        reserved: &[Range<u64>],
+        bitmap: &'a mut [u64],
+    ) -> Result<Self, FrameAllocatorError> {
+        bitmap.fill(0);
+        let mut alloc = Self {
+            bitmap,
+            total_ram: 0,
+            usable_ram: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for region in regions {
+            alloc.total_ram += region.range.end - region.range.start;
+            if !region.usable {
+                continue;
+            }
+            let first = Self::frame_index_up(region.range.start);
+            let end = Self::frame_index_down(region.range.end);
+            if end > alloc.bitmap.len() * FRAMES_PER_WORD {
+                return Err(FrameAllocatorError::BitmapTooSmall);
+            }
+            for index in first..end {
+                alloc.mark_free(index);
+            }
+        }
+        for range in reserved {
+            let first = Self::frame_index_down(range.start);
+            let end = core::cmp::min(
+                Self::frame_index_up(range.end),
+                alloc.bitmap.len() * FRAMES_PER_WORD,
+            );
+            for index in first..end {
+                alloc.mark_used(index);
+            }
+        }
+
+        alloc.usable_ram = alloc.free_frames as u64 * FRAME_SIZE;
+        Ok(alloc)
+    }
+
+    /// Size in bytes of all regions of the memory map, including memory that is not
+    /// usable.
+    pub fn total_ram(&self) -> u64 {
+        self.total_ram
+    }
+
+    /// Size in bytes of all frames, that were initially free.
+    pub fn usable_ram(&self) -> u64 {
+        self.usable_ram
+    }
+
+    /// Size in bytes of all frames, that are currently free.
+    pub fn free_ram(&self) -> u64 {
+        self.free_frames as u64 * FRAME_SIZE
+    }
+
+    /// Allocates a frame of the given size and returns its physical address. The
+    /// address is aligned to the frame size.
+    pub fn allocate(&mut self, size: FrameSize) -> Result<u64, FrameAllocatorError> {
+        let index = match size {
+            FrameSize::Size4KiB => self.find_free_frame(),
+            FrameSize::Size2MiB => self.find_free_huge_frame(),
+        }
+        .ok_or(FrameAllocatorError::OutOfMemory)?;
+
+        for i in index..index + size.frames() {
+            self.mark_used(i);
+        }
+        Ok(index as u64 * FRAME_SIZE)
+    }
+
+    /// Returns a frame to the allocator.
+    ///
+    /// # Safety
+    /// The frame must have been returned by [`Self::allocate`] with the same size and
+    /// must not be used after this call.
+    pub unsafe fn free(&mut self, addr: u64, size: FrameSize) -> Result<(), FrameAllocatorError> {
+        if addr % size.bytes() != 0 {
+            return Err(FrameAllocatorError::Unaligned);
+        }
+        let index = (addr / FRAME_SIZE) as usize;
+        if index + size.frames() > self.bitmap.len() * FRAMES_PER_WORD {
+            return Err(FrameAllocatorError::OutOfRange);
+        }
+        if (index..index + size.frames()).any(|i| self.is_free(i)) {
+            return Err(FrameAllocatorError::DoubleFree);
+        }
+
+        for i in index..index + size.frames() {
+            self.mark_free(i);
+        }
+        self.next_word = core::cmp::min(self.next_word, index / FRAMES_PER_WORD);
+        Ok(())
+    }
+
+    /// Returns the index of the first free frame.
+    fn find_free_frame(&mut self) -> Option<usize> {
+        let word = (self.next_word..self.bitmap.len()).find(|&i| self.bitmap[i] != 0)?;
+        self.next_word = word;
+        Some(word * FRAMES_PER_WORD + self.bitmap[word].trailing_zeros() as usize)
+    }
+
+    /// Returns the index of the first frame of the first free and aligned huge frame.
+    fn find_free_huge_frame(&self) -> Option<usize> {
+        const WORDS: usize = FRAMES_PER_HUGE_FRAME / FRAMES_PER_WORD;
+        self.bitmap
+            .chunks_exact(WORDS)
+            .position(|words| words.iter().all(|word| *word == u64::MAX))
+            .map(|huge_index| huge_index * FRAMES_PER_HUGE_FRAME)
+    }
+
+    fn frame_index_up(addr: u64) -> usize {
+        ((addr + FRAME_SIZE - 1) / FRAME_SIZE) as usize
+    }
+
+    fn frame_index_down(addr: u64) -> usize {
+        (addr / FRAME_SIZE) as usize
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap[index / FRAMES_PER_WORD] & (1 << (index % FRAMES_PER_WORD)) != 0
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        if !self.is_free(index) {
+            self.bitmap[index / FRAMES_PER_WORD] |= 1 << (index % FRAMES_PER_WORD);
+            self.free_frames += 1;
+        }
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        if self.is_free(index) {
+            self.bitmap[index / FRAMES_PER_WORD] &= !(1 << (index % FRAMES_PER_WORD));
+            self.free_frames -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn region(start: u64, end: u64, usable: bool) -> PhysMemoryRegion {
+        PhysMemoryRegion {
+            range: start..end,
+            usable,
+        }
+    }
+
+    /// Synthetic map that resembles the one of QEMU with 16 MiB of RAM.
+    fn memory_map() -> [PhysMemoryRegion; 4] {
+        [
+            region(0, 0xa0000, true),
+            region(0x100000, 0x800000, true),
+            region(0x800000, 0x900000, false),
+            region(0x900000, 16 * MIB, true),
+        ]
+    }
+
+    fn new_bitmap(phys_end: u64) -> &'static mut [u64] {
+        Box::leak(vec![0; FrameAllocator::bitmap_len(phys_end)].into_boxed_slice())
+    }
+
+    #[test]
+    fn test_bitmap_len() {
+        assert_eq!(FrameAllocator::bitmap_len(0), 0);
+        assert_eq!(FrameAllocator::bitmap_len(1), 1);
+        assert_eq!(FrameAllocator::bitmap_len(64 * FRAME_SIZE), 1);
+        assert_eq!(FrameAllocator::bitmap_len(64 * FRAME_SIZE + 1), 2);
+        assert_eq!(FrameAllocator::bitmap_len(16 * MIB), 64);
+    }
+
+    #[test]
+    fn test_new() {
+        let reserved = [
+            // below 1 MiB
+            0..0x100000,
+            // kernel image, not aligned
+            0x900100..0xa00010,
+        ];
+        let alloc = FrameAllocator::new(&memory_map(), &reserved, new_bitmap(16 * MIB)).unwrap();
+        assert_eq!(alloc.total_ram(), 0xa0000 + 15 * MIB);
+        assert_eq!(alloc.usable_ram(), 7 * MIB + (7 * MIB - MIB - FRAME_SIZE));
+        assert_eq!(alloc.free_ram(), alloc.usable_ram());
+
+        assert_eq!(
+            FrameAllocator::new(&memory_map(), &[], new_bitmap(8 * MIB)).unwrap_err(),
+            FrameAllocatorError::BitmapTooSmall
+        );
+    }
+
+    #[test]
+    fn test_allocate_and_free() {
+        let mut alloc = FrameAllocator::new(
+            &memory_map(),
+            &[0..0x100000, 0x200000..0x201000],
+            new_bitmap(16 * MIB),
+        )
+        .unwrap();
+
+        let frame = alloc.allocate(FrameSize::Size4KiB).unwrap();
+        assert_eq!(frame, 0x100000);
+        // 0x200000 is reserved, hence the first free huge frame starts at 4 MiB
+        let huge = alloc.allocate(FrameSize::Size2MiB).unwrap();
+        assert_eq!(huge, 4 * MIB);
+        assert_eq!(
+            alloc.free_ram(),
+            alloc.usable_ram() - FRAME_SIZE - HUGE_FRAME_SIZE
+        );
+
+        unsafe {
+            assert_eq!(
+                alloc.free(huge + FRAME_SIZE, FrameSize::Size2MiB),
+                Err(FrameAllocatorError::Unaligned)
+            );
+            assert_eq!(
+                alloc.free(32 * MIB, FrameSize::Size4KiB),
+                Err(FrameAllocatorError::OutOfRange)
+            );
+            alloc.free(frame, FrameSize::Size4KiB).unwrap();
+            assert_eq!(
+                alloc.free(frame, FrameSize::Size4KiB),
+                Err(FrameAllocatorError::DoubleFree)
+            );
+            alloc.free(huge, FrameSize::Size2MiB).unwrap();
+        }
+        assert_eq!(alloc.free_ram(), alloc.usable_ram());
+        assert_eq!(alloc.allocate(FrameSize::Size4KiB), Ok(frame));
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        let map = [region(0x200000, 0x400000, true)];
+        let mut alloc = FrameAllocator::new(&map, &[], new_bitmap(4 * MIB)).unwrap();
+        assert_eq!(alloc.allocate(FrameSize::Size2MiB), Ok(0x200000));
+        assert_eq!(
+            alloc.allocate(FrameSize::Size4KiB),
+            Err(FrameAllocatorError::OutOfMemory)
+        );
+        assert_eq!(
+            alloc.allocate(FrameSize::Size2MiB),
+            Err(FrameAllocatorError::OutOfMemory)
+        );
+    }
+}
@@ -1,4 +1,10 @@
-//! Utilities for memory. Mainly page alignment stuff.
+//! Utilities for memory. Mainly page alignment stuff, range arithmetic with
+//! [`region_set::MemoryRegionSet`], the physical [`frame_allocator::FrameAllocator`] and
+//! [`stack_paint`] to measure stack usage.
+
+pub mod frame_allocator;
+pub mod region_set;
+pub mod stack_paint;
 
 use core::ops::{Deref, DerefMut};
 
@@ -0,0 +1,263 @@
+//! Module for [`MemoryRegionSet`].
+
+use core::ops::Range;
+
+/// Possible errors for [`MemoryRegionSet`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryRegionSetError {
+    /// The operation needs more regions than the capacity of the set. The set is
+    /// unchanged.
+    Full,
+}
+
+/// Set of physical address ranges with a fixed capacity of `N` regions. The regions
+/// are always normalized: they are sorted, and overlapping or adjacent ranges are merged.
+/// This is the range arithmetic that is needed to build the free memory from a firmware
+/// memory map and a list of reserved ranges, such as the kernel image, boot modules or
+/// the boot information.
+#[derive(Debug, Clone)]
+pub struct MemoryRegionSet<const N: usize> {
+    regions: [Range<u64>; N],
+    len: usize,
+}
+
+impl<const N: usize> MemoryRegionSet<N> {
+    const EMPTY: Range<u64> = 0..0;
+
+    /// Creates an empty set.
+    pub const fn new() -> Self {
+        Self {
+            regions: [Self::EMPTY; N],
+            len: 0,
+        }
+    }
+
+    /// Returns the normalized regions in ascending order.
+    pub fn as_slice(&self) -> &[Range<u64>] {
+        &self.regions[..self.len]
+    }
+
+    /// Returns an iterator over the normalized regions in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = &Range<u64>> {
+        self.as_slice().iter()
+    }
+
+    /// Number of regions after normalization.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the set contains no memory.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size in bytes of all regions.
+    pub fn total_size(&self) -> u64 {
+        self.iter().map(|range| range.end - range.start).sum()
+    }
+
+    /// Whether the address is inside a region.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.iter().any(|range| range.contains(&addr))
+    }
+
+    /// Adds the range to the set. It is merged with all overlapping and adjacent regions.
+    pub fn insert(&mut self, range: Range<u64>) -> Result<(), MemoryRegionSetError> {
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        let first = self
+            .iter()
+            .position(|region| region.end >= range.start)
+            .unwrap_or(self.len);
+        let mut merged = range;
+        let mut last = first;
+        while last < self.len && self.regions[last].start <= merged.end {
+            merged.start = core::cmp::min(merged.start, self.regions[last].start);
+            merged.end = core::cmp::max(merged.end, self.regions[last].end);
+            last += 1;
+        }
+
+        if first == last {
+            self.insert_at(first, merged)?;
+        } else {
+            self.regions[first] = merged;
+            for _ in first + 1..last {
+                self.remove_at(first + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the range from the set. Regions that partially overlap are shrunk or
+    /// split.
+    pub fn subtract(&mut self, range: Range<u64>) -> Result<(), MemoryRegionSetError> {
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        let mut i = 0;
+        while i < self.len {
+            let region = self.regions[i].clone();
+            if region.end <= range.start || range.end <= region.start {
+                i += 1;
+            } else if region.start < range.start && range.end < region.end {
+                // Only this region overlaps, hence the set is still unchanged on error.
+                self.insert_at(i + 1, range.end..region.end)?;
+                self.regions[i].end = range.start;
+                return Ok(());
+            } else if region.start < range.start {
+                self.regions[i].end = range.start;
+                i += 1;
+            } else if range.end < region.end {
+                self.regions[i].start = range.end;
+                i += 1;
+            } else {
+                self.remove_at(i);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a set with the parts of all regions, that are aligned to `align`
+    /// at both ends. See [`split_aligned`].
+    pub fn aligned(&self, align: u64) -> Self {
+        let mut aligned = Self::new();
+        for region in self.iter() {
+            let [_, body, _] = split_aligned(region.clone(), align);
+            // can't fail: at most as many regions as in `self`
+            aligned.insert(body).unwrap();
+        }
+        aligned
+    }
+
+    fn insert_at(&mut self, index: usize, range: Range<u64>) -> Result<(), MemoryRegionSetError> {
+        if self.len == N {
+            return Err(MemoryRegionSetError::Full);
+        }
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = range;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove_at(&mut self, index: usize) {
+        self.regions[index..self.len].rotate_left(1);
+        self.len -= 1;
+        self.regions[self.len] = Self::EMPTY;
+    }
+}
+
+impl<const N: usize> Default for MemoryRegionSet<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits the range into a head, a body and a tail. The body is the biggest part that
+/// starts and ends at a multiple of `align`, which must be a power of two. Each part
+/// may be empty. This is useful to map a region with huge pages as far as possible.
+pub fn split_aligned(range: Range<u64>, align: u64) -> [Range<u64>; 3] {
+    assert!(align.is_power_of_two());
+    let mask = align - 1;
+    let up = core::cmp::min(range.start.saturating_add(mask) & !mask, range.end);
+    let down = core::cmp::max(range.end & !mask, up);
+    [range.start..up, up..down, down..range.end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_set<const N: usize>(ranges: &[Range<u64>]) -> MemoryRegionSet<N> {
+        let mut set = MemoryRegionSet::new();
+        for range in ranges {
+            set.insert(range.clone()).unwrap();
+        }
+        set
+    }
+
+    #[test]
+    fn test_insert() {
+        let set = new_set::<8>(&[0x3000..0x4000, 0x1000..0x2000, 0x5000..0x6000]);
+        assert_eq!(
+            set.as_slice(),
+            &[0x1000..0x2000, 0x3000..0x4000, 0x5000..0x6000]
+        );
+
+        // adjacent ranges are merged
+        let mut merged = set.clone();
+        merged.insert(0x2000..0x3000).unwrap();
+        assert_eq!(merged.as_slice(), &[0x1000..0x4000, 0x5000..0x6000]);
+
+        // overlapping multiple regions
+        let mut merged = set.clone();
+        merged.insert(0x1800..0x5800).unwrap();
+        assert_eq!(merged.as_slice(), &[0x1000..0x6000]);
+        assert_eq!(merged.total_size(), 0x5000);
+
+        // empty ranges are ignored
+        let mut merged = set.clone();
+        merged.insert(0x8000..0x8000).unwrap();
+        assert_eq!(merged.len(), 3);
+        assert!(merged.contains(0x1fff));
+        assert!(!merged.contains(0x2000));
+    }
+
+    #[test]
+    fn test_insert_full() {
+        let mut set = new_set::<2>(&[0x1000..0x2000, 0x3000..0x4000]);
+        assert_eq!(set.insert(0x5000..0x6000), Err(MemoryRegionSetError::Full));
+        // merging needs no additional space
+        set.insert(0x3800..0x5000).unwrap();
+        assert_eq!(set.as_slice(), &[0x1000..0x2000, 0x3000..0x5000]);
+    }
+
+    #[test]
+    fn test_subtract() {
+        let mut set = new_set::<4>(&[0x1000..0x4000, 0x5000..0x8000]);
+        // split
+        set.subtract(0x2000..0x3000).unwrap();
+        assert_eq!(
+            set.as_slice(),
+            &[0x1000..0x2000, 0x3000..0x4000, 0x5000..0x8000]
+        );
+        // shrink two regions and remove one
+        set.subtract(0x1800..0x5800).unwrap();
+        assert_eq!(set.as_slice(), &[0x1000..0x1800, 0x5800..0x8000]);
+        // no overlap
+        set.subtract(0x9000..0xa000).unwrap();
+        assert_eq!(set.len(), 2);
+        set.subtract(0..u64::MAX).unwrap();
+        assert!(set.is_empty());
+
+        let mut full = new_set::<1>(&[0x1000..0x4000]);
+        assert_eq!(
+            full.subtract(0x2000..0x3000),
+            Err(MemoryRegionSetError::Full)
+        );
+        assert_eq!(full.as_slice(), &[0x1000..0x4000]);
+    }
+
+    #[test]
+    fn test_split_aligned() {
+        assert_eq!(
+            split_aligned(0x1800..0x5800, 0x1000),
+            [0x1800..0x2000, 0x2000..0x5000, 0x5000..0x5800]
+        );
+        assert_eq!(
+            split_aligned(0x1000..0x5000, 0x1000),
+            [0x1000..0x1000, 0x1000..0x5000, 0x5000..0x5000]
+        );
+        // no aligned part
+        assert_eq!(
+            split_aligned(0x1200..0x1800, 0x1000),
+            [0x1200..0x1800, 0x1800..0x1800, 0x1800..0x1800]
+        );
+
+        let set = new_set::<4>(&[0x1800..0x5800, 0x6200..0x6800]);
+        assert_eq!(set.aligned(0x1000).as_slice(), &[0x2000..0x5000]);
+    }
+}
@@ -0,0 +1,45 @@
+//! Module for stack painting. A stack is filled with [`STACK_PAINT`] before it is used.
+//! Because stacks grow downwards, the lowest word that no longer holds the pattern marks
+//! the deepest point the stack ever reached, i.e. its high-water mark.
+
+/// Pattern for unused stack memory. It is unlikely to be a valid pointer, length or
+/// small integer.
+pub const STACK_PAINT: u64 = 0x5354_4b50_5354_4b50;
+
+/// Fills the stack with [`STACK_PAINT`]. The stack must not be in use.
+pub fn paint(stack: &mut [u64]) {
+    stack.fill(STACK_PAINT);
+}
+
+/// Returns the number of bytes that were used at most, measured from the top (the end of
+/// the slice). Values on the stack that equal [`STACK_PAINT`] by accident make the
+/// result slightly too small.
+pub fn high_water_mark(stack: &[u64]) -> usize {
+    let unused_words = stack
+        .iter()
+        .position(|word| *word != STACK_PAINT)
+        .unwrap_or(stack.len());
+    (stack.len() - unused_words) * core::mem::size_of::<u64>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_high_water_mark() {
+        let mut stack = [0_u64; 64];
+        assert_eq!(high_water_mark(&stack), 64 * 8);
+
+        paint(&mut stack);
+        assert_eq!(high_water_mark(&stack), 0);
+
+        // the stack grows downwards
+        stack[60] = 0;
+        assert_eq!(high_water_mark(&stack), 4 * 8);
+        // a word in the middle of a frame may still hold the pattern
+        stack[50] = 0;
+        stack[52] = STACK_PAINT;
+        assert_eq!(high_water_mark(&stack), 14 * 8);
+    }
+}
@@ -1,67 +1,255 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 
 const UNLOCKED: bool = false;
 const LOCKED: bool = true;
 
+/// Whether the `lock-debug` feature is active. In this mode, each [`Mutex`] records the
+/// caller location and the CPU of its owner. Locking it again on the same CPU panics
+/// with both locations instead of spinning forever. See [`set_cpu_id_fn`].
+pub const LOCK_DEBUG: bool = cfg!(feature = "lock-debug");
+
+/// Marks a [`LockOwner`] without owner.
+const NO_CPU: usize = usize::MAX;
+
+/// See [`set_cpu_id_fn`].
+static CPU_ID_FN: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Sets the function that returns the id of the current CPU for [`LOCK_DEBUG`]. Until
+/// then, all code is assumed to run on the same CPU.
+pub fn set_cpu_id_fn(cpu_id: fn() -> usize) {
+    CPU_ID_FN.store(cpu_id as *mut (), Ordering::Relaxed);
+}
+
+pub(crate) fn current_cpu_id() -> usize {
+    let cpu_id = CPU_ID_FN.load(Ordering::Relaxed);
+    if cpu_id.is_null() {
+        default_cpu_id()
+    } else {
+        let cpu_id = unsafe { core::mem::transmute::<*mut (), fn() -> usize>(cpu_id) };
+        cpu_id()
+    }
+}
+
+#[cfg(not(test))]
+fn default_cpu_id() -> usize {
+    0
+}
+
+/// Host threads take the role of the CPUs in the tests.
+#[cfg(test)]
+fn default_cpu_id() -> usize {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    std::thread_local! {
+        static ID: usize = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    }
+    ID.with(|id| *id)
+}
+
+/// The locking strategy of a [`Mutex`], without the data. This separates how a lock is
+/// acquired, e.g. fair or with interrupts disabled, from the guard logic.
+///
+/// # Safety
+/// Between a successful [`Self::lock`] or [`Self::try_lock`] and the next
+/// [`Self::unlock`], no other caller may acquire the lock.
+pub unsafe trait RawLock {
+    /// The unlocked state. A constant, so that [`Mutex::new`] can be a `const fn`.
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self;
+
+    /// Spins until the lock is acquired.
+    fn lock(&self);
+
+    /// Acquires the lock, if it is free. Returns whether it was acquired.
+    fn try_lock(&self) -> bool;
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    /// The caller must hold the lock.
+    unsafe fn unlock(&self);
+
+    /// Whether the lock is held. Only a snapshot.
+    fn is_locked(&self) -> bool;
+}
+
+/// Simple spin lock: a bare compare-exchange loop without any fairness.
+#[derive(Debug)]
+pub struct RawSpinLock {
+    lock: AtomicBool,
+}
+
+unsafe impl RawLock for RawSpinLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self = Self {
+        lock: AtomicBool::new(UNLOCKED),
+    };
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            // only read until the lock looks free, to keep the cache line shared
+            while self.is_locked() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.lock
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.lock.store(UNLOCKED, Ordering::SeqCst);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.lock.load(Ordering::Relaxed) == LOCKED
+    }
+}
+
 /// A simple mutex. The core library doesn't have this, therefore I have to build
 /// it by myself.
+pub type SimpleMutex<T> = Mutex<RawSpinLock, T>;
+
+/// Guard of [`SimpleMutex`].
+pub type SimpleMutexGuard<'a, T> = MutexGuard<'a, RawSpinLock, T>;
+
+/// A mutex whose locking strategy is defined by a [`RawLock`]. See [`SimpleMutex`],
+/// [`crate::ticket_mutex::TicketMutex`] and [`crate::irq_mutex::IrqSafeMutex`].
 #[derive(Debug)]
-pub struct SimpleMutex<T> {
+pub struct Mutex<R, T> {
     data: UnsafeCell<T>,
-    lock: AtomicBool,
+    raw: R,
+    /// Only maintained with [`LOCK_DEBUG`].
+    owner: LockOwner,
 }
 
-unsafe impl<T> Send for SimpleMutex<T> {}
-unsafe impl<T> Sync for SimpleMutex<T> {}
+/// Owner of a [`Mutex`] in [`LOCK_DEBUG`] mode.
+#[derive(Debug)]
+struct LockOwner {
+    cpu: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl LockOwner {
+    const fn new() -> Self {
+        Self {
+            cpu: AtomicUsize::new(NO_CPU),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn set(&self, location: &'static Location<'static>) {
+        self.location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        self.cpu.store(current_cpu_id(), Ordering::Relaxed);
+    }
 
-impl<T> SimpleMutex<T> {
+    fn clear(&self) {
+        self.cpu.store(NO_CPU, Ordering::Relaxed);
+        self.location.store(ptr::null_mut(), Ordering::Relaxed);
+    }
+
+    /// Panics, if the current CPU holds the lock already.
+    fn check_reentrant(&self, location: &Location) {
+        if self.cpu.load(Ordering::Relaxed) != current_cpu_id() {
+            return;
+        }
+        let owner = self.location.load(Ordering::Relaxed);
+        match unsafe { owner.as_ref() } {
+            Some(owner) => panic!(
+                "re-entrant lock at {}, the lock is already held since {}",
+                location, owner
+            ),
+            None => panic!("re-entrant lock at {}", location),
+        }
+    }
+}
+
+unsafe impl<R, T> Send for Mutex<R, T> {}
+unsafe impl<R, T> Sync for Mutex<R, T> {}
+
+impl<R: RawLock, T> Mutex<R, T> {
     pub const fn new(data: T) -> Self {
         Self {
             data: UnsafeCell::new(data),
-            lock: AtomicBool::new(UNLOCKED),
+            raw: R::UNLOCKED,
+            owner: LockOwner::new(),
         }
     }
 
     pub fn into_inner(self) -> T {
-        if self.lock.load(Ordering::SeqCst) == LOCKED {
+        if self.raw.is_locked() {
             panic!("Still in use!");
         }
         self.data.into_inner()
     }
 
-    pub fn lock(&self) -> SimpleMutexGuard<T> {
-        loop {
-            let lock_obtained =
-                self.lock
-                    .compare_exchange(UNLOCKED, LOCKED, Ordering::SeqCst, Ordering::SeqCst);
-            if lock_obtained.is_ok() {
-                break;
+    /// Spins until the lock is acquired. With [`LOCK_DEBUG`], panics if the current CPU
+    /// already holds the lock.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<R, T> {
+        if LOCK_DEBUG {
+            self.owner.check_reentrant(Location::caller());
+        }
+        self.raw.lock();
+        self.guard(Location::caller())
+    }
+
+    /// Like [`Self::lock`] but returns `None` instead of spinning, if the lock is
+    /// already held. Useful in contexts where the lock holder may never continue,
+    /// e.g. in a panic handler.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<R, T>> {
+        let location = Location::caller();
+        self.raw.try_lock().then(|| self.guard(location))
+    }
+
+    /// Like [`Self::lock`] but gives up after `spins` failed attempts. Also gives up
+    /// instead of panicking, if the current CPU holds the lock already. The attempts
+    /// don't queue up, hence a [`crate::ticket_mutex::TicketMutex`] isn't fair here.
+    #[track_caller]
+    pub fn lock_with_timeout(&self, spins: usize) -> Option<MutexGuard<R, T>> {
+        for _ in 0..spins {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
             }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    fn guard(&self, location: &'static Location<'static>) -> MutexGuard<R, T> {
+        if LOCK_DEBUG {
+            self.owner.set(location);
         }
-        SimpleMutexGuard { lock: &self }
+        MutexGuard { lock: self }
     }
 }
 
-impl<T: Default> Default for SimpleMutex<T> {
+impl<R: RawLock, T: Default> Default for Mutex<R, T> {
     fn default() -> Self {
-        SimpleMutex::new(T::default())
+        Mutex::new(T::default())
     }
 }
 
 #[derive(Debug)]
-pub struct SimpleMutexGuard<'a, T> {
-    lock: &'a SimpleMutex<T>,
+pub struct MutexGuard<'a, R: RawLock, T> {
+    lock: &'a Mutex<R, T>,
 }
 
-impl<'a, T> SimpleMutexGuard<'a, T> {
+impl<'a, R: RawLock, T> MutexGuard<'a, R, T> {
     /// This method is convenient, when you want to execute code while the lock is held
     /// and the lock doesn't hold the data. This is useful for advisory locks, like
     /// [`SimpleMutex<())`].
-    pub fn execute_while_locked<U, R>(&self, actions: U) -> R
+    pub fn execute_while_locked<U, Ret>(&self, actions: U) -> Ret
     where
-        U: FnOnce() -> R,
+        U: FnOnce() -> Ret,
     {
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
         let res = actions();
@@ -70,7 +258,7 @@ impl<'a, T> SimpleMutexGuard<'a, T> {
     }
 }
 
-impl<T> Deref for SimpleMutexGuard<'_, T> {
+impl<R: RawLock, T> Deref for MutexGuard<'_, R, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -78,16 +266,19 @@ impl<T> Deref for SimpleMutexGuard<'_, T> {
     }
 }
 
-impl<T> DerefMut for SimpleMutexGuard<'_, T> {
+impl<R: RawLock, T> DerefMut for MutexGuard<'_, R, T> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
 }
 
-impl<T> Drop for SimpleMutexGuard<'_, T> {
+impl<R: RawLock, T> Drop for MutexGuard<'_, R, T> {
     #[inline]
     fn drop(&mut self) {
-        self.lock.lock.store(UNLOCKED, Ordering::SeqCst);
+        if LOCK_DEBUG {
+            self.lock.owner.clear();
+        }
+        unsafe { self.lock.raw.unlock() };
     }
 }
 
@@ -111,4 +302,48 @@ mod tests {
         assert_eq!(1_000_000, *std_mutex.lock().unwrap());
         assert_eq!(1_000_000, *my_mutex.lock());
     }
+
+    #[test]
+    fn test_try_lock() {
+        let mutex = SimpleMutex::new(0);
+        let lock = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        drop(lock);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_lock_with_timeout() {
+        let mutex = SimpleMutex::new(0);
+        let lock = mutex.lock();
+        assert!(mutex.lock_with_timeout(100).is_none());
+        drop(lock);
+        *mutex.lock_with_timeout(100).unwrap() += 1;
+        assert_eq!(mutex.into_inner(), 1);
+    }
+
+    #[cfg(feature = "lock-debug")]
+    #[test]
+    #[should_panic(expected = "re-entrant lock at src/mutex.rs")]
+    fn test_reentrant_lock() {
+        let mutex = SimpleMutex::new(0);
+        let _lock = mutex.lock();
+        let _lock = mutex.lock();
+    }
+
+    #[cfg(feature = "lock-debug")]
+    #[test]
+    fn test_owner() {
+        let mutex = SimpleMutex::new(0);
+        let line = line!() + 1;
+        let lock = mutex.try_lock().unwrap();
+        let owner = unsafe { mutex.owner.location.load(Ordering::SeqCst).as_ref() };
+        assert_eq!(owner.unwrap().line(), line);
+        // other CPUs just wait
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(mutex.lock_with_timeout(10).is_none()));
+        });
+        drop(lock);
+        assert!(mutex.owner.location.load(Ordering::SeqCst).is_null());
+    }
 }
@@ -0,0 +1,268 @@
+//! Module for one-time initialization: [`Once`], [`OnceCell`] and [`Lazy`]. All of them
+//! can be used in `static`s. While one core runs the initialization, other cores spin
+//! until it is done, hence they never observe a partially initialized value.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Debug, Formatter};
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+/// The initialization panicked. Only reachable, if panics unwind, e.g. in tests.
+const POISONED: u8 = 3;
+
+/// Runs a closure exactly once, even if multiple cores call [`Self::call_once`] at the
+/// same time.
+#[derive(Debug)]
+pub struct Once {
+    state: AtomicU8,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+        }
+    }
+
+    /// Runs `f`, if no closure ran before. If another core runs its closure right now,
+    /// spins until it is done. Returns whether `f` was executed.
+    pub fn call_once(&self, f: impl FnOnce()) -> bool {
+        loop {
+            match self.state.compare_exchange_weak(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let poison = PoisonOnUnwind(&self.state);
+                    f();
+                    core::mem::forget(poison);
+                    self.state.store(COMPLETE, Ordering::Release);
+                    return true;
+                }
+                Err(RUNNING) => core::hint::spin_loop(),
+                Err(COMPLETE) => return false,
+                Err(POISONED) => panic!("Once poisoned: the initialization panicked"),
+                // spurious failure
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Whether a closure ran to completion.
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marks the [`Once`] as poisoned, if the initialization unwinds. Otherwise, waiting
+/// cores would spin forever.
+struct PoisonOnUnwind<'a>(&'a AtomicU8);
+
+impl Drop for PoisonOnUnwind<'_> {
+    fn drop(&mut self) {
+        self.0.store(POISONED, Ordering::Release);
+    }
+}
+
+/// Cell that is written at most once. Afterwards, shared references to the value can
+/// be handed out without any locking.
+pub struct OnceCell<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, if the cell was initialized.
+    pub fn get(&self) -> Option<&T> {
+        self.once
+            .is_completed()
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Initializes the cell with `value`. Returns the value as error, if the cell was
+    /// already initialized.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.once.call_once(|| self.write(value.take().unwrap()));
+        value.map_or(Ok(()), Err)
+    }
+
+    /// Returns the value. Initializes the cell with `f` first, if it is empty.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        self.once.call_once(|| self.write(f()));
+        // can't fail: either this or another core completed the initialization
+        self.get().unwrap()
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+
+    /// Removes the value from the cell. Needs exclusive access, hence no other core can
+    /// read the value at the same time.
+    pub fn take(&mut self) -> Option<T> {
+        if !self.once.is_completed() {
+            return None;
+        }
+        self.once = Once::new();
+        Some(unsafe { self.value.get_mut().assume_init_read() })
+    }
+
+    fn write(&self, value: T) {
+        unsafe { (*self.value.get()).write(value) };
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Debug> Debug for OnceCell<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnceCell").field(&self.get()).finish()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
+
+/// Value that is initialized on first access with the function of the constructor.
+/// Useful for `static`s whose value can't be built in a `const` context.
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            cell: OnceCell::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Forces the initialization and returns the value.
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| {
+            // only executed once, hence no one else accesses `init`
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy: init function already consumed")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T: Debug, F> Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Lazy").field(&self.cell).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_once() {
+        let once = Once::new();
+        let mut count = 0;
+        assert!(once.call_once(|| count += 1));
+        assert!(!once.call_once(|| count += 1));
+        assert!(once.is_completed());
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_once_cell() {
+        let cell = OnceCell::new();
+        assert!(cell.get().is_none());
+        assert_eq!(cell.set(String::from("a")), Ok(()));
+        assert_eq!(cell.set(String::from("b")), Err(String::from("b")));
+        assert_eq!(cell.get_or_init(|| String::from("c")), "a");
+        assert_eq!(cell.into_inner(), Some(String::from("a")));
+    }
+
+    /// All threads must see the value of the single initialization, even if they
+    /// arrive while it is still running.
+    #[test]
+    fn test_concurrent_init() {
+        static CELL: OnceCell<usize> = OnceCell::new();
+        static INITS: AtomicUsize = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || {
+                    let value = CELL.get_or_init(|| {
+                        INITS.fetch_add(1, Ordering::SeqCst);
+                        thread::yield_now();
+                        i
+                    });
+                    assert_eq!(Some(value), CELL.get());
+                });
+            }
+        });
+
+        assert_eq!(INITS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_lazy() {
+        static INITS: AtomicUsize = AtomicUsize::new(0);
+        static LAZY: Lazy<Vec<u8>> = Lazy::new(|| {
+            INITS.fetch_add(1, Ordering::SeqCst);
+            vec![1, 2, 3]
+        });
+
+        assert_eq!(INITS.load(Ordering::SeqCst), 0);
+        assert_eq!(LAZY.len(), 3);
+        assert_eq!(*LAZY, [1, 2, 3]);
+        assert_eq!(INITS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_poisoned() {
+        let once = Once::new();
+        let res = std::panic::catch_unwind(|| once.call_once(|| panic!("init failed")));
+        assert!(res.is_err());
+        let res = std::panic::catch_unwind(|| once.call_once(|| {}));
+        assert!(res.is_err());
+    }
+}
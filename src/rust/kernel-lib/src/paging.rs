@@ -0,0 +1,466 @@
+//! Module for x86_64 4-level paging. See [`PageTableBuilder`].
+
+use core::fmt::{Debug, Formatter};
+use core::ops::{BitOr, BitOrAssign, Range};
+
+/// Number of entries in a page table of any level.
+pub const ENTRY_COUNT: usize = 512;
+
+/// Bits of an entry that hold the physical address of a frame or of the next table.
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Possible errors for [`PageTableBuilder`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PagingError {
+    /// The frame source couldn't provide a frame for a page table.
+    OutOfFrames,
+    /// An address is not aligned to the page size.
+    Unaligned,
+    /// The virtual address is not canonical.
+    NonCanonical,
+    /// The page, or a part of it, is already mapped.
+    AlreadyMapped,
+}
+
+/// Supported page sizes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    /// Mapped by a PT entry.
+    Size4KiB,
+    /// Mapped by a PD entry.
+    Size2MiB,
+    /// Mapped by a PDPT entry. Needs CPU support, see `CPUID.80000001H:EDX.Page1GB`.
+    Size1GiB,
+}
+
+impl PageSize {
+    /// Returns the size in bytes.
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 0x1000,
+            PageSize::Size2MiB => 0x20_0000,
+            PageSize::Size1GiB => 0x4000_0000,
+        }
+    }
+
+    /// Returns the level of the page table that holds the entry for this page size.
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 1,
+            PageSize::Size2MiB => 2,
+            PageSize::Size1GiB => 3,
+        }
+    }
+}
+
+/// Flags of a page table entry.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PageTableFlags(u64);
+
+impl PageTableFlags {
+    pub const PRESENT: Self = Self(1 << 0);
+    pub const WRITABLE: Self = Self(1 << 1);
+    pub const USER: Self = Self(1 << 2);
+    pub const WRITE_THROUGH: Self = Self(1 << 3);
+    pub const NO_CACHE: Self = Self(1 << 4);
+    pub const ACCESSED: Self = Self(1 << 5);
+    pub const DIRTY: Self = Self(1 << 6);
+    /// Marks a 2 MiB or 1 GiB page in a PD or PDPT entry.
+    pub const HUGE_PAGE: Self = Self(1 << 7);
+    pub const GLOBAL: Self = Self(1 << 8);
+    /// Only effective, if `EFER.NXE` is set.
+    pub const NO_EXECUTE: Self = Self(1 << 63);
+
+    /// Returns flags without any bit set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the raw bits.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the flags of a raw page table entry.
+    pub const fn from_entry(entry: u64) -> Self {
+        Self(entry & !ADDR_MASK)
+    }
+
+    /// Whether all flags of `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PageTableFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PageTableFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0
+    }
+}
+
+impl Debug for PageTableFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PageTableFlags({:#x})", self.0)
+    }
+}
+
+/// A page table of any level. Each entry is the physical address of a frame or of
+/// the next table, combined with [`PageTableFlags`].
+#[repr(C, align(4096))]
+#[derive(Debug, Clone)]
+pub struct PageTable {
+    entries: [u64; ENTRY_COUNT],
+}
+
+impl PageTable {
+    /// Creates a table without any present entry.
+    pub const fn new() -> Self {
+        Self {
+            entries: [0; ENTRY_COUNT],
+        }
+    }
+
+    /// Returns the raw entries.
+    pub fn entries(&self) -> &[u64; ENTRY_COUNT] {
+        &self.entries
+    }
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Provides the frames for new page tables to the [`PageTableBuilder`].
+pub trait FrameSource {
+    /// Returns the physical address of a free 4 KiB frame or `None`, if there is no
+    /// memory left.
+    fn allocate_frame(&mut self) -> Option<u64>;
+
+    /// Returns a pointer, through which the page table in the frame at `phys` can be
+    /// accessed. The default implementation assumes an identity mapping.
+    fn table_ptr(&self, phys: u64) -> *mut PageTable {
+        phys as *mut PageTable
+    }
+}
+
+/// Builds a fresh 4-level page table hierarchy. Page tables are created on demand with
+/// frames from the [`FrameSource`]. The hierarchy is only written, never activated. The
+/// caller loads [`Self::pml4_addr`] into `CR3`.
+///
+/// Intermediate tables are writable and executable. The restrictions of a mapping are
+/// expressed by the flags of the last entry.
+#[derive(Debug)]
+pub struct PageTableBuilder<'a, F: FrameSource> {
+    frames: &'a mut F,
+    pml4: u64,
+    max_page_size: PageSize,
+}
+
+impl<'a, F: FrameSource> PageTableBuilder<'a, F> {
+    /// Creates a new builder with an empty PML4. Pages of all sizes are used.
+    pub fn new(frames: &'a mut F) -> Result<Self, PagingError> {
+        let pml4 = Self::new_table(frames)?;
+        Ok(Self {
+            frames,
+            pml4,
+            max_page_size: PageSize::Size1GiB,
+        })
+    }
+
+    /// Limits the page sizes that [`Self::map_range`] uses, e.g. because the CPU doesn't
+    /// support 1 GiB pages.
+    pub fn with_max_page_size(mut self, max_page_size: PageSize) -> Self {
+        self.max_page_size = max_page_size;
+        self
+    }
+
+    /// Physical address of the PML4.
+    pub fn pml4_addr(&self) -> u64 {
+        self.pml4
+    }
+
+    /// Maps a single page of the given size. Both addresses must be aligned to the size.
+    pub fn map(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        size: PageSize,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        if virt % size.bytes() != 0 || phys % size.bytes() != 0 {
+            return Err(PagingError::Unaligned);
+        }
+        if !is_canonical(virt) {
+            return Err(PagingError::NonCanonical);
+        }
+
+        let mut table = self.pml4;
+        for level in (size.level() + 1..=4).rev() {
+            table = self.next_table(table, table_index(virt, level), flags)?;
+        }
+
+        let entry = self.entry_mut(table, table_index(virt, size.level()));
+        if *entry & PageTableFlags::PRESENT.bits() != 0 {
+            return Err(PagingError::AlreadyMapped);
+        }
+        let mut flags = flags | PageTableFlags::PRESENT;
+        if size != PageSize::Size4KiB {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+        *entry = phys | flags.bits();
+        Ok(())
+    }
+
+    /// Maps `size` bytes at `virt` to `phys`. The biggest possible page size is used for
+    /// each part. All values must be aligned to 4 KiB.
+    pub fn map_range(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let mut offset = 0;
+        while offset < size {
+            let page_size = [PageSize::Size1GiB, PageSize::Size2MiB, PageSize::Size4KiB]
+                .into_iter()
+                .filter(|page_size| *page_size <= self.max_page_size)
+                .find(|page_size| {
+                    let bytes = page_size.bytes();
+                    (virt + offset) % bytes == 0
+                        && (phys + offset) % bytes == 0
+                        && size - offset >= bytes
+                })
+                .ok_or(PagingError::Unaligned)?;
+            self.map(virt + offset, phys + offset, page_size, flags)?;
+            offset += page_size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Identity-maps the physical address range. See [`Self::map_range`].
+    pub fn identity_map(
+        &mut self,
+        range: Range<u64>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        self.map_range(range.start, range.start, range.end - range.start, flags)
+    }
+
+    /// Returns the physical address, the page size and the flags of the mapping for the
+    /// virtual address.
+    pub fn translate(&self, virt: u64) -> Option<(u64, PageSize, PageTableFlags)> {
+        let mut table = self.pml4;
+        for level in (1..=4).rev() {
+            let entry =
+                unsafe { (*self.frames.table_ptr(table)).entries[table_index(virt, level)] };
+            let flags = PageTableFlags::from_entry(entry);
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            let size = match level {
+                1 => Some(PageSize::Size4KiB),
+                2 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(PageSize::Size2MiB),
+                3 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(PageSize::Size1GiB),
+                _ => None,
+            };
+            if let Some(size) = size {
+                let phys = (entry & ADDR_MASK) + virt % size.bytes();
+                return Some((phys, size, flags));
+            }
+            table = entry & ADDR_MASK;
+        }
+        unreachable!()
+    }
+
+    /// Returns the next table that the entry points to. It is created, if it doesn't
+    /// exist.
+    fn next_table(
+        &mut self,
+        table: u64,
+        index: usize,
+        flags: PageTableFlags,
+    ) -> Result<u64, PagingError> {
+        let entry = *self.entry_mut(table, index);
+        let entry_flags = PageTableFlags::from_entry(entry);
+        if entry_flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Err(PagingError::AlreadyMapped);
+        }
+        if entry_flags.contains(PageTableFlags::PRESENT) {
+            return Ok(entry & ADDR_MASK);
+        }
+
+        let next = Self::new_table(self.frames)?;
+        let mut next_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if flags.contains(PageTableFlags::USER) {
+            next_flags |= PageTableFlags::USER;
+        }
+        *self.entry_mut(table, index) = next | next_flags.bits();
+        Ok(next)
+    }
+
+    fn entry_mut(&mut self, table: u64, index: usize) -> &mut u64 {
+        unsafe { &mut (*self.frames.table_ptr(table)).entries[index] }
+    }
+
+    /// Allocates a frame and initializes it as empty table.
+    fn new_table(frames: &mut F) -> Result<u64, PagingError> {
+        let frame = frames.allocate_frame().ok_or(PagingError::OutOfFrames)?;
+        unsafe { frames.table_ptr(frame).write(PageTable::new()) };
+        Ok(frame)
+    }
+}
+
+/// Returns the index into the table of the given level (1 = PT, 4 = PML4).
+fn table_index(virt: u64, level: usize) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+/// Whether bits 48 to 63 are copies of bit 47.
+fn is_canonical(virt: u64) -> bool {
+    let upper = virt >> 47;
+    upper == 0 || upper == 0x1_ffff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames are boxed tables on the host heap. Fake physical addresses are the
+    /// index of the table plus one, multiplied by 4096.
+    #[derive(Debug, Default)]
+    struct TestFrames {
+        tables: Vec<Box<PageTable>>,
+        limit: Option<usize>,
+    }
+
+    impl FrameSource for TestFrames {
+        fn allocate_frame(&mut self) -> Option<u64> {
+            if self.limit == Some(self.tables.len()) {
+                return None;
+            }
+            self.tables.push(Box::new(PageTable::new()));
+            Some(self.tables.len() as u64 * 0x1000)
+        }
+
+        fn table_ptr(&self, phys: u64) -> *mut PageTable {
+            let table = &self.tables[phys as usize / 0x1000 - 1];
+            table.as_ref() as *const PageTable as *mut PageTable
+        }
+    }
+
+    const RW: PageTableFlags =
+        PageTableFlags(PageTableFlags::PRESENT.bits() | PageTableFlags::WRITABLE.bits());
+
+    #[test]
+    fn test_map_all_sizes() {
+        let mut frames = TestFrames::default();
+        let mut builder = PageTableBuilder::new(&mut frames).unwrap();
+        builder.map(0x1000, 0x5000, PageSize::Size4KiB, RW).unwrap();
+        builder
+            .map(0x20_0000, 0x60_0000, PageSize::Size2MiB, RW)
+            .unwrap();
+        builder
+            .map(
+                0x4000_0000,
+                0x8000_0000,
+                PageSize::Size1GiB,
+                PageTableFlags::PRESENT,
+            )
+            .unwrap();
+
+        assert_eq!(
+            builder.translate(0x1234),
+            Some((0x5234, PageSize::Size4KiB, RW))
+        );
+        let (phys, size, flags) = builder.translate(0x20_1234).unwrap();
+        assert_eq!((phys, size), (0x60_1234, PageSize::Size2MiB));
+        assert!(flags.contains(PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE));
+        let (phys, size, flags) = builder.translate(0x4000_1234).unwrap();
+        assert_eq!((phys, size), (0x8000_1234, PageSize::Size1GiB));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        assert_eq!(builder.translate(0x3000), None);
+        assert_eq!(builder.translate(0x8000_0000), None);
+
+        // PML4, PDPT, PD, PT
+        assert_eq!(frames.tables.len(), 4);
+    }
+
+    #[test]
+    fn test_map_errors() {
+        let mut frames = TestFrames::default();
+        let mut builder = PageTableBuilder::new(&mut frames).unwrap();
+        assert_eq!(
+            builder.map(0x1000, 0x1000, PageSize::Size2MiB, RW),
+            Err(PagingError::Unaligned)
+        );
+        assert_eq!(
+            builder.map(0x0000_8000_0000_0000, 0, PageSize::Size4KiB, RW),
+            Err(PagingError::NonCanonical)
+        );
+        builder.map(0, 0, PageSize::Size2MiB, RW).unwrap();
+        assert_eq!(
+            builder.map(0x1000, 0x1000, PageSize::Size4KiB, RW),
+            Err(PagingError::AlreadyMapped)
+        );
+        assert_eq!(
+            builder.map(0, 0, PageSize::Size2MiB, RW),
+            Err(PagingError::AlreadyMapped)
+        );
+        // higher half is canonical
+        builder
+            .map(0xffff_8000_0000_0000, 0, PageSize::Size4KiB, RW)
+            .unwrap();
+
+        let mut frames = TestFrames {
+            limit: Some(2),
+            ..TestFrames::default()
+        };
+        let mut builder = PageTableBuilder::new(&mut frames).unwrap();
+        assert_eq!(
+            builder.map(0, 0, PageSize::Size4KiB, RW),
+            Err(PagingError::OutOfFrames)
+        );
+    }
+
+    #[test]
+    fn test_map_range() {
+        let mut frames = TestFrames::default();
+        let mut builder = PageTableBuilder::new(&mut frames).unwrap();
+        // 4 KiB pages up to 2 MiB, then 2 MiB pages up to 1 GiB, one 1 GiB page, one
+        // 4 KiB page
+        builder.identity_map(0x1f_f000..0x8000_1000, RW).unwrap();
+        let size_of = |virt| builder.translate(virt).map(|(_, size, _)| size);
+        assert_eq!(size_of(0x1f_f000), Some(PageSize::Size4KiB));
+        assert_eq!(size_of(0x20_0000), Some(PageSize::Size2MiB));
+        assert_eq!(size_of(0x3fe0_0000), Some(PageSize::Size2MiB));
+        assert_eq!(size_of(0x4000_0000), Some(PageSize::Size1GiB));
+        assert_eq!(size_of(0x8000_0000), Some(PageSize::Size4KiB));
+        assert_eq!(size_of(0x8000_1000), None);
+        assert_eq!(size_of(0x1f_e000), None);
+
+        let mut frames = TestFrames::default();
+        let mut builder = PageTableBuilder::new(&mut frames)
+            .unwrap()
+            .with_max_page_size(PageSize::Size2MiB);
+        builder.identity_map(0..0x4000_0000, RW).unwrap();
+        assert_eq!(
+            builder.translate(0x3000_0000).map(|(_, size, _)| size),
+            Some(PageSize::Size2MiB)
+        );
+        assert_eq!(
+            builder.map_range(0x4000_0000, 0x4000_0800, 0x1000, RW),
+            Err(PagingError::Unaligned)
+        );
+    }
+}
@@ -0,0 +1,416 @@
+//! Module for bounded, lock-free ring buffers. They hand data from interrupt context to
+//! normal context, e.g. received bytes of the serial port or deferred log records. None
+//! of them allocates and all of them can be used in `static`s.
+//!
+//! - [`SpscRing`]: one producer and one consumer.
+//! - [`MpscRing`]: any number of producers and one consumer.
+//!
+//! Pushing never waits. If the ring is full, the value is returned to the caller. This
+//! is important in interrupt handlers: an interrupted push on the same core never
+//! completes while the handler runs.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Debug, Formatter};
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Ring buffer with capacity `N` for a single producer and a single consumer. The two
+/// sides are handed out once with [`Self::producer`] and [`Self::consumer`], hence there
+/// can't be a second producer or consumer by accident.
+///
+/// `N` must be a power of two.
+pub struct SpscRing<T, const N: usize> {
+    buf: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Index of the next read. Only written by the consumer.
+    head: AtomicUsize,
+    /// Index of the next write. Only written by the producer.
+    tail: AtomicUsize,
+    producer_taken: AtomicBool,
+    consumer_taken: AtomicBool,
+}
+
+unsafe impl<T: Send, const N: usize> Send for SpscRing<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for SpscRing<T, N> {}
+
+impl<T, const N: usize> SpscRing<T, N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_SLOT: UnsafeCell<MaybeUninit<T>> = UnsafeCell::new(MaybeUninit::uninit());
+
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "capacity must be a power of two");
+        Self {
+            buf: [Self::EMPTY_SLOT; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producer_taken: AtomicBool::new(false),
+            consumer_taken: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the producer side. Only the first call gets it.
+    pub fn producer(&self) -> Option<SpscProducer<T, N>> {
+        let taken = self.producer_taken.swap(true, Ordering::Relaxed);
+        (!taken).then_some(SpscProducer {
+            ring: self,
+            _not_sync: PhantomData,
+        })
+    }
+
+    /// Returns the consumer side. Only the first call gets it.
+    pub fn consumer(&self) -> Option<SpscConsumer<T, N>> {
+        let taken = self.consumer_taken.swap(true, Ordering::Relaxed);
+        (!taken).then_some(SpscConsumer {
+            ring: self,
+            _not_sync: PhantomData,
+        })
+    }
+
+    /// Number of values in the ring. Only a snapshot.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        self.tail.load(Ordering::Relaxed).wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Must only be called by the producer.
+    fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        unsafe { (*self.buf[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Must only be called by the consumer.
+    fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.buf[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for SpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Debug for SpscRing<T, N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpscRing")
+            .field("len", &self.len())
+            .field("capacity", &N)
+            .finish()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscRing<T, N> {
+    fn drop(&mut self) {
+        // exclusive access: nobody else pushes or pops
+        while self.pop().is_some() {}
+    }
+}
+
+/// Producer side of a [`SpscRing`]. Can be moved to another core, but not shared.
+#[derive(Debug)]
+pub struct SpscProducer<'a, T, const N: usize> {
+    ring: &'a SpscRing<T, N>,
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for SpscProducer<'_, T, N> {}
+
+impl<T, const N: usize> SpscProducer<'_, T, N> {
+    /// Appends `value`. Returns it as error, if the ring is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        self.ring.push(value)
+    }
+}
+
+/// Consumer side of a [`SpscRing`]. Can be moved to another core, but not shared.
+#[derive(Debug)]
+pub struct SpscConsumer<'a, T, const N: usize> {
+    ring: &'a SpscRing<T, N>,
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for SpscConsumer<'_, T, N> {}
+
+impl<T, const N: usize> SpscConsumer<'_, T, N> {
+    /// Removes the oldest value.
+    pub fn pop(&self) -> Option<T> {
+        self.ring.pop()
+    }
+}
+
+/// Slot of a [`MpscRing`].
+struct Slot<T> {
+    /// `2 * lap`, if the slot is free for the push of that lap, and `2 * lap + 1`, if it
+    /// holds the value of that lap. The lap of a position is `position / N`. Thanks to
+    /// this encoding, all slots start at `0` and the ring can be built in a `const fn`.
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Ring buffer with capacity `N` for any number of producers, e.g. several interrupt
+/// handlers and normal code, and a single consumer. [`Self::pop`] is also safe to call
+/// from several cores, but values are only ordered per producer.
+///
+/// If a producer is interrupted between claiming a slot and writing it, the consumer
+/// sees the ring as empty from that slot on until the producer continues.
+///
+/// `N` must be a power of two.
+pub struct MpscRing<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// Position of the next pop.
+    head: AtomicUsize,
+    /// Position of the next push.
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Send for MpscRing<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for MpscRing<T, N> {}
+
+impl<T, const N: usize> MpscRing<T, N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_SLOT: Slot<T> = Slot {
+        stamp: AtomicUsize::new(0),
+        value: UnsafeCell::new(MaybeUninit::uninit()),
+    };
+
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "capacity must be a power of two");
+        Self {
+            slots: [Self::EMPTY_SLOT; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends `value`. Returns it as error, if the ring is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let free = 2 * (pos / N);
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            match (stamp.wrapping_sub(free) as isize).signum() {
+                0 => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.stamp.store(free + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // the slot still holds the value of the previous lap
+                -1 => return Err(value),
+                // another producer pushed to this position already
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Removes the oldest value.
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let full = 2 * (pos / N) + 1;
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            match (stamp.wrapping_sub(full) as isize).signum() {
+                0 => match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        // free for the next lap
+                        slot.stamp.store(full + 1, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                },
+                // the slot was not written yet
+                -1 => return None,
+                // another consumer popped this position already
+                _ => pos = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Number of claimed positions. Only a snapshot, it includes pushes in progress.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        self.tail.load(Ordering::Relaxed).wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for MpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Debug for MpscRing<T, N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MpscRing")
+            .field("len", &self.len())
+            .field("capacity", &N)
+            .finish()
+    }
+}
+
+impl<T, const N: usize> Drop for MpscRing<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_spsc() {
+        let ring = SpscRing::<String, 4>::new();
+        let producer = ring.producer().unwrap();
+        let consumer = ring.consumer().unwrap();
+        assert!(ring.producer().is_none());
+        assert!(ring.consumer().is_none());
+
+        assert_eq!(consumer.pop(), None);
+        for i in 0..4 {
+            producer.push(i.to_string()).unwrap();
+        }
+        assert_eq!(
+            producer.push(String::from("full")),
+            Err(String::from("full"))
+        );
+        assert_eq!(ring.len(), 4);
+        assert_eq!(consumer.pop().as_deref(), Some("0"));
+        producer.push(String::from("4")).unwrap();
+        for i in 1..5 {
+            assert_eq!(consumer.pop(), Some(i.to_string()));
+        }
+        assert!(ring.is_empty());
+
+        // the remaining values are dropped with the ring
+        producer.push(String::from("leftover")).unwrap();
+    }
+
+    #[test]
+    fn test_mpsc() {
+        let ring = MpscRing::<String, 4>::new();
+        assert_eq!(ring.pop(), None);
+        for i in 0..4 {
+            ring.push(i.to_string()).unwrap();
+        }
+        assert_eq!(ring.push(String::from("full")), Err(String::from("full")));
+        // wraps around several times
+        for i in 4..20 {
+            assert_eq!(ring.pop(), Some((i - 4).to_string()));
+            ring.push(i.to_string()).unwrap();
+        }
+        assert_eq!(ring.len(), 4);
+        ring.push(String::from("full")).unwrap_err();
+    }
+
+    #[test]
+    fn test_spsc_threads() {
+        const VALUES: usize = 10_000;
+        static RING: SpscRing<usize, 16> = SpscRing::new();
+        let producer = RING.producer().unwrap();
+        let consumer = RING.consumer().unwrap();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..VALUES {
+                    let mut value = i;
+                    while let Err(v) = producer.push(value) {
+                        value = v;
+                        thread::yield_now();
+                    }
+                }
+            });
+            s.spawn(move || {
+                for i in 0..VALUES {
+                    loop {
+                        if let Some(value) = consumer.pop() {
+                            assert_eq!(value, i);
+                            break;
+                        }
+                        thread::yield_now();
+                    }
+                }
+            });
+        });
+
+        assert!(RING.is_empty());
+    }
+
+    /// Each producer pushes increasing values; the consumer must see all of them and
+    /// those of each producer in order.
+    #[test]
+    fn test_mpsc_threads() {
+        const PRODUCERS: usize = 2;
+        const VALUES: usize = 5_000;
+        let ring = MpscRing::<(usize, usize), 16>::new();
+
+        thread::scope(|s| {
+            for producer in 0..PRODUCERS {
+                let ring = &ring;
+                s.spawn(move || {
+                    for i in 0..VALUES {
+                        while ring.push((producer, i)).is_err() {
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            let mut next = [0; PRODUCERS];
+            while next.iter().any(|&n| n < VALUES) {
+                match ring.pop() {
+                    Some((producer, i)) => {
+                        assert_eq!(next[producer], i);
+                        next[producer] += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+        });
+
+        assert!(ring.is_empty());
+    }
+}
@@ -1,83 +1,136 @@
-use crate::mutex::SimpleMutex;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-/// A simple read write lock. Allows either n readers or one writer.
+/// Set while a writer holds the lock.
+const WRITER: usize = 1;
+/// Set while an upgradeable reader holds the lock.
+const UPGRADEABLE: usize = 1 << 1;
+/// Set while a writer (or an upgrading reader) waits. New readers back off, so that
+/// writers are not starved.
+const WRITER_WAITING: usize = 1 << 2;
+/// Readers are counted in the remaining bits.
+const READER: usize = 1 << 3;
+
+/// A simple read write lock. Allows either n readers or one writer. Writers are
+/// preferred: once a writer waits in [`Self::lock_write`], no new readers get the lock.
+///
+/// Additionally, there can be one upgradeable reader at a time, that coexists with
+/// regular readers and can become a writer without releasing the lock in between. See
+/// [`SimpleRwLockUpgradeableGuard::upgrade`].
 #[derive(Debug)]
 pub struct SimpleRwLock<T> {
     data: UnsafeCell<T>,
-    critical_section: SimpleMutex<()>,
-    write_count: AtomicU64,
-    read_count: AtomicU64,
+    state: AtomicUsize,
 }
 
-unsafe impl<T> Send for SimpleRwLock<T> {}
-unsafe impl<T> Sync for SimpleRwLock<T> {}
+unsafe impl<T: Send> Send for SimpleRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for SimpleRwLock<T> {}
 
 impl<T> SimpleRwLock<T> {
     pub const fn new(data: T) -> Self {
         Self {
             data: UnsafeCell::new(data),
-            critical_section: SimpleMutex::new(()),
-            read_count: AtomicU64::new(0),
-            write_count: AtomicU64::new(0),
+            state: AtomicUsize::new(0),
         }
     }
 
-    /*pub fn into_inner(self) -> T {
-        if self.lock.load(Ordering::SeqCst) == LOCKED {
-            panic!("Still in use!");
-        }
+    pub fn into_inner(self) -> T {
         self.data.into_inner()
-    }*/
-
-    pub fn try_lock_read(&self) -> Result<SimpleRwLockReadGuard<T>, ()> {
-        let lock = self.critical_section.lock();
-        lock.execute_while_locked(&|| {
-            if self.can_read() {
-                Ok(SimpleRwLockReadGuard { lock: &self })
-            } else {
-                Err(())
-            }
-        })
     }
 
-    pub fn try_lock_write(&self) -> Result<SimpleRwLockWriteGuard<T>, ()> {
-        let lock = self.critical_section.lock();
-        lock.execute_while_locked(&|| {
-            if self.can_write() {
-                Ok(SimpleRwLockWriteGuard { lock: &self })
-            } else {
-                Err(())
-            }
-        })
+    /// Returns a read guard, if there is neither a writer nor a waiting writer.
+    pub fn try_lock_read(&self) -> Option<SimpleRwLockReadGuard<T>> {
+        self.try_add_reader(WRITER | WRITER_WAITING, READER)
+            .then(|| SimpleRwLockReadGuard { lock: self })
+    }
+
+    /// Returns an upgradeable read guard, if there is neither a writer, a waiting writer
+    /// nor another upgradeable reader. Regular readers don't matter.
+    pub fn try_lock_upgradeable_read(&self) -> Option<SimpleRwLockUpgradeableGuard<T>> {
+        self.try_add_reader(WRITER | WRITER_WAITING | UPGRADEABLE, UPGRADEABLE)
+            .then(|| SimpleRwLockUpgradeableGuard { lock: self })
+    }
+
+    /// Returns a write guard, if the lock is not held at all.
+    pub fn try_lock_write(&self) -> Option<SimpleRwLockWriteGuard<T>> {
+        self.try_set_writer(0)
+            .then(|| SimpleRwLockWriteGuard { lock: self })
     }
 
     pub fn lock_read(&self) -> SimpleRwLockReadGuard<T> {
         loop {
-            if let Ok(l) = self.try_lock_read() {
+            if let Some(l) = self.try_lock_read() {
                 return l;
             }
+            core::hint::spin_loop();
         }
     }
 
+    pub fn lock_upgradeable_read(&self) -> SimpleRwLockUpgradeableGuard<T> {
+        loop {
+            if let Some(l) = self.try_lock_upgradeable_read() {
+                return l;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits until all readers and writers are gone. Meanwhile, new readers are held off.
     pub fn lock_write(&self) -> SimpleRwLockWriteGuard<T> {
         loop {
-            if let Ok(l) = self.try_lock_write() {
+            if let Some(l) = self.try_lock_write() {
                 return l;
             }
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            core::hint::spin_loop();
         }
     }
 
-    /// NOTE THAT THIS IS JUST A SNAPSHOT DURING THE FUNCTION CALL! During the time you call
-    /// "lock_write" already everything can be changed! This is useful for testing.
-    fn can_write(&self) -> bool {
-        self.read_count.load(Ordering::SeqCst) == 0 && self.write_count.load(Ordering::SeqCst) == 0
+    /// Adds `bits` to the state, if none of the `blocking` bits is set.
+    fn try_add_reader(&self, blocking: usize, bits: usize) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & blocking != 0 {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state + bits,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => state = current,
+            }
+        }
     }
 
-    fn can_read(&self) -> bool {
-        self.write_count.load(Ordering::SeqCst) == 0
+    /// Replaces the state with [`WRITER`], if nothing but the `held` bits and
+    /// [`WRITER_WAITING`] is set. This also clears [`WRITER_WAITING`]; other waiting
+    /// writers set it again.
+    fn try_set_writer(&self, held: usize) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & !WRITER_WAITING != held {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                WRITER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => state = current,
+            }
+        }
+    }
+}
+
+impl<T: Default> Default for SimpleRwLock<T> {
+    fn default() -> Self {
+        SimpleRwLock::new(T::default())
     }
 }
 
@@ -86,6 +139,17 @@ pub struct SimpleRwLockWriteGuard<'a, T> {
     lock: &'a SimpleRwLock<T>,
 }
 
+impl<'a, T> SimpleRwLockWriteGuard<'a, T> {
+    /// Turns the writer into a reader, without letting other writers in between.
+    pub fn downgrade(self) -> SimpleRwLockReadGuard<'a, T> {
+        let lock = self.lock;
+        core::mem::forget(self);
+        lock.state.fetch_add(READER, Ordering::Acquire);
+        lock.state.fetch_and(!WRITER, Ordering::Release);
+        SimpleRwLockReadGuard { lock }
+    }
+}
+
 impl<T> Deref for SimpleRwLockWriteGuard<'_, T> {
     type Target = T;
 
@@ -103,7 +167,7 @@ impl<T> DerefMut for SimpleRwLockWriteGuard<'_, T> {
 impl<T> Drop for SimpleRwLockWriteGuard<'_, T> {
     #[inline]
     fn drop(&mut self) {
-        self.lock.write_count.fetch_sub(1, Ordering::SeqCst);
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
     }
 }
 
@@ -123,28 +187,174 @@ impl<T> Deref for SimpleRwLockReadGuard<'_, T> {
 impl<T> Drop for SimpleRwLockReadGuard<'_, T> {
     #[inline]
     fn drop(&mut self) {
-        self.lock.read_count.fetch_sub(1, Ordering::SeqCst);
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+/// Read guard that can be upgraded to a [`SimpleRwLockWriteGuard`]. Only one exists at
+/// a time, hence the upgrade can't deadlock with another upgrade.
+#[derive(Debug)]
+pub struct SimpleRwLockUpgradeableGuard<'a, T> {
+    lock: &'a SimpleRwLock<T>,
+}
+
+impl<'a, T> SimpleRwLockUpgradeableGuard<'a, T> {
+    /// Waits until all regular readers are gone and turns into a writer. Meanwhile, new
+    /// readers are held off.
+    pub fn upgrade(self) -> SimpleRwLockWriteGuard<'a, T> {
+        let mut guard = self;
+        loop {
+            guard = match guard.try_upgrade() {
+                Ok(writer) => return writer,
+                Err(guard) => guard,
+            };
+            guard.lock.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Turns into a writer, if there are no regular readers. Otherwise, returns itself.
+    pub fn try_upgrade(self) -> Result<SimpleRwLockWriteGuard<'a, T>, Self> {
+        if self.lock.try_set_writer(UPGRADEABLE) {
+            let lock = self.lock;
+            core::mem::forget(self);
+            Ok(SimpleRwLockWriteGuard { lock })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Turns into a regular reader, so that another reader can become upgradeable.
+    pub fn downgrade(self) -> SimpleRwLockReadGuard<'a, T> {
+        let lock = self.lock;
+        core::mem::forget(self);
+        lock.state.fetch_add(READER, Ordering::Acquire);
+        lock.state.fetch_and(!UPGRADEABLE, Ordering::Release);
+        SimpleRwLockReadGuard { lock }
+    }
+}
+
+impl<T> Deref for SimpleRwLockUpgradeableGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SimpleRwLockUpgradeableGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.state.fetch_and(!UPGRADEABLE, Ordering::Release);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::thread;
 
     #[test]
-    fn test_simple_mutex() {
-        let std_mutex = std::sync::Mutex::new(0);
-        let my_mutex = SimpleMutex::new(0);
+    fn test_try_lock() {
+        let lock = SimpleRwLock::new(0);
 
-        for _i in 0..1_000_000 {
-            let mut std_lock = std_mutex.lock().unwrap();
-            let mut my_lock = my_mutex.lock();
+        let read1 = lock.try_lock_read().unwrap();
+        let read2 = lock.try_lock_read().unwrap();
+        assert!(lock.try_lock_write().is_none());
+        drop((read1, read2));
 
-            *std_lock = *std_lock + 1;
-            *my_lock = *my_lock + 1;
-        }
+        let write = lock.try_lock_write().unwrap();
+        assert!(lock.try_lock_read().is_none());
+        assert!(lock.try_lock_upgradeable_read().is_none());
+        assert!(lock.try_lock_write().is_none());
+        let read = write.downgrade();
+        assert!(lock.try_lock_write().is_none());
+        assert!(lock.try_lock_read().is_some());
+        drop(read);
+
+        assert_eq!(lock.state.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let lock = SimpleRwLock::new(0);
+
+        let upgradeable = lock.try_lock_upgradeable_read().unwrap();
+        assert!(lock.try_lock_upgradeable_read().is_none());
+        let read = lock.try_lock_read().unwrap();
+        assert!(lock.try_lock_write().is_none());
+
+        // the regular reader is still there
+        let upgradeable = upgradeable.try_upgrade().unwrap_err();
+        drop(read);
+        let mut write = upgradeable.try_upgrade().unwrap();
+        *write += 1;
+        assert!(lock.try_lock_read().is_none());
+        drop(write);
+
+        let read = lock.try_lock_upgradeable_read().unwrap().downgrade();
+        assert!(lock.try_lock_upgradeable_read().is_some());
+        drop(read);
+
+        assert_eq!(lock.state.load(Ordering::SeqCst), 0);
+        assert_eq!(lock.into_inner(), 1);
+    }
+
+    #[test]
+    fn test_writer_preference() {
+        let lock = SimpleRwLock::new(0);
+        let read = lock.lock_read();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.lock_write() += 1);
+            // the waiting writer holds off new readers
+            while lock.state.load(Ordering::SeqCst) & WRITER_WAITING == 0 {
+                core::hint::spin_loop();
+            }
+            assert!(lock.try_lock_read().is_none());
+            assert!(lock.try_lock_upgradeable_read().is_none());
+            drop(read);
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.lock_read(), 1);
+    }
+
+    /// Writers update two values one after another; no reader may ever observe them
+    /// to differ.
+    #[test]
+    fn test_mutual_exclusion() {
+        const THREADS: usize = 2;
+        const ITERATIONS: usize = 500;
+        let lock = SimpleRwLock::new((0_usize, 0_usize));
+
+        thread::scope(|s| {
+            for i in 0..THREADS {
+                let lock = &lock;
+                s.spawn(move || {
+                    for _ in 0..ITERATIONS {
+                        let mut write = if i % 2 == 0 {
+                            lock.lock_write()
+                        } else {
+                            lock.lock_upgradeable_read().upgrade()
+                        };
+                        write.0 += 1;
+                        thread::yield_now();
+                        write.1 += 1;
+                    }
+                });
+                s.spawn(move || {
+                    for _ in 0..ITERATIONS {
+                        let read = lock.lock_read();
+                        assert_eq!(read.0, read.1);
+                    }
+                });
+            }
+        });
 
-        assert_eq!(1_000_000, *std_mutex.lock().unwrap());
-        assert_eq!(1_000_000, *my_mutex.lock());
+        assert_eq!(
+            lock.into_inner(),
+            (THREADS * ITERATIONS, THREADS * ITERATIONS)
+        );
     }
 }
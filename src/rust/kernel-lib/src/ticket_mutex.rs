@@ -0,0 +1,96 @@
+//! Module for the fair [`TicketMutex`].
+
+use crate::mutex::{Mutex, MutexGuard, RawLock};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Mutex that grants the lock in the order of the [`TicketMutex::lock`] calls. Unlike
+/// [`crate::mutex::SimpleMutex`], no core can starve, when many cores compete for the
+/// lock.
+pub type TicketMutex<T> = Mutex<RawTicketLock, T>;
+
+/// Guard of [`TicketMutex`].
+pub type TicketMutexGuard<'a, T> = MutexGuard<'a, RawTicketLock, T>;
+
+/// Ticket lock: each locker draws the next ticket and waits until it is served.
+#[derive(Debug)]
+pub struct RawTicketLock {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+}
+
+unsafe impl RawLock for RawTicketLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self = Self {
+        next_ticket: AtomicUsize::new(0),
+        now_serving: AtomicUsize::new(0),
+    };
+
+    fn lock(&self) {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        // only draw a ticket, if it is served immediately
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        // only the lock holder writes `now_serving`
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        self.now_serving
+            .store(ticket.wrapping_add(1), Ordering::Release);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_try_lock() {
+        let mutex = TicketMutex::new(0);
+        let lock = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        drop(lock);
+        *mutex.try_lock().unwrap() += 1;
+        assert_eq!(mutex.into_inner(), 1);
+    }
+
+    #[test]
+    fn test_mutual_exclusion() {
+        const THREADS: usize = 2;
+        const ITERATIONS: usize = 200;
+        let mutex = TicketMutex::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ITERATIONS {
+                        let mut lock = mutex.lock();
+                        let value = *lock;
+                        thread::yield_now();
+                        *lock = value + 1;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(mutex.into_inner(), THREADS * ITERATIONS);
+    }
+}